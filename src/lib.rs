@@ -0,0 +1,5686 @@
+use fuser::{
+    consts, FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate,
+    ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+
+use libc::{c_int, ENOSYS};
+mod nsfs;
+mod selftest;
+mod sync;
+pub use fuser::MountOption;
+pub use nsfs::NsFS;
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Set from the SIGUSR1 handler; the next getattr/statfs prints memory stats
+/// and, at debug level, the storage details of every file.
+static STATS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_signal: c_int) {
+    STATS_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown(_signal: c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// The signal handler can't reach the filesystem directly. Instead it raises a
+/// flag which is checked by the requests the kernel sends most often, and by
+/// `mount` while it waits for the session.
+fn print_stats_if_requested(fs: &nsfs::NsFS) {
+    if STATS_REQUESTED.swap(false, Ordering::Relaxed) {
+        println!("memory stats: {}", fs.memory_stats());
+        println!("dedup savings: {} bytes", fs.dedup_savings());
+        for (op, count) in fs.metrics() {
+            println!("{}: {} calls", op, count);
+        }
+
+        for (ino, attrs) in fs.attrs.iter() {
+            if attrs.kind != FileType::RegularFile {
+                continue;
+            }
+            if let Ok(info) = fs.storage_info(*ino) {
+                log::debug!("storage of {:?}: {:?}", fs.path_of(*ino), info);
+            }
+        }
+    }
+}
+
+/// Logs a handled operation when tracing is on. `name` is an entry of the
+/// directory `ino` for operations on directory entries.
+fn trace(fs: &nsfs::NsFS, op: &str, ino: u64, name: Option<&OsStr>, result: Result<(), c_int>) {
+    if !fs.trace {
+        return;
+    }
+    let path = fs.path_of(ino).map(|path| match name {
+        Some(name) => path.join(name),
+        None => path,
+    });
+    log::debug!("{}", trace_line(op, ino, path.as_deref(), result));
+}
+
+/// Formats a traced operation as `<op> ino=<ino> path=<path>: <result>`.
+fn trace_line(op: &str, ino: u64, path: Option<&Path>, result: Result<(), c_int>) -> String {
+    let path = match path {
+        Some(path) => path.display().to_string(),
+        None => "?".to_string(),
+    };
+    match result {
+        Ok(()) => format!("{} ino={} path={}: ok", op, ino, path),
+        Err(errno) => format!(
+            "{} ino={} path={}: {}",
+            op,
+            ino,
+            path,
+            io::Error::from_raw_os_error(errno)
+        ),
+    }
+}
+
+/// Flags for replying to an open with the given open flags. Content only
+/// changes through this filesystem, so the kernel may keep its page cache
+/// across opens, unless `O_DIRECT` asks to bypass it.
+fn open_reply_flags(flags: i32) -> u32 {
+    if flags & libc::O_DIRECT != 0 {
+        consts::FOPEN_DIRECT_IO
+    } else {
+        consts::FOPEN_KEEP_CACHE
+    }
+}
+
+/// Whether an operation succeeded, without what it returned.
+fn status<T>(result: &Result<T, c_int>) -> Result<(), c_int> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(errno) => Err(*errno),
+    }
+}
+
+/// Adds directory entries to a reply buffer through `add`, which returns true
+/// once the buffer is full and the entry didn't fit. The kernel asks for the
+/// rest starting from the offset of the last entry that made it.
+fn fill_dir(
+    entries: Vec<(u64, u64, FileType, std::ffi::OsString)>,
+    mut add: impl FnMut(u64, i64, FileType, &OsStr) -> bool,
+) {
+    for (cookie, ino, kind, name) in entries {
+        if add(ino, cookie as i64, kind, &name) {
+            break;
+        }
+    }
+}
+
+/// Answers an xattr request following the size-probe protocol: a zero `size`
+/// asks for the length only, otherwise the data must fit into `size` bytes.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+impl Filesystem for nsfs::NsFS {
+    /// Initialize filesystem.
+    /// Called before any other filesystem method.
+    /// The kernel module connection can be configured using the KernelConfig object
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Some(max_write) = self.max_write {
+            if let Err(nearest) = config.set_max_write(max_write as u32) {
+                config.set_max_write(nearest).map_err(|_| libc::EINVAL)?;
+                self.max_write = Some(nearest as usize);
+            }
+        }
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    fn destroy(&mut self) {
+        // unlike logs, this is printed whatever the log filter is
+        for (op, count) in self.metrics() {
+            eprintln!("{}: {} calls", op, count);
+        }
+        self.apply_access_times();
+        self.sync_shadow();
+        if let Err(err) = self.sync_all() {
+            log::error!("syncing on unmount failed: {}", err);
+        }
+        if let Err(err) = self.fault_in_all() {
+            log::error!("reading spilled files back failed: {}", err);
+        }
+        if let Err(err) = self.checkpoint() {
+            log::error!("saving state failed: {}", err);
+        }
+    }
+
+    /// Look up a directory entry by name and get its attributes.
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.count_op("lookup");
+        let ttl = self.ttl;
+        if let Some(attrs) = self.lookup_handle(parent, name) {
+            trace(self, "lookup", parent, Some(name), Ok(()));
+            reply.entry(&ttl, &attrs, 0);
+            return;
+        }
+
+        let result = self
+            .lookup_entry(parent, name, req.uid(), req.gid())
+            .copied()
+            .map_err(c_int::from);
+        trace(self, "lookup", parent, Some(name), status(&result));
+        match result {
+            Ok(attrs) => reply.entry(&ttl, &attrs, self.generation(attrs.ino)),
+            Err(libc::ENOENT) => match self.negative_entry(parent, name) {
+                Some((ttl, attrs)) => reply.entry(&ttl, &attrs, 0),
+                None => reply.error(libc::ENOENT),
+            },
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Forget about an inode.
+    /// The nlookup parameter indicates the number of lookups previously performed on
+    /// this inode. If the filesystem implements inode lifetimes, it is recommended that
+    /// inodes acquire a single reference on each lookup, and lose nlookup references on
+    /// each forget. The filesystem may ignore forget calls, if the inodes don't need to
+    /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
+    /// inodes will receive a forget message.
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.count_op("forget");
+        trace(self, "forget", ino, None, Ok(()));
+        self.forget_inode(ino, nlookup);
+    }
+
+    /// Get file attributes.
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        self.count_op("getattr");
+        print_stats_if_requested(self);
+        self.apply_access_times();
+        let ttl = self.ttl;
+        if let Some(attrs) = self.handle_attr(ino) {
+            trace(self, "getattr", ino, None, Ok(()));
+            reply.attr(&ttl, &attrs);
+            return;
+        }
+
+        let result = self.statx(ino).map(FileAttr::from).map_err(c_int::from);
+        trace(self, "getattr", ino, None, status(&result));
+        match result {
+            Ok(attrs) => reply.attr(&ttl, &attrs),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Set file attributes.
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.count_op("setattr");
+        let changes = nsfs::SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            crtime,
+        };
+
+        let ttl = self.ttl;
+        let result = self.set_attr(ino, changes).copied().map_err(c_int::from);
+        trace(self, "setattr", ino, None, status(&result));
+        match result {
+            Ok(attrs) => reply.attr(&ttl, &attrs),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Read symbolic link.
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.count_op("readlink");
+        let result = self
+            .read_link(ino)
+            .map(|target| target.as_os_str().as_encoded_bytes().to_vec())
+            .map_err(c_int::from);
+        trace(self, "readlink", ino, None, status(&result));
+        match result {
+            Ok(target) => reply.data(&target),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Create file node.
+    /// Create a regular file, character device, block device, fifo or socket node.
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.count_op("mknod");
+        let ttl = self.ttl;
+        let result = self
+            .create_special(parent, name, mode, rdev, req.uid(), req.gid(), umask)
+            .copied()
+            .map_err(c_int::from);
+        trace(self, "mknod", parent, Some(name), status(&result));
+        match result {
+            Ok(attrs) => reply.entry(&ttl, &attrs, self.generation(attrs.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Create a directory.
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.count_op("mkdir");
+        let ttl = self.ttl;
+        let result = self
+            .create_dir(parent, name, req.uid(), req.gid(), Some(mode), umask)
+            .copied()
+            .map_err(c_int::from);
+        trace(self, "mkdir", parent, Some(name), status(&result));
+        match result {
+            Ok(attrs) => reply.entry(&ttl, &attrs, self.generation(attrs.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Remove a file.
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.count_op("unlink");
+        let result = self
+            .remove_file(parent, name, req.uid())
+            .map_err(c_int::from);
+        trace(self, "unlink", parent, Some(name), result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Remove a directory.
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.count_op("rmdir");
+        let result = self
+            .remove_dir(parent, name, req.uid())
+            .map_err(c_int::from);
+        trace(self, "rmdir", parent, Some(name), result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Create a symbolic link.
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.count_op("symlink");
+        let ttl = self.ttl;
+        let result = self
+            .create_symlink(parent, name, link, req.uid(), req.gid())
+            .copied()
+            .map_err(c_int::from);
+        trace(self, "symlink", parent, Some(name), status(&result));
+        match result {
+            Ok(attrs) => reply.entry(&ttl, &attrs, self.generation(attrs.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Rename a file.
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("rename");
+        let result = self
+            .rename_ex(parent, name, newparent, newname, flags, req.uid())
+            .map(drop)
+            .map_err(c_int::from);
+        trace(self, "rename", newparent, Some(newname), result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Create a hard link.
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.count_op("link");
+        let ttl = self.ttl;
+        let result = self
+            .link_node(ino, newparent, newname)
+            .copied()
+            .map_err(c_int::from);
+        trace(self, "link", ino, Some(newname), status(&result));
+        match result {
+            Ok(attrs) => reply.entry(&ttl, &attrs, self.generation(attrs.ino)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Open a file.
+    /// Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are
+    /// available in flags. Filesystem may store an arbitrary file handle (pointer, index,
+    /// etc) in fh, and use this in other all other file operations (read, write, flush,
+    /// release, fsync). Filesystem may also implement stateless file I/O and not store
+    /// anything in fh. There are also some flags (direct_io, keep_cache) which the
+    /// filesystem may set, to change the way the file is opened. See fuse_file_info
+    /// structure in <fuse_common.h> for more details.
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.count_op("open");
+        let result = self.open_inode(ino, flags).map_err(c_int::from);
+        trace(self, "open", ino, None, status(&result));
+        match result {
+            Ok(fh) => reply.opened(fh, open_reply_flags(flags)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Read data.
+    /// Read should send exactly the number of bytes requested except on EOF or error,
+    /// otherwise the rest of the data will be substituted with zeroes. An exception to
+    /// this is when the file has been opened in 'direct_io' mode, in which case the
+    /// return value of the read system call will reflect the return value of this
+    /// operation. fh will contain the value set by the open method, or will be undefined
+    /// if the open method didn't set any value.
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.count_op("read");
+        if let Some(content) = self.handle_content(ino) {
+            let content = content.as_bytes();
+            let start = (offset as usize).min(content.len());
+            let end = (start + size as usize).min(content.len());
+            reply.data(&content[start..end]);
+            trace(self, "read", ino, None, Ok(()));
+            return;
+        }
+
+        // the data borrows the filesystem, so it's traced after replying
+        let result = match self.read_handle(fh, size as usize, offset as usize) {
+            Ok(data) => {
+                reply.data(&data);
+                Ok(())
+            }
+            Err(nsfs::Error::Eof) => {
+                reply.data(&[]);
+                Ok(())
+            }
+            Err(err) => {
+                let errno = c_int::from(err);
+                reply.error(errno);
+                Err(errno)
+            }
+        };
+        trace(self, "read", ino, None, result);
+    }
+
+    /// Write data.
+    /// Write should return exactly the number of bytes requested except on error. An
+    /// exception to this is when the file has been opened in 'direct_io' mode, in
+    /// which case the return value of the write system call will reflect the return
+    /// value of this operation. fh will contain the value set by the open method, or
+    /// will be undefined if the open method didn't set any value.
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.count_op("write");
+        let result = self
+            .write_handle(fh, data, offset as usize)
+            .map_err(c_int::from);
+        trace(self, "write", ino, None, status(&result));
+        match result {
+            Ok(size) => reply.written(size as u32),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Flush method.
+    /// This is called on each close() of the opened file. Since file descriptors can
+    /// be duplicated (dup, dup2, fork), for one open call there may be many flush
+    /// calls. Filesystems shouldn't assume that flush will always be called after some
+    /// writes, or that if will be called at all. fh will contain the value set by the
+    /// open method, or will be undefined if the open method didn't set any value.
+    /// NOTE: the name of the method is misleading, since (unlike fsync) the filesystem
+    /// is not forced to flush pending writes. One reason to flush data, is if the
+    /// filesystem wants to return write errors. If the filesystem supports file locking
+    /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("flush");
+        let result = self.flush_handle(fh).map_err(c_int::from);
+        trace(self, "flush", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Release an open file.
+    /// Release is called when there are no more references to an open file: all file
+    /// descriptors are closed and all memory mappings are unmapped. For every open
+    /// call there will be exactly one release call. The filesystem may reply with an
+    /// error, but error values are not returned to close() or munmap() which triggered
+    /// the release. fh will contain the value set by the open method, or will be undefined
+    /// if the open method didn't set any value. flags will contain the same flags as for
+    /// open.
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("release");
+        let result = self.close_file(fh).map_err(c_int::from);
+        trace(self, "release", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Synchronize file contents.
+    /// If the datasync parameter is non-zero, then only the user data should be flushed,
+    /// not the meta data.
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.count_op("fsync");
+        let result = self.sync_handle(fh).map_err(c_int::from);
+        trace(self, "fsync", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Open a directory.
+    /// Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and
+    /// use this in other all other directory stream operations (readdir, releasedir,
+    /// fsyncdir). Filesystem may also implement stateless directory I/O and not store
+    /// anything in fh, though that makes it impossible to implement standard conforming
+    /// directory stream operations in case the contents of the directory can change
+    /// between opendir and releasedir.
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.count_op("opendir");
+        trace(self, "opendir", ino, None, Ok(()));
+        reply.opened(0, 0);
+    }
+
+    /// Read directory.
+    /// Send a buffer filled using buffer.fill(), with size not exceeding the
+    /// requested size. Send an empty buffer on end of stream. fh will contain the
+    /// value set by the opendir method, or will be undefined if the opendir method
+    /// didn't set any value.
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        self.count_op("readdir");
+        let result = self.read_dir(ino, offset as u64).map_err(c_int::from);
+        trace(self, "readdir", ino, None, status(&result));
+        let entries = match result {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        fill_dir(entries, |ino, offset, kind, name| {
+            reply.add(ino, offset, kind, name)
+        });
+        reply.ok();
+    }
+
+    /// Release an open directory.
+    /// For every opendir call there will be exactly one releasedir call. fh will
+    /// contain the value set by the opendir method, or will be undefined if the
+    /// opendir method didn't set any value.
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("releasedir");
+        trace(self, "releasedir", ino, None, Ok(()));
+        reply.ok();
+    }
+
+    /// Synchronize directory contents.
+    /// If the datasync parameter is set, then only the directory contents should
+    /// be flushed, not the meta data. fh will contain the value set by the opendir
+    /// method, or will be undefined if the opendir method didn't set any value.
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("fsyncdir");
+        let result = self.sync_dir(ino).map_err(c_int::from);
+        trace(self, "fsyncdir", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Get file system statistics.
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.count_op("statfs");
+        trace(self, "statfs", ino, None, Ok(()));
+        print_stats_if_requested(self);
+        let (blocks, free) = self.statfs_blocks();
+        let block_size = self.block_size();
+        reply.statfs(blocks, free, free, 0, 0, block_size, 255, block_size);
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("setxattr");
+        let result = match parse_reflink(name, value) {
+            Some((name, src)) => self
+                .create_reflink(ino, name, src, req.uid(), req.gid(), None, 0)
+                .map(drop),
+            None if nsfs::is_control(name) => self.control(ino, name, value, req.uid()),
+            None => self.set_xattr(ino, name, value, flags),
+        };
+        let result = result.map_err(c_int::from);
+        trace(self, "setxattr", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Get an extended attribute.
+    /// If `size` is 0, the size of the value should be sent with `reply.size()`.
+    /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
+    /// `reply.error(ERANGE)` if it doesn't.
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.count_op("getxattr");
+        // the MIME type is sniffed from the content
+        let value = self.fault_in(ino).and_then(|()| self.get_xattr(ino, name));
+        let result = match value {
+            Ok(value) => {
+                reply_xattr(reply, size, &value);
+                Ok(())
+            }
+            Err(err) => {
+                let errno = c_int::from(err);
+                reply.error(errno);
+                Err(errno)
+            }
+        };
+        trace(self, "getxattr", ino, None, result);
+    }
+
+    /// List extended attribute names.
+    /// If `size` is 0, the size of the value should be sent with `reply.size()`.
+    /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
+    /// `reply.error(ERANGE)` if it doesn't.
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.count_op("listxattr");
+        let result = self.list_xattr(ino).map_err(c_int::from);
+        trace(self, "listxattr", ino, None, status(&result));
+        match result {
+            Ok(names) => reply_xattr(reply, size, &names),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.count_op("removexattr");
+        let result = self.remove_xattr(ino, name).map_err(c_int::from);
+        trace(self, "removexattr", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Check file access permissions.
+    /// This will be called for the access() system call. If the 'default_permissions'
+    /// mount option is given, this method is not called. This method is not called
+    /// under Linux kernel versions 2.4.x
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.count_op("access");
+        let result = self
+            .check_access(ino, req.uid(), req.gid(), mask)
+            .map_err(c_int::from);
+        trace(self, "access", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Create and open a file.
+    /// If the file does not exist, first create it with the specified mode, and then
+    /// open it. Open flags (with the exception of O_NOCTTY) are available in flags.
+    /// Filesystem may store an arbitrary file handle (pointer, index, etc) in fh,
+    /// and use this in other all other file operations (read, write, flush, release,
+    /// fsync). There are also some flags (direct_io, keep_cache) which the
+    /// filesystem may set, to change the way the file is opened. See fuse_file_info
+    /// structure in <fuse_common.h> for more details. If this method is not
+    /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
+    /// and open() methods will be called instead.
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.count_op("create");
+        let ttl = self.ttl;
+        let result = self
+            .create_file(
+                parent,
+                name,
+                flags as u32,
+                req.uid(),
+                req.gid(),
+                Some(mode),
+                umask,
+            )
+            .map(|(attrs, fh)| (*attrs, fh))
+            .map_err(c_int::from);
+        trace(self, "create", parent, Some(name), status(&result));
+        match result {
+            Ok((attrs, fh)) => reply.created(
+                &ttl,
+                &attrs,
+                self.generation(attrs.ino),
+                fh,
+                open_reply_flags(flags),
+            ),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: i32,
+        _pid: u32,
+        reply: ReplyLock,
+    ) {
+        self.count_op("getlk");
+        trace(self, "getlk", ino, None, Err(ENOSYS));
+        reply.error(ENOSYS);
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    /// For POSIX threads (NPTL) there's a 1-1 relation between pid and owner, but
+    /// otherwise this is not always the case.  For checking lock ownership,
+    /// 'fi->owner' must be used. The l_pid field in 'struct flock' should only be
+    /// used to fill in this field in getlk(). Note: if the locking methods are not
+    /// implemented, the kernel will still allow file locking to work locally.
+    /// Hence these are only interesting for network filesystems and similar.
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: i32,
+        _pid: u32,
+        _sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("setlk");
+        trace(self, "setlk", ino, None, Err(ENOSYS));
+        reply.error(ENOSYS);
+    }
+
+    /// Map block index within file to block index within device.
+    /// Note: This makes sense only for block device backed filesystems mounted
+    /// with the 'blkdev' option
+    fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.count_op("bmap");
+        let result = nsfs::NsFS::bmap(self, ino, blocksize, idx).map_err(c_int::from);
+        trace(self, "bmap", ino, None, status(&result));
+        match result {
+            Ok(block) => reply.bmap(block),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Control device.
+    /// Only `nsfs::STATS_IOCTL` is understood, other commands fail with ENOTTY.
+    fn ioctl(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.count_op("ioctl");
+        let result = match cmd {
+            nsfs::STATS_IOCTL if (out_size as usize) < nsfs::STATS_SIZE => Err(libc::EINVAL),
+            nsfs::STATS_IOCTL => Ok(self.packed_stats()),
+            _ => Err(libc::ENOTTY),
+        };
+        trace(self, "ioctl", ino, None, status(&result));
+        match result {
+            Ok(stats) => reply.ioctl(0, &stats),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Preallocate or deallocate space to a file.
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.count_op("fallocate");
+        let result = self
+            .allocate(ino, offset as u64, length as u64, mode)
+            .map_err(c_int::from);
+        trace(self, "fallocate", ino, None, result);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Copy a range of data from one file to another.
+    /// Only copying a whole file over the start of a file that isn't longer,
+    /// which is what `cp` does, is handled. Anything else is left to the
+    /// kernel, which falls back to reading and writing.
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.count_op("copy_file_range");
+        let whole = match (self.get_attr(ino_in), self.get_attr(ino_out)) {
+            (Ok(src), Ok(dst)) => is_whole_copy(src, dst, offset_in, offset_out, len),
+            _ => false,
+        };
+        let result = match whole {
+            true => self
+                .transfer(ino_in, ino_out)
+                .map(|attrs| attrs.size as u32)
+                .map_err(c_int::from),
+            false => Err(libc::EOPNOTSUPP),
+        };
+        trace(self, "copy_file_range", ino_out, None, status(&result));
+        match result {
+            Ok(size) => reply.written(size),
+            Err(errno) => reply.error(errno),
+        }
+    }
+}
+
+/// Whether copying `len` bytes of `src` at `offset_in` to `dst` at
+/// `offset_out` leaves `dst` with exactly the content of `src`.
+fn is_whole_copy(
+    src: &FileAttr,
+    dst: &FileAttr,
+    offset_in: i64,
+    offset_out: i64,
+    len: u64,
+) -> bool {
+    offset_in == 0
+        && offset_out == 0
+        && len >= src.size
+        && dst.size <= src.size
+        && src.size <= u32::MAX as u64
+}
+
+/// Command line options.
+struct Options {
+    mountpoint: String,
+    atime_policy: nsfs::AtimePolicy,
+    debug_handles: bool,
+    buffered: bool,
+    strict_writes: bool,
+    write_policy: nsfs::WritePolicy,
+    backing_dir: Option<String>,
+    /// A host directory changes are mirrored to.
+    shadow: Option<String>,
+    attr_ttl: Option<Duration>,
+    /// Lets the kernel cache failed lookups for this long.
+    negative_ttl: Option<Duration>,
+    mount_options: Vec<MountOption>,
+    state_file: Option<String>,
+    /// A JSON tree to start from instead of an empty filesystem.
+    import_json: Option<String>,
+    capacity: Option<u64>,
+    block_size: Option<u32>,
+    spill: Option<String>,
+    spill_high_water: Option<usize>,
+    max_entries: Option<usize>,
+    max_depth: Option<u32>,
+    quotas: Vec<(u32, u64)>,
+    trace: bool,
+    /// Keep directory entries sorted for prefix searches.
+    sorted_index: bool,
+    /// Print the tree of the state file instead of mounting.
+    dump: bool,
+    /// Print the tree of the state file as JSON instead of mounting.
+    export_json: bool,
+    /// Include file content in the JSON export.
+    with_content: bool,
+    /// Run the self-test on a temporary mount instead of mounting.
+    selftest: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut mountpoint = None;
+    let mut atime_policy = nsfs::AtimePolicy::Relatime;
+    let mut debug_handles = false;
+    let mut buffered = false;
+    let mut strict_writes = false;
+    let mut write_policy = nsfs::WritePolicy::WriteThrough;
+    let mut backing_dir = None;
+    let mut shadow = None;
+    let mut attr_ttl = None;
+    let mut negative_ttl = None;
+    let mut mount_options = Vec::new();
+    let mut state_file = None;
+    let mut import_json = None;
+    let mut capacity = None;
+    let mut block_size = None;
+    let mut spill = None;
+    let mut spill_high_water = None;
+    let mut max_entries = None;
+    let mut max_depth = None;
+    let mut quotas = Vec::new();
+    let mut trace = false;
+    let mut sorted_index = false;
+    let mut dump = false;
+    let mut export_json = false;
+    let mut with_content = false;
+    let mut selftest = false;
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--atime" => {
+                atime_policy = match args.next().map(String::as_str) {
+                    Some("strict") => nsfs::AtimePolicy::Strict,
+                    Some("relatime") => nsfs::AtimePolicy::Relatime,
+                    Some("noatime") => nsfs::AtimePolicy::Noatime,
+                    _ => return Err("--atime expects strict, relatime or noatime".to_string()),
+                }
+            }
+            "--debug-handles" => debug_handles = true,
+            "-o" => match args.next() {
+                Some(list) => {
+                    for option in list.split(',') {
+                        mount_options.push(parse_mount_option(option)?);
+                    }
+                }
+                None => return Err("-o expects a list of mount options".to_string()),
+            },
+            "--write-back" => buffered = true,
+            "--strict-writes" => strict_writes = true,
+            "--write-policy" => {
+                write_policy = match args.next().map(String::as_str) {
+                    Some("through") => nsfs::WritePolicy::WriteThrough,
+                    Some("back") => nsfs::WritePolicy::WriteBack,
+                    _ => return Err("--write-policy expects through or back".to_string()),
+                }
+            }
+            "--attr-ttl" => match args.next().map(|secs| secs.parse::<u64>()) {
+                Some(Ok(secs)) => attr_ttl = Some(Duration::from_secs(secs)),
+                _ => return Err("--attr-ttl expects a number of seconds".to_string()),
+            },
+            "--negative-ttl" => match args.next().map(|secs| secs.parse::<u64>()) {
+                Some(Ok(secs)) => negative_ttl = Some(Duration::from_secs(secs)),
+                _ => return Err("--negative-ttl expects a number of seconds".to_string()),
+            },
+            "--capacity" => match args.next().map(|bytes| bytes.parse::<u64>()) {
+                Some(Ok(bytes)) => capacity = Some(bytes),
+                _ => return Err("--capacity expects a number of bytes".to_string()),
+            },
+            "--spill" => match args.next() {
+                Some(dir) => spill = Some(dir.clone()),
+                None => return Err("--spill expects a directory".to_string()),
+            },
+            "--spill-high-water" => match args.next().map(|bytes| bytes.parse::<usize>()) {
+                Some(Ok(bytes)) => spill_high_water = Some(bytes),
+                _ => return Err("--spill-high-water expects a number of bytes".to_string()),
+            },
+            "--max-entries" => match args.next().map(|count| count.parse::<usize>()) {
+                Some(Ok(count)) => max_entries = Some(count),
+                _ => return Err("--max-entries expects a number of entries".to_string()),
+            },
+            "--max-depth" => match args.next().map(|depth| depth.parse::<u32>()) {
+                Some(Ok(depth)) => max_depth = Some(depth),
+                _ => return Err("--max-depth expects a number of directories".to_string()),
+            },
+            "--trace" => trace = true,
+            "--sorted-index" => sorted_index = true,
+            "--dump" => dump = true,
+            "--export-json" => export_json = true,
+            "--with-content" => with_content = true,
+            "--selftest" => selftest = true,
+            "--quota" => match args.next().and_then(|quota| parse_quota(quota)) {
+                Some(quota) => quotas.push(quota),
+                None => return Err("--quota expects <uid>=<bytes>".to_string()),
+            },
+            "--state" => match args.next() {
+                Some(file) => state_file = Some(file.clone()),
+                None => return Err("--state expects a file".to_string()),
+            },
+            "--import-json" => match args.next() {
+                Some(file) => import_json = Some(file.clone()),
+                None => return Err("--import-json expects a file".to_string()),
+            },
+            "--backing-dir" => match args.next() {
+                Some(dir) => backing_dir = Some(dir.clone()),
+                None => return Err("--backing-dir expects a directory".to_string()),
+            },
+            "--block-size" => match args.next().map(|size| size.parse::<u32>()) {
+                Some(Ok(size)) if size >= 512 && size.is_power_of_two() => block_size = Some(size),
+                _ => {
+                    return Err(
+                        "--block-size expects a power of two of at least 512 bytes".to_string()
+                    )
+                }
+            },
+            "--shadow" => match args.next() {
+                Some(dir) => shadow = Some(dir.clone()),
+                None => return Err("--shadow expects a directory".to_string()),
+            },
+            _ if mountpoint.is_none() => mountpoint = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    // dumping doesn't mount anything
+    let mountpoint = match mountpoint {
+        Some(mountpoint) => Some(mountpoint),
+        None if dump || export_json || selftest => Some(String::new()),
+        None => None,
+    };
+    match mountpoint {
+        Some(mountpoint) => Ok(Options {
+            mountpoint,
+            atime_policy,
+            debug_handles,
+            buffered,
+            strict_writes,
+            write_policy,
+            backing_dir,
+            shadow,
+            attr_ttl,
+            negative_ttl,
+            mount_options,
+            state_file,
+            import_json,
+            capacity,
+            block_size,
+            spill,
+            spill_high_water,
+            max_entries,
+            max_depth,
+            quotas,
+            trace,
+            sorted_index,
+            dump,
+            export_json,
+            with_content,
+            selftest,
+        }),
+        None => Err("missing mountpoint".to_string()),
+    }
+}
+
+/// The whole tree, one line per node indented by depth, with directories
+/// marked by a trailing slash and regular files followed by their size.
+fn dump_tree(fs: &nsfs::NsFS) -> String {
+    let mut nodes: Vec<(PathBuf, FileType, u64)> = fs
+        .list_inodes()
+        .into_iter()
+        .filter_map(|(ino, kind, size)| Some((fs.path_of(ino)?, kind, size)))
+        .collect();
+    nodes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut tree = String::new();
+    for (path, kind, size) in nodes {
+        let depth = path.components().count() - 1;
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => "/".into(),
+        };
+        tree.push_str(&"  ".repeat(depth));
+        tree.push_str(&name);
+        match kind {
+            FileType::Directory if depth > 0 => tree.push('/'),
+            FileType::RegularFile => tree.push_str(&format!(" ({} bytes)", size)),
+            _ => {}
+        }
+        tree.push('\n');
+    }
+    tree
+}
+
+/// The entry name and source inode of a reflink requested through
+/// `nsfs::REFLINK_XATTR_PREFIX`.
+fn parse_reflink<'a>(name: &'a OsStr, value: &[u8]) -> Option<(&'a OsStr, u64)> {
+    let name = name
+        .as_bytes()
+        .strip_prefix(nsfs::REFLINK_XATTR_PREFIX.as_bytes())?;
+    let src = std::str::from_utf8(value).ok()?.trim().parse().ok()?;
+    Some((OsStr::from_bytes(name), src))
+}
+
+/// Parses a `--quota` value of the form `<uid>=<bytes>`.
+fn parse_quota(quota: &str) -> Option<(u32, u64)> {
+    let (uid, bytes) = quota.split_once('=')?;
+    Some((uid.parse().ok()?, bytes.parse().ok()?))
+}
+
+/// Translates a single `-o` option.
+fn parse_mount_option(option: &str) -> Result<MountOption, String> {
+    match option {
+        "allow_other" => Ok(MountOption::AllowOther),
+        "ro" => Ok(MountOption::RO),
+        "default_permissions" => Ok(MountOption::DefaultPermissions),
+        _ => match option.strip_prefix("fsname=") {
+            Some(name) if !name.is_empty() => Ok(MountOption::FSName(name.to_string())),
+            _ => Err(format!("unsupported mount option: {}", option)),
+        },
+    }
+}
+
+/// Mount options passed to the kernel for the given command line options.
+/// The source shows up as `nsfs` unless `-o fsname=` says otherwise.
+fn mount_options(options: &Options) -> Vec<MountOption> {
+    let mut mount_options = options.mount_options.clone();
+    let named = mount_options
+        .iter()
+        .any(|option| matches!(option, MountOption::FSName(_)));
+    if !named {
+        mount_options.insert(0, MountOption::FSName("nsfs".to_string()));
+    }
+    mount_options
+}
+
+/// Serves `fs` at `mountpoint` until it's unmounted or the process is asked to
+/// stop with SIGINT or SIGTERM, in which case it unmounts cleanly.
+pub fn mount(mut fs: NsFS, mountpoint: &Path, options: &[MountOption]) -> io::Result<()> {
+    unsafe {
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            libc::signal(
+                signal,
+                on_shutdown as extern "C" fn(c_int) as libc::sighandler_t,
+            );
+        }
+    }
+
+    // Changed inodes reach this thread through a shared queue. Telling the
+    // kernel about them takes a `Notifier` from the session, which fuser 0.12
+    // doesn't offer yet; until then they're only logged and the kernel relies
+    // on the attribute TTL.
+    let invalidations = nsfs::InvalidationQueue::default();
+    fs.invalidations = Some(invalidations.clone());
+
+    // the session thread serves requests while this one reaches the same
+    // filesystem for stats
+    let fs = sync::SyncNsFS::new(fs);
+    let session = fuser::spawn_mount2(fs.clone(), mountpoint, options)?;
+    while !SHUTDOWN_REQUESTED.load(Ordering::Relaxed) && !session.guard.is_finished() {
+        std::thread::sleep(Duration::from_millis(100));
+        print_stats_if_requested(&fs.lock());
+        for ino in invalidations.drain() {
+            log::debug!("attributes of inode {} changed", ino);
+        }
+        for (parent, name) in invalidations.drain_entries() {
+            log::debug!("{:?} in inode {} was created", name, parent);
+        }
+    }
+    session.join();
+    Ok(())
+}
+
+/// Mounts `fs` on a new temporary directory, runs the self-test through the
+/// kernel and unmounts it again.
+fn run_selftest(fs: nsfs::NsFS, options: &[MountOption]) -> Result<(), String> {
+    let dir = env::temp_dir().join(format!("nsfs-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).map_err(|err| format!("creating {:?} failed: {}", dir, err))?;
+    let result = match fuser::spawn_mount2(fs, &dir, options) {
+        Ok(session) => {
+            let result = selftest::run(&mut selftest::MountedDir::new(&dir));
+            session.join();
+            result
+        }
+        Err(err) => Err(format!("mounting on {:?} failed: {}", dir, err)),
+    };
+    if let Err(err) = std::fs::remove_dir(&dir) {
+        log::warn!("removing {:?} failed: {}", dir, err);
+    }
+    result
+}
+
+/// A filesystem set up from the command line, ready for `mount`.
+pub struct Setup {
+    pub fs: NsFS,
+    pub mountpoint: PathBuf,
+    pub options: Vec<MountOption>,
+}
+
+/// Sets up the filesystem the command line `args` asks for, logging and the
+/// SIGUSR1 handler included. Commands that don't mount anything, like
+/// `--dump` or `--selftest`, are run right away and give `None`.
+pub fn from_args(args: &[String]) -> Result<Option<Setup>, String> {
+    let options = parse_args(args).map_err(|err| {
+        format!(
+            "{}\nUsage: {} [--atime strict|relatime|noatime] [--debug-handles] [--write-back] [--strict-writes] [--write-policy through|back] [--backing-dir <dir>] [--shadow <dir>] [--attr-ttl <secs>] [--negative-ttl <secs>] [--state <file>] [--import-json <file>] [--capacity <bytes>] [--block-size <bytes>] [--max-entries <count>] [--max-depth <count>] [--spill <dir>] [--spill-high-water <bytes>] [--quota <uid>=<bytes>] [--trace] [--sorted-index] [-o allow_other,ro,default_permissions,fsname=NAME] <mountpoint> | --state <file> --dump | --state <file> --export-json [--with-content] | --selftest",
+            err, args[0]
+        )
+    })?;
+    // --trace turns on debug logging unless RUST_LOG says otherwise
+    let default_filter = if options.trace { "debug" } else { "error" };
+    // an embedding program may have set up logging already
+    let _ =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+            .try_init();
+
+    unsafe {
+        libc::signal(
+            libc::SIGUSR1,
+            on_sigusr1 as extern "C" fn(c_int) as libc::sighandler_t,
+        );
+    }
+
+    let mount_options = mount_options(&options);
+    let mut fs = match &options.state_file {
+        Some(path) if Path::new(path).exists() => NsFS::load_from(Path::new(path))
+            .map_err(|err| format!("loading state from {} failed: {}", path, err))?,
+        _ => match &options.import_json {
+            Some(path) => std::fs::read_to_string(path)
+                .and_then(|json| NsFS::from_json(&json))
+                .map_err(|err| format!("importing {} failed: {}", path, err))?,
+            None => NsFS::new(),
+        },
+    };
+    if options.dump {
+        print!("{}", dump_tree(&fs));
+        return Ok(None);
+    }
+    if options.export_json {
+        match options.with_content {
+            true => println!("{}", fs.to_json_with_content()),
+            false => println!("{}", fs.to_json()),
+        }
+        return Ok(None);
+    }
+    fs.state_file = options.state_file.map(PathBuf::from);
+    if fs.state_file.is_some() {
+        fs.open_wal()
+            .map_err(|err| format!("opening the write-ahead log failed: {}", err))?;
+    }
+    fs.atime_policy = options.atime_policy;
+    fs.debug_handles = options.debug_handles;
+    fs.default_permissions = mount_options.contains(&MountOption::DefaultPermissions);
+    fs.trace = options.trace || log::log_enabled!(log::Level::Debug);
+    fs.set_sorted_index(options.sorted_index);
+    fs.buffered = options.buffered;
+    fs.strict_writes = options.strict_writes;
+    fs.capacity_bytes = options.capacity;
+    fs.max_entries_per_dir = options.max_entries;
+    fs.max_depth = options.max_depth;
+    fs.spill_path = options.spill.map(PathBuf::from);
+    if let Some(bytes) = options.spill_high_water {
+        fs.spill_high_water = bytes;
+    }
+    for &(uid, bytes) in &options.quotas {
+        fs.set_quota(uid, bytes);
+    }
+    fs.write_policy = options.write_policy;
+    if let Some(ttl) = options.attr_ttl {
+        fs.ttl = ttl;
+    }
+    fs.negative_ttl = options.negative_ttl;
+    if let Some(dir) = options.backing_dir {
+        fs.backing = Box::new(nsfs::DirBacking::new(dir));
+    }
+    fs.shadow_root = options.shadow.map(PathBuf::from);
+    if let Some(block_size) = options.block_size {
+        fs.set_block_size(block_size)
+            .expect("block size was validated");
+    }
+    if options.selftest {
+        run_selftest(fs, &mount_options).map_err(|err| format!("selftest failed: {}", err))?;
+        println!("selftest passed");
+        return Ok(None);
+    }
+    Ok(Some(Setup {
+        fs,
+        mountpoint: PathBuf::from(options.mountpoint),
+        options: mount_options,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nsfs::Clock;
+
+    #[test]
+    fn test_create_file() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let (attrs, fh) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+        assert_eq!(attrs.ino, 2);
+        assert_eq!(fh, 0);
+    }
+
+    #[test]
+    fn test_write_read_file() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+
+        let data = b"Hello, Rust";
+        let written = fs.write_file(ino, data, 0).unwrap();
+        assert_eq!(written, 11);
+
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Hello, Rust"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+
+        match fs.get_attr(ino) {
+            Ok(attrs) => assert_eq!(attrs.size, 11),
+            Err(err) => panic!("get_attr failed: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_append_to_file() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+
+        let data = b"Hello, Rust";
+        let written = fs.write_file(ino, data, 0).unwrap();
+        assert_eq!(written, 11);
+
+        let data = b"Hello, Rust";
+        let written = fs.write_file(ino, data, 11).unwrap();
+        assert_eq!(written, 11);
+
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Hello, RustHello, Rust"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+
+        match fs.get_attr(ino) {
+            Ok(attrs) => assert_eq!(attrs.size, 22),
+            Err(err) => panic!("get_attr failed: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_read_big_file() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+
+        let mut offset = 0;
+        let mut expected = Vec::new();
+
+        for i in 0..100 {
+            let data = format!("#{} Hello, Rust", i);
+            expected.extend_from_slice(data.as_bytes());
+
+            let written = fs
+                .write_file(ino, &expected[offset..offset + data.len()], offset)
+                .unwrap();
+            assert_eq!(written, data.len());
+
+            offset += data.len();
+        }
+
+        match fs.get_attr(ino) {
+            Ok(attrs) => assert_eq!(attrs.size, expected.len() as u64),
+            Err(err) => panic!("get_attr failed: {}", err),
+        }
+
+        let mut data = Vec::new();
+        offset = 0;
+        while let Ok(chunk) = fs.read_file(ino, 10, offset) {
+            data.extend_from_slice(chunk);
+            offset += chunk.len();
+        }
+
+        assert_eq!(data.len(), offset);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn test_read_empty_file() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert!(data.is_empty()),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_forget_unlinked_inode() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+
+        let attrs = fs.lookup_entry(parent, name, 0, 0).unwrap();
+        assert_eq!(attrs.ino, ino);
+        assert_eq!(attrs.nlink, 1);
+
+        fs.remove_file(parent, name, 0).unwrap();
+        assert!(fs.lookup_entry(parent, name, 0, 0).is_err());
+
+        // the kernel still holds two references: from create and from lookup
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Hello, Rust"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+
+        fs.forget_inode(ino, 1);
+        assert!(fs.get_attr(ino).is_ok());
+
+        fs.forget_inode(ino, 1);
+        assert!(fs.get_attr(ino).is_err());
+        assert!(fs.read_file(ino, 1024, 0).is_err());
+    }
+
+    #[test]
+    fn test_forget_linked_inode() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+
+        fs.forget_inode(ino, 1);
+        assert!(fs.get_attr(ino).is_ok());
+        assert_eq!(fs.lookup_entry(parent, name, 0, 0).unwrap().ino, ino);
+    }
+
+    #[test]
+    fn test_path_of() {
+        let mut fs = nsfs::NsFS::new();
+        let first = fs
+            .create_dir(1, OsStr::new("first"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let second = fs
+            .create_dir(first, OsStr::new("second"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = {
+            let (attrs, _) = fs
+                .create_file(second, OsStr::new("test"), 0, 0, 0, None, 0)
+                .unwrap();
+            attrs.ino
+        };
+
+        assert_eq!(fs.path_of(1), Some(PathBuf::from("/")));
+        assert_eq!(fs.path_of(first), Some(PathBuf::from("/first")));
+        assert_eq!(fs.path_of(file), Some(PathBuf::from("/first/second/test")));
+        assert_eq!(fs.path_of(42), None);
+
+        fs.nodes.get_mut(&first).unwrap().parent = second;
+        assert_eq!(fs.path_of(file), None);
+    }
+
+    #[test]
+    fn test_max_write() {
+        let mut fs = nsfs::NsFS::new();
+        fs.max_write = Some(4);
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+
+        let written = fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        assert_eq!(written, 4);
+
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Hell"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+
+        match fs.get_attr(ino) {
+            Ok(attrs) => assert_eq!(attrs.size, 4),
+            Err(err) => panic!("get_attr failed: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_set_attr_times() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let (ino, atime) = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            (attrs.ino, attrs.atime)
+        };
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let changes = nsfs::SetAttr {
+            mtime: Some(TimeOrNow::SpecificTime(mtime)),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert_eq!(attrs.mtime, mtime);
+        assert_eq!(attrs.atime, atime);
+
+        let past = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000);
+        let changes = nsfs::SetAttr {
+            atime: Some(TimeOrNow::SpecificTime(past)),
+            ..Default::default()
+        };
+        assert_eq!(fs.set_attr(ino, changes).unwrap().atime, past);
+
+        let changes = nsfs::SetAttr {
+            atime: Some(TimeOrNow::Now),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert!(attrs.atime > past);
+        assert_eq!(attrs.mtime, mtime);
+    }
+
+    #[test]
+    fn test_read_handle_buffered() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let (ino, fh) = {
+            let (attrs, fh) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            (attrs.ino, fh)
+        };
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        assert!(!fs.open_files[&fh].direct_io);
+
+        match fs.read_handle(fh, 1024, 7) {
+            Ok(data) => assert_eq!(&data[..], b"Rust"),
+            Err(err) => panic!("read_handle failed: {}", err),
+        }
+        assert!(matches!(
+            fs.read_handle(fh, 1024, 11),
+            Err(nsfs::Error::Eof)
+        ));
+    }
+
+    #[test]
+    fn test_read_handle_direct_io() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = libc::O_DIRECT as u32;
+        let (ino, fh) = {
+            let (attrs, fh) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            (attrs.ino, fh)
+        };
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        assert!(fs.open_files[&fh].direct_io);
+
+        match fs.read_handle(fh, 1024, 7) {
+            Ok(data) => assert_eq!(&data[..], b"Rust"),
+            Err(err) => panic!("read_handle failed: {}", err),
+        }
+        match fs.read_handle(fh, 1024, 11) {
+            Ok(data) => assert!(data.is_empty()),
+            Err(err) => panic!("read_handle failed: {}", err),
+        }
+        assert!(matches!(
+            fs.read_handle(fh + 1, 1024, 0),
+            Err(nsfs::Error::BadFileDescriptor)
+        ));
+    }
+
+    #[test]
+    fn test_storage_info() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let flags = 0;
+        let empty = {
+            let (attrs, _) = fs
+                .create_file(parent, OsStr::new("empty"), flags, 0, 0, None, 0)
+                .unwrap();
+            attrs.ino
+        };
+        let written = {
+            let (attrs, _) = fs
+                .create_file(parent, OsStr::new("written"), flags, 0, 0, None, 0)
+                .unwrap();
+            attrs.ino
+        };
+        fs.write_file(written, b"Hello, Rust", 0).unwrap();
+
+        let info = fs.storage_info(empty).unwrap();
+        assert_eq!(info.representation, nsfs::Representation::Segmented);
+        assert_eq!(info.resident_bytes, 0);
+        assert_eq!(info.segments, 0);
+
+        let info = fs.storage_info(written).unwrap();
+        assert_eq!(info.representation, nsfs::Representation::Segmented);
+        assert!(info.resident_bytes >= 11);
+        assert_eq!(info.segments, 1);
+
+        assert!(fs.storage_info(1).is_err());
+
+        // a file pushed out to the spill directory
+        let dir = std::env::temp_dir().join(format!("nsfs-info-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        fs.spill_path = Some(dir.clone());
+        fs.spill_high_water = 4096;
+        let hot = fs
+            .create_file(parent, OsStr::new("hot"), flags, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(hot, &[1; 4096], 0).unwrap();
+        fs.read_file(hot, 1, 0).unwrap();
+        let info = fs.storage_info(written).unwrap();
+        assert_eq!(info.representation, nsfs::Representation::Spilled);
+        assert_eq!(info.resident_bytes, 0);
+        fs.read_file(written, 1024, 0).unwrap();
+        let info = fs.storage_info(written).unwrap();
+        assert_eq!(info.representation, nsfs::Representation::Segmented);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // a file whose content is kept by a storage
+        let mut fs = nsfs::NsFS::new();
+        fs.storage = Some(Box::new(nsfs::RecordingStorage::default()));
+        let stored = fs
+            .create_file(parent, OsStr::new("stored"), flags, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(stored, b"Hello, Rust", 0).unwrap();
+        let info = fs.storage_info(stored).unwrap();
+        assert_eq!(info.representation, nsfs::Representation::Stored);
+        assert_eq!(info.resident_bytes, 0);
+    }
+
+    #[test]
+    fn test_create_under_file() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = {
+            let (attrs, _) = fs
+                .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+                .unwrap();
+            attrs.ino
+        };
+
+        assert!(matches!(
+            fs.create_file(parent, OsStr::new("test"), 0, 0, 0, None, 0),
+            Err(nsfs::Error::NotADirectory)
+        ));
+        assert!(matches!(
+            fs.create_dir(parent, OsStr::new("test"), 0, 0, None, 0),
+            Err(nsfs::Error::NotADirectory)
+        ));
+        assert!(matches!(
+            fs.find_node(parent, OsStr::new("test")),
+            Err(nsfs::Error::NotADirectory)
+        ));
+        assert_eq!(c_int::from(nsfs::Error::NotADirectory), libc::ENOTDIR);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let (file, other) = {
+            let (attrs, _) = fs
+                .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+                .unwrap();
+            let file = attrs.ino;
+            let (attrs, _) = fs
+                .create_file(dir, OsStr::new("other"), 0, 0, 0, None, 0)
+                .unwrap();
+            (file, attrs.ino)
+        };
+
+        assert!(matches!(
+            fs.rename_ex(
+                1,
+                OsStr::new("file"),
+                dir,
+                OsStr::new("other"),
+                libc::RENAME_NOREPLACE,
+                0
+            ),
+            Err(nsfs::Error::AlreadyExists)
+        ));
+
+        fs.rename_ex(1, OsStr::new("file"), dir, OsStr::new("other"), 0, 0)
+            .unwrap();
+        assert!(fs.find_node(1, OsStr::new("file")).is_err());
+        assert_eq!(fs.find_node(dir, OsStr::new("other")).unwrap().index, file);
+        assert_eq!(fs.path_of(file), Some(PathBuf::from("/dir/other")));
+        assert!(fs.get_attr(other).is_ok());
+
+        fs.forget_inode(other, 1);
+        assert!(fs.get_attr(other).is_err());
+
+        assert!(matches!(
+            fs.rename_ex(1, OsStr::new("dir"), dir, OsStr::new("nested"), 0, 0),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn test_rename_type_rules() {
+        let mut fs = nsfs::NsFS::new();
+        fs.create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap();
+        fs.create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap();
+        fs.create_dir(1, OsStr::new("dir"), 0, 0, None, 0).unwrap();
+        fs.create_dir(1, OsStr::new("empty"), 0, 0, None, 0)
+            .unwrap();
+        let full = fs
+            .create_dir(1, OsStr::new("full"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        fs.create_file(full, OsStr::new("child"), 0, 0, 0, None, 0)
+            .unwrap();
+
+        let mut rename = |name: &str, newname: &str| {
+            fs.rename_ex(1, OsStr::new(name), 1, OsStr::new(newname), 0, 0)
+        };
+        assert!(matches!(
+            rename("file", "dir"),
+            Err(nsfs::Error::IsADirectory)
+        ));
+        assert!(matches!(
+            rename("dir", "file"),
+            Err(nsfs::Error::NotADirectory)
+        ));
+        assert!(matches!(rename("dir", "full"), Err(nsfs::Error::NotEmpty)));
+        assert!(rename("dir", "empty").is_ok());
+        assert!(rename("file", "other").is_ok());
+    }
+
+    #[test]
+    fn test_rename_exchange() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let (file, child) = {
+            let (attrs, _) = fs
+                .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+                .unwrap();
+            let file = attrs.ino;
+            let (attrs, _) = fs
+                .create_file(dir, OsStr::new("child"), 0, 0, 0, None, 0)
+                .unwrap();
+            (file, attrs.ino)
+        };
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+
+        fs.rename_ex(
+            1,
+            OsStr::new("file"),
+            1,
+            OsStr::new("dir"),
+            libc::RENAME_EXCHANGE,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(fs.find_node(1, OsStr::new("file")).unwrap().index, dir);
+        assert_eq!(fs.find_node(1, OsStr::new("dir")).unwrap().index, file);
+        assert_eq!(fs.path_of(file), Some(PathBuf::from("/dir")));
+        assert_eq!(fs.path_of(child), Some(PathBuf::from("/file/child")));
+        match fs.read_file(file, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Hello, Rust"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+
+        assert!(matches!(
+            fs.rename_ex(
+                1,
+                OsStr::new("file"),
+                1,
+                OsStr::new("missing"),
+                libc::RENAME_EXCHANGE,
+                0,
+            ),
+            Err(nsfs::Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_relatime() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(clock.clone());
+        fs.atime_policy = nsfs::AtimePolicy::Relatime;
+        let parent = 1;
+        let name = OsStr::new("test");
+        let flags = 0;
+        let ino = {
+            let (attrs, _) = fs.create_file(parent, name, flags, 0, 0, None, 0).unwrap();
+            attrs.ino
+        };
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+
+        // atime isn't newer than mtime, so the first read updates it
+        clock.advance(Duration::from_secs(60 * 60));
+        fs.read_file(ino, 1024, 0).unwrap();
+        let atime = fs.get_attr(ino).unwrap().atime;
+        assert_eq!(atime, clock.now());
+
+        clock.advance(Duration::from_secs(60 * 60));
+        fs.read_file(ino, 1024, 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().atime, atime);
+
+        // more than a day later the update fires even though atime > mtime
+        clock.advance(Duration::from_secs(25 * 60 * 60));
+        fs.read_file(ino, 1024, 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().atime, clock.now());
+    }
+
+    #[test]
+    fn test_strict_and_noatime() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let mut fs = nsfs::NsFS::new();
+        assert_eq!(fs.atime_policy, nsfs::AtimePolicy::Relatime);
+        fs.clock = Box::new(clock.clone());
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+
+        // every read counts
+        fs.atime_policy = nsfs::AtimePolicy::Strict;
+        for _ in 0..2 {
+            clock.advance(Duration::from_secs(60));
+            fs.read_file(ino, 1024, 0).unwrap();
+            assert_eq!(fs.get_attr(ino).unwrap().atime, clock.now());
+        }
+
+        // no read counts, however stale atime is
+        fs.atime_policy = nsfs::AtimePolicy::Noatime;
+        let atime = fs.get_attr(ino).unwrap().atime;
+        fs.write_file(ino, b"Hello", 0).unwrap();
+        clock.advance(Duration::from_secs(25 * 60 * 60));
+        fs.read_file(ino, 1024, 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().atime, atime);
+    }
+
+    #[test]
+    fn test_parse_args_atime() {
+        let args = |list: &[&str]| -> Vec<String> { list.iter().map(|a| a.to_string()).collect() };
+
+        let options = parse_args(&args(&["nsfs", "/mnt"])).unwrap();
+        assert_eq!(options.mountpoint, "/mnt");
+        assert_eq!(options.atime_policy, nsfs::AtimePolicy::Relatime);
+
+        let options = parse_args(&args(&["nsfs", "--atime", "strict", "/mnt"])).unwrap();
+        assert_eq!(options.atime_policy, nsfs::AtimePolicy::Strict);
+        let options = parse_args(&args(&["nsfs", "--atime", "noatime", "/mnt"])).unwrap();
+        assert_eq!(options.atime_policy, nsfs::AtimePolicy::Noatime);
+
+        assert!(parse_args(&args(&["nsfs", "--atime", "/mnt"])).is_err());
+        assert!(parse_args(&args(&["nsfs"])).is_err());
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let args = |list: &[&str]| -> Vec<String> { list.iter().map(|a| a.to_string()).collect() };
+
+        let options = parse_args(&args(&["nsfs", "/mnt"])).unwrap();
+        assert_eq!(
+            mount_options(&options),
+            vec![MountOption::FSName("nsfs".to_string())]
+        );
+
+        let options = parse_args(&args(&["nsfs", "-o", "allow_other,ro", "/mnt"])).unwrap();
+        assert_eq!(
+            mount_options(&options),
+            vec![
+                MountOption::FSName("nsfs".to_string()),
+                MountOption::AllowOther,
+                MountOption::RO
+            ]
+        );
+
+        let options = parse_args(&args(&["nsfs", "-o", "fsname=scratch", "/mnt"])).unwrap();
+        assert_eq!(
+            mount_options(&options),
+            vec![MountOption::FSName("scratch".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_mount_option() {
+        assert_eq!(
+            parse_mount_option("allow_other"),
+            Ok(MountOption::AllowOther)
+        );
+        assert_eq!(parse_mount_option("ro"), Ok(MountOption::RO));
+        assert_eq!(
+            parse_mount_option("fsname=tmp"),
+            Ok(MountOption::FSName("tmp".to_string()))
+        );
+        assert!(parse_mount_option("fsname=").is_err());
+        assert!(parse_mount_option("suid").is_err());
+
+        let args = |list: &[&str]| -> Vec<String> { list.iter().map(|a| a.to_string()).collect() };
+        assert!(parse_args(&args(&["nsfs", "-o", "exec", "/mnt"])).is_err());
+        assert!(parse_args(&args(&["nsfs", "/mnt", "-o"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_attr_ttl() {
+        let args = |list: &[&str]| -> Vec<String> { list.iter().map(|a| a.to_string()).collect() };
+
+        let options = parse_args(&args(&["nsfs", "/mnt"])).unwrap();
+        assert_eq!(options.attr_ttl, None);
+
+        let options = parse_args(&args(&["nsfs", "--attr-ttl", "60", "/mnt"])).unwrap();
+        assert_eq!(options.attr_ttl, Some(Duration::from_secs(60)));
+
+        assert!(parse_args(&args(&["nsfs", "--attr-ttl", "soon", "/mnt"])).is_err());
+        assert!(parse_args(&args(&["nsfs", "--attr-ttl"])).is_err());
+
+        let options = parse_args(&args(&["nsfs", "--negative-ttl", "5", "/mnt"])).unwrap();
+        assert_eq!(options.negative_ttl, Some(Duration::from_secs(5)));
+        assert!(parse_args(&args(&["nsfs", "--negative-ttl", "-1", "/mnt"])).is_err());
+    }
+
+    #[test]
+    fn test_debug_handles() {
+        let mut fs = nsfs::NsFS::new();
+        let (first, first_fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("first"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+        let (second, second_fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("second"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+        fs.write_handle(second_fh, b"Hello, Rust", 0).unwrap();
+
+        assert!(fs.lookup_handle(1, OsStr::new(nsfs::HANDLES_DIR)).is_none());
+        assert!(!fs
+            .list_dir(1)
+            .unwrap()
+            .iter()
+            .any(|(_, _, name)| name == nsfs::HANDLES_DIR));
+
+        fs.debug_handles = true;
+        let dir = fs.lookup_handle(1, OsStr::new(nsfs::HANDLES_DIR)).unwrap();
+        assert_eq!(dir.ino, nsfs::HANDLES_INO);
+        assert_eq!(dir.kind, FileType::Directory);
+
+        let entries = fs.list_dir(nsfs::HANDLES_INO).unwrap();
+        let names: Vec<&OsStr> = entries
+            .iter()
+            .map(|(_, _, name)| name.as_os_str())
+            .collect();
+        assert_eq!(names, vec![".", "..", "0", "1"]);
+
+        for (fh, ino, offset) in [(first_fh, first, 0), (second_fh, second, 11)] {
+            let name = fh.to_string();
+            let attrs = fs
+                .lookup_handle(nsfs::HANDLES_INO, OsStr::new(&name))
+                .unwrap();
+            let content = fs.handle_content(attrs.ino).unwrap();
+            assert_eq!(
+                content,
+                format!("ino: {}\nflags: 0o0\noffset: {}\n", ino, offset)
+            );
+            assert_eq!(attrs.size, content.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_whiteout() {
+        let mut fs = nsfs::NsFS::new();
+        let attrs = fs
+            .create_special(1, OsStr::new("whiteout"), libc::S_IFCHR | 0o600, 0, 0, 0, 0)
+            .unwrap();
+        assert_eq!(attrs.kind, FileType::CharDevice);
+        assert_eq!(attrs.rdev, 0);
+
+        let ino = fs
+            .lookup_entry(1, OsStr::new("whiteout"), 0, 0)
+            .unwrap()
+            .ino;
+        let attrs = fs.get_attr(ino).unwrap();
+        assert_eq!(attrs.kind, FileType::CharDevice);
+        assert_eq!(attrs.rdev, 0);
+
+        assert!(matches!(
+            fs.create_special(1, OsStr::new("dir"), libc::S_IFDIR | 0o700, 0, 0, 0, 0),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn test_mknod_mode() {
+        let mut fs = nsfs::NsFS::new();
+        let mode = libc::S_IFCHR | 0o600;
+        let attrs = fs
+            .create_special(1, OsStr::new("whiteout"), mode, 0, 0, 0, 0o022)
+            .unwrap();
+        assert_eq!((attrs.kind, attrs.perm), (FileType::CharDevice, 0o600));
+
+        let mode = libc::S_IFIFO | 0o666;
+        let attrs = fs
+            .create_special(1, OsStr::new("fifo"), mode, 0, 0, 0, 0o022)
+            .unwrap();
+        assert_eq!(attrs.perm, 0o644);
+    }
+
+    #[test]
+    fn test_overlay_opaque_xattr() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let name = OsStr::new("trusted.overlay.opaque");
+
+        assert!(matches!(
+            fs.get_xattr(dir, name),
+            Err(nsfs::Error::NoAttribute)
+        ));
+        assert!(matches!(
+            fs.set_xattr(dir, name, b"y", libc::XATTR_REPLACE),
+            Err(nsfs::Error::NoAttribute)
+        ));
+
+        fs.set_xattr(dir, name, b"y", libc::XATTR_CREATE).unwrap();
+        assert_eq!(*fs.get_xattr(dir, name).unwrap(), *b"y");
+        assert_eq!(fs.list_xattr(dir).unwrap(), b"trusted.overlay.opaque\0");
+        assert!(matches!(
+            fs.set_xattr(dir, name, b"y", libc::XATTR_CREATE),
+            Err(nsfs::Error::AlreadyExists)
+        ));
+
+        fs.remove_xattr(dir, name).unwrap();
+        assert!(fs.get_xattr(dir, name).is_err());
+        assert!(fs.list_xattr(dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_immediate() {
+        let mut fs = nsfs::NsFS::new();
+        let (ino, fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+
+        fs.write_handle(fh, b"Hello, Rust", 0).unwrap();
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Hello, Rust"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_write_buffered() {
+        let mut fs = nsfs::NsFS::new();
+        fs.buffered = true;
+        let (ino, fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+
+        assert_eq!(fs.write_handle(fh, b"Hello, ", 0).unwrap(), 7);
+        assert_eq!(fs.write_handle(fh, b"Rust", 7).unwrap(), 4);
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert!(data.is_empty()),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+        assert_eq!(fs.get_attr(ino).unwrap().size, 0);
+
+        fs.flush_handle(fh).unwrap();
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Hello, Rust"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+
+        fs.write_handle(fh, b"J", 0).unwrap();
+        fs.close_file(fh).unwrap();
+        match fs.read_file(ino, 1024, 0) {
+            Ok(data) => assert_eq!(data, b"Jello, Rust"),
+            Err(err) => panic!("read_file failed: {}", err),
+        }
+        assert!(fs.flush_handle(fh).is_err());
+    }
+
+    #[test]
+    fn test_write_through() {
+        let backing = nsfs::RecordingBacking::default();
+        let mut fs = nsfs::NsFS::new();
+        fs.backing = Box::new(backing.clone());
+        let (ino, fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+
+        fs.write_handle(fh, b"Hello, ", 0).unwrap();
+        fs.write_handle(fh, b"Rust", 7).unwrap();
+        assert_eq!(backing.persisted(), vec![ino, ino]);
+
+        backing.set_failing(true);
+        assert!(matches!(
+            fs.write_handle(fh, b"!", 11),
+            Err(nsfs::Error::Io)
+        ));
+
+        // the file is persisted again by the next sync
+        backing.set_failing(false);
+        fs.sync_all().unwrap();
+        assert_eq!(backing.persisted(), vec![ino, ino, ino]);
+    }
+
+    #[test]
+    fn test_failed_persist_logged() {
+        let dir = std::env::temp_dir().join(format!("nsfs-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        let backing = nsfs::RecordingBacking::default();
+        let mut fs = nsfs::NsFS::new();
+        fs.backing = Box::new(backing.clone());
+        fs.state_file = Some(path.clone());
+        fs.open_wal().unwrap();
+        let events = fs.subscribe();
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        // what's in memory is in the log, even if the backing failed
+        backing.set_failing(true);
+        assert!(matches!(
+            fs.write_file(ino, b"Hello", 0),
+            Err(nsfs::Error::Io)
+        ));
+        assert!(matches!(
+            fs.replace_content(ino, b"Hello, Rust"),
+            Err(nsfs::Error::Io)
+        ));
+        assert_eq!(events.try_iter().count(), 3);
+
+        let replayed = nsfs::NsFS::load_from(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replayed.to_json_with_content(), fs.to_json_with_content());
+    }
+
+    #[test]
+    fn test_write_back() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let backing = nsfs::RecordingBacking::default();
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(clock.clone());
+        fs.backing = Box::new(backing.clone());
+        fs.write_policy = nsfs::WritePolicy::WriteBack;
+        let (ino, fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+
+        fs.write_handle(fh, b"Hello, ", 0).unwrap();
+        fs.write_handle(fh, b"Rust", 7).unwrap();
+        assert!(backing.persisted().is_empty());
+
+        fs.sync_handle(fh).unwrap();
+        assert_eq!(backing.persisted(), vec![ino]);
+        fs.sync_handle(fh).unwrap();
+        assert_eq!(backing.persisted(), vec![ino]);
+
+        fs.write_handle(fh, b"J", 0).unwrap();
+        assert_eq!(backing.persisted(), vec![ino]);
+        clock.advance(Duration::from_secs(31));
+        fs.write_handle(fh, b"B", 0).unwrap();
+        assert_eq!(backing.persisted(), vec![ino, ino]);
+    }
+
+    #[test]
+    fn test_buffered_write_capacity() {
+        let mut fs = nsfs::NsFS::new();
+        fs.buffered = true;
+        fs.capacity_bytes = Some(100);
+        fs.set_quota(1000, 150);
+        let (ino, fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("test"), 0, 1000, 1000, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+
+        // the space is reserved when the write is buffered
+        assert_eq!(fs.write_handle(fh, &[1; 60], 0).unwrap(), 60);
+        assert_eq!(fs.write_handle(fh, &[2; 60], 60).unwrap(), 40);
+        assert!(matches!(
+            fs.write_handle(fh, &[3; 10], 100),
+            Err(nsfs::Error::NoSpace)
+        ));
+        assert_eq!(fs.get_attr(ino).unwrap().size, 0);
+        fs.flush_handle(fh).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().size, 100);
+
+        fs.capacity_bytes = None;
+        assert_eq!(fs.write_handle(fh, &[4; 40], 100).unwrap(), 40);
+        assert!(matches!(
+            fs.write_handle(fh, &[4; 20], 140),
+            Err(nsfs::Error::QuotaExceeded)
+        ));
+    }
+
+    #[test]
+    fn test_buffered_flush_short() {
+        let mut fs = nsfs::NsFS::new();
+        fs.buffered = true;
+        fs.capacity_bytes = Some(100);
+        let (ino, fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+        fs.write_handle(fh, &[1; 30], 0).unwrap();
+        fs.write_handle(fh, &[2; 30], 30).unwrap();
+
+        // other files can't take the space the buffered writes reserved
+        let (other, other_fh) = {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+        fs.close_file(other_fh).unwrap();
+        assert_eq!(fs.write_file(other, &[3; 80], 0).unwrap(), 40);
+        assert!(matches!(
+            fs.allocate(other, 0, 50, 0),
+            Err(nsfs::Error::NoSpace)
+        ));
+        let grow = nsfs::SetAttr {
+            size: Some(50),
+            ..Default::default()
+        };
+        assert!(matches!(
+            fs.set_attr(other, grow),
+            Err(nsfs::Error::NoSpace)
+        ));
+        assert_eq!(fs.free_bytes(), Some(0));
+
+        // only shrinking the filesystem makes the flush come up short
+        fs.capacity_bytes = Some(60);
+        assert!(matches!(fs.flush_handle(fh), Err(nsfs::Error::NoSpace)));
+        assert_eq!(fs.get_attr(ino).unwrap().size, 20);
+
+        // nothing is dropped on release, the rest is written once there's room
+        assert!(matches!(fs.close_file(fh), Err(nsfs::Error::NoSpace)));
+        let truncate = nsfs::SetAttr {
+            size: Some(0),
+            ..Default::default()
+        };
+        fs.set_attr(other, truncate).unwrap();
+        fs.sync_all().unwrap();
+        let data = fs.read_file(ino, 100, 0).unwrap();
+        assert_eq!(&data[..30], &[1; 30][..]);
+        assert_eq!(&data[30..], &[2; 30][..]);
+        assert_eq!(fs.memory_stats().open_files, 0);
+    }
+
+    #[test]
+    fn test_tree_eq() {
+        let mut a = nsfs::NsFS::new();
+        let docs = a
+            .create_dir(1, OsStr::new("docs"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let (readme, fh) = {
+            let (attrs, fh) = a
+                .create_file(docs, OsStr::new("readme"), 0, 0, 0, None, 0)
+                .unwrap();
+            (attrs.ino, fh)
+        };
+        a.write_handle(fh, b"Hello, Rust", 0).unwrap();
+        a.create_file(1, OsStr::new("empty"), 0, 0, 0, None, 0)
+            .unwrap();
+
+        // same tree, built in another order and through a rename
+        let mut b = nsfs::NsFS::new();
+        b.create_file(1, OsStr::new("empty"), 0, 0, 0, None, 0)
+            .unwrap();
+        let (_, fh) = b
+            .create_file(1, OsStr::new("draft"), 0, 0, 0, None, 0)
+            .unwrap();
+        b.write_handle(fh, b"Hello, Rust", 0).unwrap();
+        let docs = b
+            .create_dir(1, OsStr::new("docs"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        b.rename_ex(1, OsStr::new("draft"), docs, OsStr::new("readme"), 0, 0)
+            .unwrap();
+        assert!(a.tree_eq(&b));
+        assert!(b.tree_eq(&a));
+
+        a.write_file(readme, b"J", 0).unwrap();
+        assert!(!a.tree_eq(&b));
+        assert_eq!(
+            a.tree_diff(&b),
+            vec![nsfs::Difference::Content(PathBuf::from("/docs/readme"))]
+        );
+
+        b.create_dir(1, OsStr::new("extra"), 0, 0, None, 0).unwrap();
+        assert_eq!(
+            a.tree_diff(&b),
+            vec![
+                nsfs::Difference::Content(PathBuf::from("/docs/readme")),
+                nsfs::Difference::OnlyInOther(PathBuf::from("/extra")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(fs.get_attr(ino).unwrap().blocks, 0);
+        assert_eq!(fs.get_attr(ino).unwrap().blksize, 4096);
+
+        // blocks are counted in 512 byte units, whatever the block size
+        fs.write_file(ino, &[1; 5000], 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().blocks, 16);
+
+        let truncate = nsfs::SetAttr {
+            size: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(fs.set_attr(ino, truncate).unwrap().blocks, 8);
+        assert_eq!(fs.read_file(ino, 1024, 0).unwrap().len(), 100);
+
+        fs.allocate(ino, 0, 8192, 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().size, 8192);
+        assert_eq!(fs.get_attr(ino).unwrap().blocks, 16);
+        fs.allocate(ino, 0, 16384, libc::FALLOC_FL_KEEP_SIZE)
+            .unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().blocks, 16);
+    }
+
+    #[test]
+    fn test_parent_dir_times() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(clock.clone());
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let created = fs.get_attr(dir).unwrap().mtime;
+
+        clock.advance(Duration::from_secs(1));
+        fs.create_file(dir, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap();
+        let attrs = fs.get_attr(dir).unwrap();
+        assert_eq!(attrs.mtime, created + Duration::from_secs(1));
+        assert_eq!(attrs.ctime, attrs.mtime);
+
+        clock.advance(Duration::from_secs(1));
+        fs.remove_file(dir, OsStr::new("test"), 0).unwrap();
+        let attrs = fs.get_attr(dir).unwrap();
+        assert_eq!(attrs.mtime, created + Duration::from_secs(2));
+        assert_eq!(attrs.ctime, attrs.mtime);
+    }
+
+    #[test]
+    fn test_ownership() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 1000, 100, None, 0)
+            .unwrap();
+        assert_eq!((dir.uid, dir.gid), (1000, 100));
+        let dir = dir.ino;
+
+        let (attrs, _) = fs
+            .create_file(dir, OsStr::new("file"), 0, 1001, 101, None, 0)
+            .unwrap();
+        assert_eq!((attrs.uid, attrs.gid), (1001, 101));
+
+        let attrs = fs
+            .create_special(dir, OsStr::new("fifo"), libc::S_IFIFO, 0, 1002, 102, 0)
+            .unwrap();
+        assert_eq!((attrs.uid, attrs.gid), (1002, 102));
+    }
+
+    #[test]
+    fn test_sticky_dir() {
+        let mut fs = nsfs::NsFS::new();
+        let tmp = fs
+            .create_dir(1, OsStr::new("tmp"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let sticky = nsfs::SetAttr {
+            mode: Some(0o1777),
+            ..Default::default()
+        };
+        fs.set_attr(tmp, sticky).unwrap();
+        fs.create_file(tmp, OsStr::new("alice"), 0, 1000, 1000, None, 0)
+            .unwrap();
+        fs.create_file(tmp, OsStr::new("bob"), 0, 1001, 1001, None, 0)
+            .unwrap();
+
+        assert!(matches!(
+            fs.remove_file(tmp, OsStr::new("alice"), 1001),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.rename_ex(tmp, OsStr::new("bob"), tmp, OsStr::new("alice"), 0, 1001),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(fs.find_node(tmp, OsStr::new("alice")).is_ok());
+
+        fs.rename_ex(tmp, OsStr::new("alice"), tmp, OsStr::new("carol"), 0, 1000)
+            .unwrap();
+        fs.remove_file(tmp, OsStr::new("carol"), 1000).unwrap();
+        fs.remove_file(tmp, OsStr::new("bob"), 0).unwrap();
+        assert_eq!(fs.list_dir(tmp).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_max_file_size() {
+        let mut fs = nsfs::NsFS::new();
+        fs.max_file_size = Some(16);
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        assert_eq!(fs.write_file(ino, &[1; 16], 0).unwrap(), 16);
+        assert!(matches!(
+            fs.write_file(ino, &[1; 1], 16),
+            Err(nsfs::Error::FileTooBig)
+        ));
+        assert_eq!(fs.get_attr(ino).unwrap().size, 16);
+
+        let grow = nsfs::SetAttr {
+            size: Some(17),
+            ..Default::default()
+        };
+        assert!(matches!(
+            fs.set_attr(ino, grow),
+            Err(nsfs::Error::FileTooBig)
+        ));
+        assert!(matches!(
+            fs.allocate(ino, 8, 9, 0),
+            Err(nsfs::Error::FileTooBig)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+
+        let snap = fs.snapshot().unwrap();
+        let mut expected = nsfs::NsFS::new();
+        expected.restore(snap.clone());
+        assert!(fs.tree_eq(&expected));
+
+        fs.write_file(file, b"J", 0).unwrap();
+        fs.create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap();
+        fs.rename_ex(dir, OsStr::new("file"), 1, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        assert!(!fs.tree_eq(&expected));
+
+        fs.restore(snap);
+        assert!(fs.tree_eq(&expected));
+        assert_eq!(fs.read_file(file, 1024, 0).unwrap(), b"Hello, Rust");
+        let other = fs
+            .create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert!(other > file + 1);
+    }
+
+    #[test]
+    fn test_snapshot_symlinks_and_spilled() {
+        let dir = std::env::temp_dir().join(format!("nsfs-snap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut fs = nsfs::NsFS::new();
+        fs.spill_path = Some(dir.clone());
+        fs.spill_high_water = 4096;
+        let cold = fs
+            .create_file(1, OsStr::new("cold"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(cold, &[1; 4], 0).unwrap();
+        let hot = fs
+            .create_file(1, OsStr::new("hot"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(hot, &[2; 4096], 0).unwrap();
+        fs.read_file(hot, 1, 0).unwrap();
+        assert_eq!(
+            fs.storage_info(cold).unwrap().representation,
+            nsfs::Representation::Spilled
+        );
+        let link = fs
+            .create_symlink(1, OsStr::new("link"), Path::new("cold"), 0, 0)
+            .unwrap()
+            .ino;
+
+        let snap = fs.snapshot().unwrap();
+        fs.remove_file(1, OsStr::new("link"), 0).unwrap();
+        fs.write_file(cold, &[3; 4], 0).unwrap();
+        fs.read_file(hot, 1, 0).unwrap();
+        fs.restore(snap);
+
+        assert_eq!(fs.read_link(link).unwrap(), Path::new("cold"));
+        assert_eq!(fs.read_file(cold, 64, 0).unwrap(), [1; 4]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_clone_xattr() {
+        let mut fs = nsfs::NsFS::new();
+        let template = fs
+            .create_dir(1, OsStr::new("template"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(template, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+        fs.create_symlink(template, OsStr::new("link"), Path::new("file"), 0, 0)
+            .unwrap();
+
+        let clone = OsStr::new("user.nsfs.clone");
+        fs.control(template, clone, b"project", 0).unwrap();
+        let project = fs.lookup_entry(1, OsStr::new("project"), 0, 0).unwrap().ino;
+        let copy = fs
+            .lookup_entry(project, OsStr::new("file"), 0, 0)
+            .unwrap()
+            .ino;
+        assert_ne!(copy, file);
+        assert_eq!(fs.read_file(copy, 64, 0).unwrap(), b"Hello, Rust");
+        let link = fs
+            .lookup_entry(project, OsStr::new("link"), 0, 0)
+            .unwrap()
+            .ino;
+        assert_eq!(fs.read_link(link).unwrap(), Path::new("file"));
+
+        fs.control(file, clone, b"copy", 0).unwrap();
+        assert!(fs.lookup_entry(template, OsStr::new("copy"), 0, 0).is_ok());
+        assert!(matches!(
+            fs.control(file, clone, b"copy", 0),
+            Err(nsfs::Error::AlreadyExists)
+        ));
+        assert!(matches!(
+            fs.control(1, clone, b"root", 0),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_xattrs() {
+        let mut fs = nsfs::NsFS::new();
+        let queue = nsfs::InvalidationQueue::default();
+        fs.invalidations = Some(queue.clone());
+        let file = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+        let snapshot = OsStr::new("user.nsfs.snapshot");
+        let restore = OsStr::new("user.nsfs.restore");
+        fs.control(1, snapshot, b"before", 0).unwrap();
+        assert!(matches!(
+            fs.get_xattr(1, snapshot),
+            Err(nsfs::Error::NoAttribute)
+        ));
+
+        fs.write_file(file, b"J", 0).unwrap();
+        fs.create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap();
+        queue.drain();
+        fs.control(1, restore, b"before", 0).unwrap();
+        assert_eq!(fs.read_file(file, 64, 0).unwrap(), b"Hello, Rust");
+        assert!(fs.lookup_entry(1, OsStr::new("other"), 0, 0).is_err());
+        assert!(queue.drain().contains(&file));
+
+        assert!(matches!(
+            fs.control(1, restore, b"missing", 0),
+            Err(nsfs::Error::NotFound)
+        ));
+        assert!(matches!(
+            fs.control(file, snapshot, b"before", 0),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            fs.control(1, snapshot, b"", 0),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+
+        // only root and the owner of the root may roll back everything
+        fs.set_attr(
+            1,
+            nsfs::SetAttr {
+                uid: Some(1000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        fs.control(1, snapshot, b"owner", 1000).unwrap();
+        assert!(matches!(
+            fs.control(1, restore, b"owner", 1001),
+            Err(nsfs::Error::NotPermitted)
+        ));
+        assert!(matches!(
+            fs.set_xattr(1, restore, b"owner", 0),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn test_clone_subtree() {
+        let mut fs = nsfs::NsFS::new();
+        let template = fs
+            .create_dir(1, OsStr::new("template"), 1000, 1000, None, 0)
+            .unwrap()
+            .ino;
+        let nested = fs
+            .create_dir(template, OsStr::new("nested"), 1000, 1000, None, 0)
+            .unwrap()
+            .ino;
+        let top = fs
+            .create_file(template, OsStr::new("top"), 0, 1000, 1000, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let deep = fs
+            .create_file(nested, OsStr::new("deep"), 0, 1000, 1000, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(top, b"Hello", 0).unwrap();
+        fs.write_file(deep, b"Rust", 0).unwrap();
+
+        let copy = fs.clone_subtree(template, 1, OsStr::new("copy")).unwrap();
+        assert_eq!(copy.uid, 1000);
+        let copy = copy.ino;
+        assert_ne!(copy, template);
+
+        let copied_nested = fs.find_node(copy, OsStr::new("nested")).unwrap().index;
+        let copied_top = fs.find_node(copy, OsStr::new("top")).unwrap().index;
+        let copied_deep = fs
+            .find_node(copied_nested, OsStr::new("deep"))
+            .unwrap()
+            .index;
+        assert_ne!(copied_nested, nested);
+        assert_ne!(copied_top, top);
+        assert_ne!(copied_deep, deep);
+        assert_eq!(fs.read_file(copied_top, 1024, 0).unwrap(), b"Hello");
+        assert_eq!(fs.read_file(copied_deep, 1024, 0).unwrap(), b"Rust");
+
+        // the copy is independent of its template
+        fs.write_file(copied_deep, b"B", 0).unwrap();
+        assert_eq!(fs.read_file(deep, 1024, 0).unwrap(), b"Rust");
+
+        assert!(matches!(
+            fs.clone_subtree(template, nested, OsStr::new("loop")),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn test_clone_subtree_limits() {
+        let dir = std::env::temp_dir().join(format!("nsfs-clone-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        let mut fs = nsfs::NsFS::new();
+        fs.state_file = Some(path.clone());
+        fs.open_wal().unwrap();
+        let events = fs.subscribe();
+        let template = fs
+            .create_dir(1, OsStr::new("template"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(template, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, &[7; 8000], 0).unwrap();
+        fs.link_node(file, template, OsStr::new("alias")).unwrap();
+
+        // the copy has to fit before anything is copied
+        fs.capacity_bytes = Some(10000);
+        assert!(matches!(
+            fs.clone_subtree(template, 1, OsStr::new("full")),
+            Err(nsfs::Error::NoSpace)
+        ));
+        assert!(fs.lookup_entry(1, OsStr::new("full"), 0, 0).is_err());
+        assert_eq!(fs.used_bytes(), 8000);
+        fs.capacity_bytes = None;
+        fs.set_quota(0, 10000);
+        assert!(matches!(
+            fs.clone_subtree(template, 1, OsStr::new("full")),
+            Err(nsfs::Error::QuotaExceeded)
+        ));
+        fs.set_quota(0, 20000);
+        events.try_iter().count();
+
+        // links within the tree stay links of a single copy
+        let copy = fs
+            .clone_subtree(template, 1, OsStr::new("copy"))
+            .unwrap()
+            .ino;
+        let first = fs.lookup_entry(copy, OsStr::new("file"), 0, 0).unwrap();
+        assert_eq!(first.nlink, 2);
+        let first = first.ino;
+        assert_eq!(
+            fs.lookup_entry(copy, OsStr::new("alias"), 0, 0)
+                .unwrap()
+                .ino,
+            first
+        );
+        assert_ne!(first, file);
+        assert_eq!(fs.used_bytes(), 16000);
+        assert_eq!(events.try_iter().count(), 3);
+        assert!(matches!(
+            fs.clone_subtree(1 << 40, 1, OsStr::new("stale")),
+            Err(nsfs::Error::NotFound)
+        ));
+
+        // the copy is in the write-ahead log
+        let replayed = nsfs::NsFS::load_from(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replayed.to_json_with_content(), fs.to_json_with_content());
+        assert_eq!(replayed.get_attr(first).unwrap().nlink, 2);
+    }
+
+    #[test]
+    fn test_save_on_shutdown() {
+        let path = std::env::temp_dir().join(format!("nsfs-state-{}", std::process::id()));
+        let mut fs = nsfs::NsFS::new();
+        fs.state_file = Some(path.clone());
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 1000, 1000, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 1000, 1000, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+        fs.set_xattr(file, OsStr::new("user.tag"), b"value", 0)
+            .unwrap();
+        fs.create_special(1, OsStr::new("fifo"), libc::S_IFIFO, 0, 0, 0, 0)
+            .unwrap();
+
+        fs.destroy();
+        let loaded = nsfs::NsFS::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.tree_eq(&fs));
+        assert_eq!(loaded.get_attr(file).unwrap().uid, 1000);
+        assert_eq!(
+            *loaded.get_xattr(file, OsStr::new("user.tag")).unwrap(),
+            *b"value"
+        );
+        assert_eq!(loaded.path_of(file), Some(PathBuf::from("/dir/file")));
+    }
+
+    #[test]
+    fn test_dir_size() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        assert_eq!(fs.get_attr(dir).unwrap().size, 0);
+
+        fs.create_file(dir, OsStr::new("first"), 0, 0, 0, None, 0)
+            .unwrap();
+        assert_eq!(fs.get_attr(dir).unwrap().size, 32);
+        fs.create_dir(dir, OsStr::new("second"), 0, 0, None, 0)
+            .unwrap();
+        assert_eq!(fs.get_attr(dir).unwrap().size, 64);
+
+        fs.rename_ex(dir, OsStr::new("first"), 1, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        assert_eq!(fs.get_attr(dir).unwrap().size, 32);
+        assert_eq!(fs.get_attr(1).unwrap().size, 64);
+
+        fs.remove_file(1, OsStr::new("moved"), 0).unwrap();
+        assert_eq!(fs.get_attr(1).unwrap().size, 32);
+    }
+
+    #[test]
+    fn test_inode_allocator() {
+        struct Fixed(Vec<u64>, std::sync::Arc<std::sync::Mutex<Vec<u64>>>);
+
+        impl nsfs::InodeAllocator for Fixed {
+            fn next(&mut self) -> u64 {
+                self.0.remove(0)
+            }
+
+            fn free(&mut self, ino: u64) {
+                self.1.lock().unwrap().push(ino);
+            }
+        }
+
+        let freed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let allocator = Fixed(vec![100, 7, 42], freed.clone());
+        let mut fs = nsfs::NsFS::with_allocator(Box::new(allocator));
+
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let first = fs
+            .create_file(dir, OsStr::new("first"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let second = fs
+            .create_file(dir, OsStr::new("second"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!((dir, first, second), (100, 7, 42));
+        assert_eq!(fs.path_of(first), Some(PathBuf::from("/dir/first")));
+
+        fs.remove_file(dir, OsStr::new("second"), 0).unwrap();
+        assert!(freed.lock().unwrap().is_empty());
+        fs.forget_inode(second, 1);
+        assert_eq!(*freed.lock().unwrap(), vec![42]);
+    }
+
+    #[test]
+    fn test_scattered_writes() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let mut expected = Vec::new();
+
+        // a simple LCG keeps the offsets scattered but reproducible
+        let mut seed: u64 = 42;
+        for i in 0..2000u32 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let offset = (seed >> 33) as usize % 100_000;
+            let data = i.to_le_bytes().repeat((seed % 1500) as usize + 1);
+
+            if expected.len() < offset + data.len() {
+                expected.resize(offset + data.len(), 0);
+            }
+            expected[offset..offset + data.len()].copy_from_slice(&data);
+            fs.write_file(ino, &data, offset).unwrap();
+        }
+
+        assert_eq!(fs.get_attr(ino).unwrap().size, expected.len() as u64);
+        for (offset, size) in [(0, expected.len()), (4000, 200), (8191, 2), (99_999, 10)] {
+            let end = expected.len().min(offset + size);
+            assert_eq!(
+                fs.read_file(ino, size, offset).unwrap(),
+                &expected[offset..end]
+            );
+        }
+
+        fs.write_file(ino, b"tail", expected.len() + 10_000)
+            .unwrap();
+        let hole = fs.read_file(ino, 10_000, expected.len()).unwrap();
+        assert!(hole.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut fs = nsfs::NsFS::new();
+        let first = fs
+            .create_file(1, OsStr::new("first"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let second = fs
+            .create_file(1, OsStr::new("second"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let content = b"Hello, Rust".repeat(1000);
+
+        fs.write_file(first, &content, 0).unwrap();
+        assert_eq!(fs.dedup_savings(), 0);
+        fs.write_file(second, &content, 0).unwrap();
+        let shared = fs.storage_info(first).unwrap().resident_bytes;
+        assert_eq!(fs.dedup_savings(), shared);
+
+        fs.write_file(second, b"J", 0).unwrap();
+        assert_eq!(fs.dedup_savings(), shared - 4096);
+        assert_eq!(&fs.read_file(first, 5, 0).unwrap(), b"Hello");
+        assert_eq!(&fs.read_file(second, 5, 0).unwrap(), b"Jello");
+
+        fs.write_file(second, b"H", 0).unwrap();
+        assert_eq!(fs.dedup_savings(), shared);
+
+        // segments no file holds any more don't pile up in the index
+        for i in 0..10_000u32 {
+            fs.write_file(second, &i.to_le_bytes(), 0).unwrap();
+        }
+        assert!(fs.indexed_segments() < 2048);
+        assert_eq!(fs.dedup_savings(), shared - 4096);
+    }
+
+    #[test]
+    fn test_read_write_dir() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+
+        assert!(matches!(
+            fs.read_file(dir, 1024, 0),
+            Err(nsfs::Error::IsADirectory)
+        ));
+        assert!(matches!(
+            fs.write_file(dir, b"Hello, Rust", 0),
+            Err(nsfs::Error::IsADirectory)
+        ));
+        assert!(matches!(
+            fs.read_file(1, 1024, 0),
+            Err(nsfs::Error::IsADirectory)
+        ));
+        assert!(matches!(
+            fs.read_file(dir + 1, 1024, 0),
+            Err(nsfs::Error::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_set_attr_now() {
+        // every reading of this clock is a nanosecond later than the last
+        struct Ticking(std::sync::Mutex<SystemTime>);
+
+        impl nsfs::Clock for Ticking {
+            fn now(&self) -> SystemTime {
+                let mut now = self.0.lock().unwrap();
+                *now += Duration::from_nanos(1);
+                *now
+            }
+        }
+
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(Ticking(std::sync::Mutex::new(SystemTime::UNIX_EPOCH)));
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        let touch = nsfs::SetAttr {
+            atime: Some(TimeOrNow::Now),
+            mtime: Some(TimeOrNow::Now),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, touch).unwrap();
+        assert_eq!(attrs.atime, attrs.mtime);
+        assert_eq!(attrs.ctime, attrs.mtime);
+    }
+
+    #[test]
+    fn test_read_file_shared() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(clock.clone());
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let content = b"Hello, Rust".repeat(1000);
+        fs.write_file(ino, &content, 0).unwrap();
+        let written = fs.get_attr(ino).unwrap().atime;
+
+        clock.advance(Duration::from_secs(1));
+        {
+            let shared = &fs;
+            let first = shared.read_file_shared(ino, 5, 0).unwrap();
+            let spanning = shared.read_file_shared(ino, 100, 4090).unwrap();
+            assert_eq!(&first[..], b"Hello");
+            assert_eq!(&spanning[..], &content[4090..4190]);
+        }
+        assert_eq!(fs.get_attr(ino).unwrap().atime, written);
+
+        fs.apply_access_times();
+        assert_eq!(
+            fs.get_attr(ino).unwrap().atime,
+            written + Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_lookup_errors() {
+        let mut fs = nsfs::NsFS::new();
+        let file = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        assert!(fs.lookup_entry(1, OsStr::new("file"), 0, 0).is_ok());
+        match fs.lookup_entry(1, OsStr::new("missing"), 0, 0) {
+            Err(err) => assert_eq!(c_int::from(err), libc::ENOENT),
+            Ok(_) => panic!("lookup of a missing entry succeeded"),
+        }
+        match fs.lookup_entry(file, OsStr::new("child"), 0, 0) {
+            Err(err) => assert_eq!(c_int::from(err), libc::ENOTDIR),
+            Ok(_) => panic!("lookup under a file succeeded"),
+        }
+        match fs.lookup_entry(file + 100, OsStr::new("child"), 0, 0) {
+            Err(err) => assert_eq!(c_int::from(err), libc::ENOENT),
+            Ok(_) => panic!("lookup under a missing parent succeeded"),
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut fs = nsfs::NsFS::new();
+        fs.capacity_bytes = Some(10_000);
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(fs.statfs_blocks(), (2, 2));
+
+        let mut offset = 0;
+        loop {
+            match fs.write_file(ino, &[1; 4096], offset) {
+                Ok(written) => offset += written,
+                Err(nsfs::Error::NoSpace) => break,
+                Err(err) => panic!("write_file failed: {}", err),
+            }
+        }
+        assert_eq!(offset, 10_000);
+        assert_eq!(fs.used_bytes(), 10_000);
+        assert_eq!(fs.free_bytes(), Some(0));
+        assert_eq!(fs.statfs_blocks(), (2, 0));
+
+        let other = fs
+            .create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert!(matches!(
+            fs.allocate(other, 0, 1, 0),
+            Err(nsfs::Error::NoSpace)
+        ));
+        // overwriting in place doesn't need more space
+        assert_eq!(fs.write_file(ino, b"Hello, Rust", 0).unwrap(), 11);
+    }
+
+    #[test]
+    fn test_partial_write() {
+        let mut fs = nsfs::NsFS::new();
+        fs.capacity_bytes = Some(100);
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        assert_eq!(fs.write_file(ino, &[1; 60], 0).unwrap(), 60);
+        // a hole past the end takes space too, so nothing fits
+        assert!(matches!(
+            fs.write_file(ino, &[2; 10], 150),
+            Err(nsfs::Error::NoSpace)
+        ));
+        assert_eq!(fs.write_file(ino, &[2; 60], 60).unwrap(), 40);
+        assert_eq!(fs.get_attr(ino).unwrap().size, 100);
+        let data = fs.read_file(ino, 200, 0).unwrap();
+        assert_eq!(&data[..60], &[1; 60][..]);
+        assert_eq!(&data[60..], &[2; 40][..]);
+
+        // only the part overwriting existing content fits
+        assert_eq!(fs.write_file(ino, &[3; 20], 90).unwrap(), 10);
+        assert!(matches!(
+            fs.write_file(ino, &[3; 20], 100),
+            Err(nsfs::Error::NoSpace)
+        ));
+        assert_eq!(fs.used_bytes(), 100);
+    }
+
+    #[test]
+    fn test_dir_search_permission() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 1000, 100, None, 0)
+            .unwrap()
+            .ino;
+        fs.create_file(dir, OsStr::new("file"), 0, 1000, 100, None, 0)
+            .unwrap();
+        let name = OsStr::new("file");
+
+        let perm = |fs: &mut nsfs::NsFS, mode| {
+            let changes = nsfs::SetAttr {
+                mode: Some(mode),
+                ..Default::default()
+            };
+            fs.set_attr(dir, changes).unwrap();
+        };
+
+        perm(&mut fs, 0o710);
+        assert!(fs.lookup_entry(dir, name, 1000, 100).is_ok());
+        assert!(fs.lookup_entry(dir, name, 2000, 100).is_ok());
+        assert!(matches!(
+            fs.lookup_entry(dir, name, 2000, 200),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(fs.lookup_entry(dir, name, 0, 0).is_ok());
+
+        perm(&mut fs, 0o600);
+        assert!(matches!(
+            fs.lookup_entry(dir, name, 1000, 100),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.lookup_entry(dir, OsStr::new("missing"), 1000, 100),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(fs.lookup_entry(dir, name, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut fs = nsfs::NsFS::new();
+        assert!(fs.metrics().is_empty());
+
+        fs.count_op("write");
+        fs.count_op("lookup");
+        fs.count_op("write");
+        assert_eq!(fs.metrics(), vec![("lookup", 1), ("write", 2)]);
+    }
+
+    #[test]
+    fn test_touch_nanoseconds() {
+        let path = std::env::temp_dir().join(format!("nsfs-touch-{}", std::process::id()));
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let atime = std::time::UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        let mtime = std::time::UNIX_EPOCH + Duration::new(1_700_000_000, 987_654_321);
+
+        let attrs = fs
+            .touch(
+                ino,
+                Some(TimeOrNow::SpecificTime(atime)),
+                Some(TimeOrNow::SpecificTime(mtime)),
+            )
+            .unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (atime, mtime));
+
+        fs.save_to(&path).unwrap();
+        let loaded = nsfs::NsFS::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let attrs = loaded.get_attr(ino).unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (atime, mtime));
+    }
+
+    #[test]
+    fn test_text_busy() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, b"#!/bin/sh", 0).unwrap();
+
+        let exec = fs.open_file(ino, libc::O_RDONLY | 0x20);
+        let other = fs.open_file(ino, libc::O_RDONLY | 0x20);
+        assert!(matches!(
+            fs.write_file(ino, b"Hello, Rust", 0),
+            Err(nsfs::Error::TextBusy)
+        ));
+
+        fs.close_file(exec).unwrap();
+        assert!(matches!(
+            fs.write_file(ino, b"Hello, Rust", 0),
+            Err(nsfs::Error::TextBusy)
+        ));
+        fs.close_file(other).unwrap();
+        assert_eq!(fs.write_file(ino, b"Hello, Rust", 0).unwrap(), 11);
+    }
+
+    #[test]
+    fn test_read_dir_cookies() {
+        let mut fs = nsfs::NsFS::new();
+        for name in ["a", "b", "c", "d"] {
+            fs.create_file(1, OsStr::new(name), 0, 0, 0, None, 0)
+                .unwrap();
+        }
+
+        // the first page only has room for three entries
+        let first: Vec<_> = fs.read_dir(1, 0).unwrap().into_iter().take(3).collect();
+        let last_cookie = first.last().unwrap().0;
+
+        fs.create_file(1, OsStr::new("0"), 0, 0, 0, None, 0)
+            .unwrap();
+        fs.remove_file(1, OsStr::new(&first[2].3), 0).unwrap();
+
+        let rest = fs.read_dir(1, last_cookie).unwrap();
+        let mut seen: Vec<std::ffi::OsString> = first.into_iter().map(|entry| entry.3).collect();
+        for (cookie, _, _, name) in rest {
+            assert!(cookie > last_cookie);
+            assert!(!seen.contains(&name), "{:?} listed twice", name);
+            seen.push(name);
+        }
+        assert_eq!(seen.len(), 6);
+        assert!(seen.contains(&"0".into()));
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut fs = nsfs::NsFS::new();
+        fs.set_quota(1000, 16);
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 1000, 1000, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let other = fs
+            .create_file(1, OsStr::new("other"), 0, 1000, 1000, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        assert_eq!(fs.write_file(ino, b"Hello, Rust", 0).unwrap(), 11);
+        assert_eq!(fs.write_file(other, b"Hello", 0).unwrap(), 5);
+        assert_eq!(fs.usage(1000), 16);
+        assert!(matches!(
+            fs.write_file(other, b"!", 5),
+            Err(nsfs::Error::QuotaExceeded)
+        ));
+        // overwriting doesn't use more space, and other uids aren't limited
+        assert_eq!(fs.write_file(ino, b"hello", 0).unwrap(), 5);
+        let root = fs
+            .create_file(1, OsStr::new("root"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(fs.write_file(root, &[0; 64], 0).unwrap(), 64);
+
+        fs.remove_file(1, OsStr::new("test"), 0).unwrap();
+        fs.forget_inode(ino, 1);
+        assert_eq!(fs.usage(1000), 5);
+        assert_eq!(fs.write_file(other, b", Rust", 5).unwrap(), 6);
+        assert_eq!(parse_quota("1000=16"), Some((1000, 16)));
+    }
+
+    #[test]
+    fn test_trace_line() {
+        assert_eq!(
+            trace_line("unlink", 1, Some(Path::new("/dir/file")), Ok(())),
+            "unlink ino=1 path=/dir/file: ok"
+        );
+        assert_eq!(
+            trace_line("getattr", 42, None, Err(libc::ENOENT)),
+            format!(
+                "getattr ino=42 path=?: {}",
+                io::Error::from_raw_os_error(libc::ENOENT)
+            )
+        );
+        assert_eq!(status(&Ok::<_, c_int>(5)), Ok(()));
+    }
+
+    #[test]
+    fn test_bmap() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, &[1; 1500], 0).unwrap();
+
+        assert_eq!(fs.bmap(ino, 512, 0).unwrap(), 0);
+        assert_eq!(fs.bmap(ino, 512, 2).unwrap(), 2);
+        assert!(matches!(
+            fs.bmap(ino, 512, 3),
+            Err(nsfs::Error::NoSuchAddress)
+        ));
+        assert!(matches!(
+            fs.bmap(ino, 4096, 1),
+            Err(nsfs::Error::NoSuchAddress)
+        ));
+        assert!(matches!(
+            fs.bmap(ino, 0, 0),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn test_default_permissions() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 1000, 1000, None, 0)
+            .unwrap()
+            .ino;
+        fs.create_file(dir, OsStr::new("file"), 0, 1000, 1000, None, 0)
+            .unwrap();
+        let changes = nsfs::SetAttr {
+            mode: Some(0o1700),
+            ..Default::default()
+        };
+        fs.set_attr(dir, changes).unwrap();
+        assert!(matches!(
+            fs.lookup_entry(dir, OsStr::new("file"), 2000, 2000),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+
+        fs.default_permissions = true;
+        assert!(fs.lookup_entry(dir, OsStr::new("file"), 2000, 2000).is_ok());
+        fs.remove_file(dir, OsStr::new("file"), 2000).unwrap();
+        assert_eq!(
+            parse_mount_option("default_permissions"),
+            Ok(MountOption::DefaultPermissions)
+        );
+    }
+
+    #[test]
+    fn test_interrupted() {
+        let mut fs = nsfs::NsFS::new();
+        let (attrs, fh) = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        let cancel = fs.interrupt_token(fh).unwrap();
+
+        cancel.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(matches!(
+            fs.read_handle(fh, 5, 0),
+            Err(nsfs::Error::Interrupted)
+        ));
+        assert_eq!(&fs.read_handle(fh, 5, 0).unwrap()[..], b"Hello");
+
+        cancel.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(matches!(
+            fs.write_handle(fh, b"!", 11),
+            Err(nsfs::Error::Interrupted)
+        ));
+        assert_eq!(fs.get_attr(ino).unwrap().size, 11);
+        assert!(fs.interrupt_token(fh + 1).is_none());
+    }
+
+    #[test]
+    fn test_deterministic_inodes() {
+        let build = |names: &[&str]| {
+            let mut fs = nsfs::NsFS::new_deterministic();
+            let dir = fs
+                .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+                .unwrap()
+                .ino;
+            for name in names {
+                fs.create_file(dir, OsStr::new(name), 0, 0, 0, None, 0)
+                    .unwrap();
+            }
+            fs
+        };
+        let fs = build(&["a", "b", "c"]);
+        let reversed = build(&["c", "b", "a"]);
+
+        let dir = fs.find_node(1, OsStr::new("dir")).unwrap().index;
+        for name in ["a", "b", "c"] {
+            let name = OsStr::new(name);
+            assert_eq!(
+                fs.find_node(dir, name).unwrap().index,
+                reversed.find_node(dir, name).unwrap().index
+            );
+        }
+
+        // a path that hashes to a taken inode gets the next free one
+        use nsfs::InodeAllocator;
+        let mut allocator = nsfs::PathHashAllocator::default();
+        let first = allocator.next_for(Path::new("/same"));
+        let second = allocator.next_for(Path::new("/same"));
+        assert_ne!(first, second);
+        allocator.free(first);
+        assert_eq!(allocator.next_for(Path::new("/same")), first);
+    }
+
+    #[test]
+    fn test_flush_threshold() {
+        let mut fs = nsfs::NsFS::new();
+        fs.buffered = true;
+        fs.flush_threshold = 100;
+        let (attrs, fh) = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+
+        for i in 0..10 {
+            fs.write_handle(fh, &[1; 10], i * 10).unwrap();
+        }
+        assert_eq!(fs.get_attr(ino).unwrap().size, 0);
+
+        fs.write_handle(fh, &[1; 10], 100).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().size, 110);
+        assert_eq!(fs.metrics(), vec![("coalesced_writes", 11)]);
+    }
+
+    #[test]
+    fn test_list_inodes() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+
+        let inodes = fs.list_inodes();
+        assert_eq!(inodes.len(), 3);
+        assert!(inodes
+            .iter()
+            .any(|&(ino, kind, _)| ino == 1 && kind == FileType::Directory));
+        assert!(inodes.contains(&(file, FileType::RegularFile, 11)));
+        assert_eq!(dump_tree(&fs), "/\n  dir/\n    file (11 bytes)\n");
+
+        let args: Vec<String> = ["nsfs", "--state", "state", "--dump"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert!(parse_args(&args).unwrap().dump);
+    }
+
+    #[test]
+    fn test_reflink() {
+        let mut fs = nsfs::NsFS::new();
+        let src = fs
+            .create_file(1, OsStr::new("src"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        fs.write_file(src, &data, 0).unwrap();
+        assert_eq!(fs.dedup_savings(), 0);
+
+        let (name, from) = parse_reflink(
+            OsStr::new("user.nsfs.reflink.copy"),
+            src.to_string().as_bytes(),
+        )
+        .unwrap();
+        let copy = fs.create_reflink(1, name, from, 0, 0, None, 0).unwrap().ino;
+        assert_eq!(fs.get_attr(copy).unwrap().size, 8192);
+        assert_eq!(&fs.read_file_shared(copy, 8192, 0).unwrap()[..], &data[..]);
+        assert_eq!(fs.dedup_savings(), 8192);
+
+        fs.write_file(copy, b"Hello, Rust", 0).unwrap();
+        assert_eq!(fs.dedup_savings(), 4096);
+        assert_eq!(&fs.read_file_shared(src, 8192, 0).unwrap()[..], &data[..]);
+        assert!(parse_reflink(OsStr::new("user.tag"), b"2").is_none());
+    }
+
+    #[test]
+    fn test_reflink_permissions() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 1000, 1000, Some(0o755), 0)
+            .unwrap()
+            .ino;
+        let src = fs
+            .create_file(dir, OsStr::new("src"), 0, 1000, 1000, Some(0o640), 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(src, b"Hello, Rust", 0).unwrap();
+        let shared = fs
+            .create_dir(1, OsStr::new("shared"), 0, 0, Some(0o777), 0)
+            .unwrap()
+            .ino;
+
+        // others can't read the source
+        assert!(matches!(
+            fs.create_reflink(shared, OsStr::new("copy"), src, 2000, 2000, None, 0),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        // the group can read it, but can't write to the directory
+        assert!(matches!(
+            fs.create_reflink(dir, OsStr::new("copy"), src, 2000, 1000, None, 0),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(fs.find_node(dir, OsStr::new("copy")).is_err());
+
+        let copy = fs
+            .create_reflink(shared, OsStr::new("copy"), src, 2000, 1000, None, 0o022)
+            .unwrap();
+        assert_eq!((copy.uid, copy.gid, copy.perm), (2000, 1000, 0o640));
+        let copy = fs
+            .create_reflink(dir, OsStr::new("copy"), src, 1000, 1000, Some(0o666), 0o027)
+            .unwrap();
+        assert_eq!(copy.perm, 0o640);
+    }
+
+    #[test]
+    fn test_packed_stats() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("test"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        fs.count_op("write");
+        fs.count_op("read");
+
+        let packed = fs.packed_stats();
+        let fields: Vec<u64> = packed
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(fields, vec![11, 2, 1, 1, 0, 2]);
+        assert_eq!(nsfs::STATS_IOCTL, 0x8030_4e01);
+    }
+
+    #[test]
+    fn test_create_mode() {
+        let mut fs = nsfs::NsFS::new();
+        let file = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, Some(0o777), 0o022)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(fs.get_attr(file).unwrap().perm, 0o755);
+
+        fs.default_file_mode = 0o666;
+        fs.default_dir_mode = 0o775;
+        let file = fs
+            .create_file(1, OsStr::new("other"), 0, 0, 0, None, 0o022)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(fs.get_attr(file).unwrap().perm, 0o644);
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0o002)
+            .unwrap()
+            .ino;
+        assert_eq!(fs.get_attr(dir).unwrap().perm, 0o775);
+    }
+
+    #[test]
+    fn test_root_attrs() {
+        let fs = nsfs::NsFS::new();
+        let attrs = fs.get_attr(1).unwrap();
+        assert_eq!(attrs.ino, 1);
+        assert_eq!(attrs.kind, FileType::Directory);
+        assert_eq!(attrs.perm, 0o755);
+        assert_eq!(attrs.nlink, 2);
+        assert_eq!(attrs.uid, unsafe { libc::getuid() });
+        assert_eq!(fs.path_of(1), Some(PathBuf::from("/")));
+        assert_eq!(fs.list_dir(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_readdir_pages() {
+        let mut fs = nsfs::NsFS::new();
+        for i in 0..10 {
+            let name = format!("file{}", i);
+            fs.create_file(1, OsStr::new(&name), 0, 0, 0, None, 0)
+                .unwrap();
+        }
+
+        // a buffer with room for three entries per reply
+        let mut names = Vec::new();
+        let mut offset = 0;
+        let mut replies = 0;
+        loop {
+            let mut page: Vec<(i64, std::ffi::OsString)> = Vec::new();
+            fill_dir(
+                fs.read_dir(1, offset as u64).unwrap(),
+                |_, offset, _, name| {
+                    if page.len() == 3 {
+                        return true;
+                    }
+                    page.push((offset, name.to_os_string()));
+                    false
+                },
+            );
+            match page.last() {
+                Some(&(last, _)) => offset = last,
+                None => break,
+            }
+            replies += 1;
+            names.extend(page.into_iter().map(|(_, name)| name));
+        }
+
+        assert_eq!(replies, 4);
+        assert_eq!(names.len(), 11);
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 11);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut fs = nsfs::NsFS::new();
+        let (attrs, fh) = fs
+            .create_file(1, OsStr::new("sparse"), 0, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+        // zeroes only take segments where there was data before
+        fs.write_file(ino, &[1; 4 * 4096], 0).unwrap();
+        fs.write_file(ino, &[0; 4 * 4096], 0).unwrap();
+        fs.write_file(ino, b"tail", 4 * 4096).unwrap();
+        let before = fs.read_file(ino, 5 * 4096, 0).unwrap().to_vec();
+        assert_eq!(fs.storage_info(ino).unwrap().segments, 5);
+
+        assert_eq!(fs.compact(ino).unwrap(), 4);
+        assert_eq!(fs.storage_info(ino).unwrap().segments, 1);
+        assert_eq!(fs.read_file(ino, 5 * 4096, 0).unwrap(), &before[..]);
+        assert!(fs.compact(1).is_err());
+
+        // closing the last handle compacts a file past the threshold
+        fs.compact_threshold = 2;
+        fs.write_file(ino, &[1; 2 * 4096], 4096 * 5).unwrap();
+        fs.write_file(ino, &[0; 2 * 4096], 4096 * 5).unwrap();
+        assert_eq!(fs.storage_info(ino).unwrap().segments, 3);
+        fs.close_file(fh).unwrap();
+        assert_eq!(fs.storage_info(ino).unwrap().segments, 1);
+    }
+
+    #[test]
+    fn test_max_entries_per_dir() {
+        let mut fs = nsfs::NsFS::new();
+        fs.max_entries_per_dir = Some(2);
+        fs.create_file(1, OsStr::new("a"), 0, 0, 0, None, 0)
+            .unwrap();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+
+        assert!(matches!(
+            fs.create_file(1, OsStr::new("b"), 0, 0, 0, None, 0),
+            Err(nsfs::Error::DirFull)
+        ));
+        assert!(matches!(
+            fs.create_dir(1, OsStr::new("sub"), 0, 0, None, 0),
+            Err(nsfs::Error::DirFull)
+        ));
+        assert_eq!(c_int::from(nsfs::Error::DirFull), libc::EMFILE);
+
+        // renaming within a full directory or replacing an entry is fine
+        fs.rename_ex(1, OsStr::new("a"), 1, OsStr::new("b"), 0, 0)
+            .unwrap();
+        fs.create_file(dir, OsStr::new("c"), 0, 0, 0, None, 0)
+            .unwrap();
+        assert!(matches!(
+            fs.rename_ex(dir, OsStr::new("c"), 1, OsStr::new("c"), 0, 0),
+            Err(nsfs::Error::DirFull)
+        ));
+        fs.rename_ex(dir, OsStr::new("c"), 1, OsStr::new("b"), 0, 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_mime_type_xattr() {
+        let mut fs = nsfs::NsFS::new();
+        let name = OsStr::new("user.mime_type");
+        let mime_type = |fs: &mut nsfs::NsFS, content: &[u8]| {
+            let ino = fs
+                .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+                .unwrap()
+                .0
+                .ino;
+            fs.write_file(ino, content, 0).unwrap();
+            let mime_type = String::from_utf8(fs.get_xattr(ino, name).unwrap().to_vec());
+            fs.remove_file(1, OsStr::new("file"), 0).unwrap();
+            mime_type.unwrap()
+        };
+        assert_eq!(
+            mime_type(&mut fs, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            "image/png"
+        );
+        assert_eq!(
+            mime_type(&mut fs, b"\xff\xd8\xff\xe0\0\x10JFIF"),
+            "image/jpeg"
+        );
+        assert_eq!(mime_type(&mut fs, b"\x1f\x8b\x08\0"), "application/gzip");
+        assert_eq!(mime_type(&mut fs, b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(
+            mime_type(&mut fs, "hello, wörld\n".as_bytes()),
+            "text/plain"
+        );
+        assert_eq!(
+            mime_type(&mut fs, b"\0\x01\x02"),
+            "application/octet-stream"
+        );
+
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(fs.list_xattr(ino).unwrap(), b"user.mime_type\0");
+        assert!(matches!(
+            fs.set_xattr(ino, name, b"image/gif", 0),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.remove_xattr(ino, name),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(fs.get_xattr(1, name).is_err());
+    }
+
+    #[test]
+    fn test_replace_content() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("config"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let old = vec![b'o'; 3 * 4096 + 10];
+        fs.write_file(ino, &old, 0).unwrap();
+        let copy = fs
+            .create_reflink(1, OsStr::new("copy"), ino, 0, 0, None, 0)
+            .unwrap()
+            .ino;
+
+        let attrs = *fs.replace_content(ino, b"new = 1\n").unwrap();
+        assert_eq!(attrs.size, 8);
+        assert_eq!(attrs.ctime, attrs.mtime);
+        assert_eq!(fs.read_file(ino, 4096, 0).unwrap(), b"new = 1\n");
+        assert_eq!(fs.storage_info(ino).unwrap().segments, 1);
+        // whoever held on to the old content still sees all of it
+        assert_eq!(fs.read_file(copy, old.len(), 0).unwrap(), &old[..]);
+
+        assert!(fs.replace_content(1, b"").is_err());
+    }
+
+    #[test]
+    fn test_missing_parent() {
+        let mut fs = nsfs::NsFS::new();
+        let missing = 1000;
+        let first = fs
+            .create_file(1, OsStr::new("first"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        let result = fs.create_file(missing, OsStr::new("file"), 0, 0, 0, None, 0);
+        assert!(matches!(result, Err(nsfs::Error::NotFound)));
+        let result = fs.create_dir(missing, OsStr::new("dir"), 0, 0, None, 0);
+        assert!(matches!(result, Err(nsfs::Error::NotFound)));
+        let result = fs.create_special(missing, OsStr::new("fifo"), libc::S_IFIFO, 0, 0, 0, 0);
+        assert!(matches!(result, Err(nsfs::Error::NotFound)));
+        let result = fs.rename_ex(1, OsStr::new("first"), missing, OsStr::new("x"), 0, 0);
+        assert!(matches!(result, Err(nsfs::Error::NotFound)));
+        let result = fs.create_file(first, OsStr::new("file"), 0, 0, 0, None, 0);
+        assert!(matches!(result, Err(nsfs::Error::NotADirectory)));
+
+        // none of the failures used up an inode
+        let second = fs
+            .create_file(1, OsStr::new("second"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(second, first + 1);
+    }
+
+    #[test]
+    fn test_open_reply_flags() {
+        let flags = open_reply_flags(libc::O_RDWR);
+        assert_eq!(flags, consts::FOPEN_KEEP_CACHE);
+        // open flags aren't echoed back
+        assert_eq!(
+            open_reply_flags(libc::O_WRONLY | libc::O_APPEND) & libc::O_APPEND as u32,
+            0
+        );
+
+        let flags = open_reply_flags(libc::O_RDONLY | libc::O_DIRECT);
+        assert_eq!(flags & consts::FOPEN_KEEP_CACHE, 0);
+        assert_ne!(flags & consts::FOPEN_DIRECT_IO, 0);
+    }
+
+    #[test]
+    fn test_sync_dir() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.sync_dir(dir).unwrap();
+        fs.sync_dir(1).unwrap();
+        assert!(matches!(fs.sync_dir(file), Err(nsfs::Error::NotADirectory)));
+        assert!(matches!(fs.sync_dir(1000), Err(nsfs::Error::NotFound)));
+
+        let path = std::env::temp_dir().join(format!("nsfs-syncdir-{}", std::process::id()));
+        fs.state_file = Some(path.clone());
+        fs.rename_ex(dir, OsStr::new("file"), 1, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        fs.sync_dir(1).unwrap();
+        let loaded = nsfs::NsFS::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.find_node(1, OsStr::new("moved")).is_ok());
+    }
+
+    #[test]
+    fn test_storage() {
+        let storage = nsfs::RecordingStorage::default();
+        let mut fs = nsfs::NsFS::new();
+        fs.storage = Some(Box::new(storage.clone()));
+        let (attrs, fh) = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        assert_eq!(fs.read_file(ino, 1024, 0).unwrap(), b"Hello, Rust");
+        // the content isn't kept in memory
+        assert_eq!(fs.storage_info(ino).unwrap().resident_bytes, 0);
+
+        let changes = nsfs::SetAttr {
+            size: Some(5),
+            ..Default::default()
+        };
+        fs.set_attr(ino, changes).unwrap();
+        assert_eq!(&fs.read_file_shared(ino, 1024, 0).unwrap()[..], b"Hello");
+
+        fs.close_file(fh).unwrap();
+        fs.remove_file(1, OsStr::new("file"), 0).unwrap();
+        fs.forget_inode(ino, 1);
+        assert_eq!(
+            storage.calls(),
+            [
+                format!("write {} 0 11", ino),
+                format!("read {} 0 11", ino),
+                format!("truncate {} 5", ino),
+                format!("read {} 0 5", ino),
+                format!("remove {}", ino),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_file_chunks() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let content: Vec<u8> = (0..3 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        fs.write_file(ino, &content, 0).unwrap();
+        // leave a hole in the middle
+        fs.write_file(ino, b"end", 6 * 4096).unwrap();
+
+        for (offset, size) in [(0, 10), (100, 4096), (4000, 3 * 4096), (0, 8 * 4096)] {
+            let contiguous = fs.read_file(ino, size, offset).unwrap().to_vec();
+            let chunks = fs.read_file_chunks(ino, size, offset).unwrap();
+            assert!(chunks.iter().all(|chunk| chunk.len() <= 4096));
+            assert_eq!(chunks.concat(), contiguous);
+        }
+        assert!(matches!(
+            fs.read_file_chunks(ino, 10, 7 * 4096),
+            Err(nsfs::Error::Eof)
+        ));
+    }
+
+    #[test]
+    fn test_handle_reuse() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let first = fs.open_file(ino, 0);
+        let second = fs.open_file(ino, 0);
+        assert_ne!(first, second);
+
+        fs.close_file(first).unwrap();
+        assert!(matches!(
+            fs.read_handle(first, 1, 0),
+            Err(nsfs::Error::BadFileDescriptor)
+        ));
+        assert_eq!(fs.open_file(ino, libc::O_DIRECT), first);
+        assert!(fs.open_files[&first].direct_io);
+        assert_eq!(fs.memory_stats().open_files, 3);
+    }
+
+    #[test]
+    fn test_statx() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 1000, 1000, Some(0o750), 0)
+            .unwrap()
+            .ino;
+        fs.create_dir(dir, OsStr::new("a"), 0, 0, None, 0).unwrap();
+        fs.create_dir(dir, OsStr::new("b"), 0, 0, None, 0).unwrap();
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 1000, 1000, Some(0o640), 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, &[1; 1000], 0).unwrap();
+
+        let statx = fs.statx(file).unwrap();
+        let attrs = *fs.get_attr(file).unwrap();
+        assert_eq!(statx.kind, FileType::RegularFile);
+        assert_eq!(statx.perm, 0o640);
+        assert_eq!(statx.nlink, 1);
+        assert_eq!((statx.uid, statx.gid), (1000, 1000));
+        assert_eq!(statx.size, 1000);
+        assert_eq!(statx.blocks, 8);
+        assert_eq!(statx.blksize, 4096);
+        assert_eq!(statx.btime, attrs.crtime);
+        assert_eq!(FileAttr::from(statx), attrs);
+
+        let statx = fs.statx(dir).unwrap();
+        assert_eq!(statx.kind, FileType::Directory);
+        assert_eq!(statx.perm, 0o750);
+        // "." and the entry in the root, plus ".." of both subdirectories
+        assert_eq!(statx.nlink, 4);
+        assert_eq!(fs.statx(1).unwrap().nlink, 3);
+        assert!(fs.statx(1000).is_err());
+    }
+
+    #[test]
+    fn test_generation() {
+        let mut fs = nsfs::NsFS::new_deterministic();
+        let create = |fs: &mut nsfs::NsFS| {
+            let (attrs, fh) = fs
+                .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+                .unwrap();
+            let ino = attrs.ino;
+            fs.close_file(fh).unwrap();
+            ino
+        };
+        let first = create(&mut fs);
+        assert_eq!(fs.generation(first), 0);
+
+        fs.remove_file(1, OsStr::new("file"), 0).unwrap();
+        fs.forget_inode(first, 1);
+        let second = create(&mut fs);
+        // the same path hashes to the same, now reused, inode
+        assert_eq!(second, first);
+        assert!(fs.generation(second) > 0);
+    }
+
+    #[test]
+    fn test_lookup_dot_entries() {
+        let mut fs = nsfs::NsFS::new();
+        let outer = fs
+            .create_dir(1, OsStr::new("outer"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let inner = fs
+            .create_dir(outer, OsStr::new("inner"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+
+        assert_eq!(fs.find_node(inner, OsStr::new(".")).unwrap().index, inner);
+        assert_eq!(fs.find_node(inner, OsStr::new("..")).unwrap().index, outer);
+        assert_eq!(fs.find_node(outer, OsStr::new("..")).unwrap().index, 1);
+        assert_eq!(fs.find_node(1, OsStr::new("..")).unwrap().index, 1);
+        assert_eq!(
+            fs.lookup_entry(inner, OsStr::new(".."), 0, 0).unwrap().ino,
+            outer
+        );
+        assert_eq!(fs.lookup_entry(1, OsStr::new("."), 0, 0).unwrap().ino, 1);
+    }
+
+    #[test]
+    fn test_strict_writes() {
+        let mut fs = nsfs::NsFS::new();
+        fs.buffered = true;
+        fs.strict_writes = true;
+        let (attrs, first) = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+        let second = fs.open_file(ino, 0);
+
+        fs.write_handle(first, b"0123456789", 0).unwrap();
+        // the same handle may overwrite its own writes
+        fs.write_handle(first, b"abc", 5).unwrap();
+        assert!(matches!(
+            fs.write_handle(second, b"xyz", 8),
+            Err(nsfs::Error::WouldBlock)
+        ));
+        assert!(matches!(
+            fs.write_file(ino, b"xyz", 9),
+            Err(nsfs::Error::WouldBlock)
+        ));
+        assert_eq!(c_int::from(nsfs::Error::WouldBlock), libc::EAGAIN);
+        fs.write_handle(second, b"xyz", 10).unwrap();
+
+        // once flushed, the range is free again
+        fs.flush_handle(first).unwrap();
+        fs.write_handle(second, b"XY", 0).unwrap();
+        fs.flush_handle(second).unwrap();
+        assert_eq!(fs.read_file(ino, 1024, 0).unwrap(), b"XY234abc89xyz");
+
+        fs.strict_writes = false;
+        fs.write_handle(first, b"!", 0).unwrap();
+        fs.write_handle(second, b"?", 0).unwrap();
+    }
+
+    #[test]
+    fn test_rename_open_file() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let (attrs, fh) = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+        fs.write_handle(fh, b"Hello", 0).unwrap();
+
+        fs.rename_ex(1, OsStr::new("file"), dir, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        fs.write_handle(fh, b", Rust", 5).unwrap();
+        assert_eq!(&fs.read_handle(fh, 1024, 0).unwrap()[..], b"Hello, Rust");
+        assert_eq!(fs.find_node(dir, OsStr::new("moved")).unwrap().index, ino);
+
+        // a file replaced by a rename lives on while the kernel references it
+        let other = fs
+            .create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.rename_ex(1, OsStr::new("other"), dir, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        assert_eq!(fs.find_node(dir, OsStr::new("moved")).unwrap().index, other);
+        assert_eq!(&fs.read_handle(fh, 1024, 0).unwrap()[..], b"Hello, Rust");
+        fs.close_file(fh).unwrap();
+        fs.forget_inode(ino, 1);
+        assert!(fs.get_attr(ino).is_err());
+    }
+
+    #[test]
+    fn test_link_node() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let (attrs, fh) = fs
+            .create_file(dir, OsStr::new("named"), 0, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+        fs.write_handle(fh, b"Hello, Rust", 0).unwrap();
+        fs.close_file(fh).unwrap();
+
+        // a second link keeps the file reachable when the first one goes
+        let attrs = fs.link_node(ino, 1, OsStr::new("alias")).unwrap();
+        assert_eq!(attrs.nlink, 2);
+        fs.remove_file(dir, OsStr::new("named"), 0).unwrap();
+        assert_eq!(
+            fs.lookup_entry(1, OsStr::new("alias"), 0, 0).unwrap().nlink,
+            1
+        );
+        assert_eq!(fs.read_file(ino, 1024, 0).unwrap(), b"Hello, Rust");
+        assert_eq!(fs.path_of(ino).unwrap(), Path::new("/alias"));
+        assert!(matches!(
+            fs.link_node(dir, 1, OsStr::new("dir")),
+            Err(nsfs::Error::NotPermitted)
+        ));
+    }
+
+    #[test]
+    fn test_tmpfile_refused() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+
+        // the kernel can't ask for a tmpfile through open
+        assert!(matches!(
+            fs.open_inode(dir, libc::O_TMPFILE | libc::O_RDWR),
+            Err(nsfs::Error::NotSupported)
+        ));
+        assert!(fs.open_inode(dir, libc::O_DIRECTORY).is_ok());
+    }
+
+    #[test]
+    fn test_save_hard_links() {
+        let path = std::env::temp_dir().join(format!("nsfs-links-{}", std::process::id()));
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let ino = fs
+            .create_file(1, OsStr::new("first"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        fs.link_node(ino, dir, OsStr::new("second")).unwrap();
+        fs.link_node(ino, 1, OsStr::new("third")).unwrap();
+
+        fs.save_to(&path).unwrap();
+        let mut loaded = nsfs::NsFS::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.tree_eq(&fs));
+        for (parent, name) in [(1, "first"), (dir, "second"), (1, "third")] {
+            let attrs = loaded.lookup_entry(parent, OsStr::new(name), 0, 0).unwrap();
+            assert_eq!((attrs.ino, attrs.nlink), (ino, 3));
+        }
+        assert_eq!(loaded.read_file(ino, 1024, 0).unwrap(), b"Hello, Rust");
+        assert_eq!(loaded.used_bytes(), 11);
+    }
+
+    #[test]
+    fn test_hard_link_path() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let ino = fs
+            .create_file(1, OsStr::new("first"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.link_node(ino, dir, OsStr::new("second")).unwrap();
+        fs.link_node(ino, dir, OsStr::new("third")).unwrap();
+
+        // replacing a link through a rename also moves the path
+        let other = fs
+            .create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.rename_ex(1, OsStr::new("other"), 1, OsStr::new("first"), 0, 0)
+            .unwrap();
+        let path = fs.path_of(ino).unwrap();
+        assert!(path == Path::new("/dir/second") || path == Path::new("/dir/third"));
+        assert_eq!(fs.path_of(other).unwrap(), Path::new("/first"));
+
+        let name = path.file_name().unwrap().to_os_string();
+        fs.remove_file(dir, &name, 0).unwrap();
+        let left = fs.path_of(ino).unwrap();
+        assert_ne!(left, path);
+        assert_eq!(left.parent().unwrap(), Path::new("/dir"));
+    }
+
+    #[test]
+    fn test_append_at_end() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, b"Hello", 0).unwrap();
+        // exactly at the end, then overlapping the end
+        fs.write_file(ino, b", ", 5).unwrap();
+        fs.write_file(ino, b" Rust", 6).unwrap();
+        assert_eq!(fs.read_file(ino, 1024, 0).unwrap(), b"Hello, Rust");
+
+        // appending across a segment boundary
+        let head = vec![b'a'; 4094];
+        fs.replace_content(ino, &head).unwrap();
+        fs.write_file(ino, b"bcde", 4094).unwrap();
+        let content = fs.read_file(ino, 8192, 0).unwrap();
+        assert_eq!(content.len(), 4098);
+        assert_eq!(&content[4092..], b"aabcde");
+    }
+
+    #[test]
+    fn test_spill() {
+        let dir = std::env::temp_dir().join(format!("nsfs-spill-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut fs = nsfs::NsFS::new();
+        fs.spill_path = Some(dir.clone());
+        fs.spill_high_water = 2 * 4096;
+        let mut create = |name: &str, byte: u8| {
+            let ino = fs
+                .create_file(1, OsStr::new(name), 0, 0, 0, None, 0)
+                .unwrap()
+                .0
+                .ino;
+            fs.write_file(ino, &vec![byte; 2 * 4096 + 1], 0).unwrap();
+            ino
+        };
+        let cold = create("cold", b'c');
+        let hot = create("hot", b'h');
+
+        // using the hot file pushes the cold one out
+        assert_eq!(fs.read_file(hot, 1, 0).unwrap(), b"h");
+        assert_eq!(fs.storage_info(cold).unwrap().resident_bytes, 0);
+        assert!(dir.join(cold.to_string()).exists());
+        assert_eq!(fs.get_attr(cold).unwrap().size, 2 * 4096 + 1);
+
+        let content = fs.read_file(cold, 3 * 4096, 0).unwrap().to_vec();
+        assert_eq!(content, vec![b'c'; 2 * 4096 + 1]);
+        assert!(!dir.join(cold.to_string()).exists());
+        assert_eq!(fs.storage_info(hot).unwrap().resident_bytes, 0);
+
+        fs.remove_file(1, OsStr::new("hot"), 0).unwrap();
+        fs.forget_inode(hot, 1);
+        assert!(!dir.join(hot.to_string()).exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalidations() {
+        let mut fs = nsfs::NsFS::new();
+        let queue = nsfs::InvalidationQueue::default();
+        fs.invalidations = Some(queue.clone());
+        let first = fs
+            .create_file(1, OsStr::new("first"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let second = fs
+            .create_file(1, OsStr::new("second"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert!(queue.drain().is_empty());
+
+        fs.write_file(second, b"Hello", 0).unwrap();
+        fs.write_file(first, b"Hello", 0).unwrap();
+        fs.write_file(second, b", Rust", 5).unwrap();
+        let changes = nsfs::SetAttr {
+            size: Some(1),
+            ..Default::default()
+        };
+        fs.set_attr(first, changes).unwrap();
+        // each inode is queued once, in the order it first changed
+        assert_eq!(queue.drain(), [second, first]);
+        assert!(queue.drain().is_empty());
+
+        fs.read_file(first, 1, 0).unwrap();
+        assert!(queue.drain().is_empty());
+    }
+
+    #[test]
+    fn test_symlink_resolution() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.create_symlink(1, OsStr::new("to_dir"), Path::new("dir"), 0, 0)
+            .unwrap();
+        fs.create_symlink(1, OsStr::new("chain"), Path::new("to_dir/file"), 0, 0)
+            .unwrap();
+        fs.create_symlink(dir, OsStr::new("up"), Path::new("../chain"), 0, 0)
+            .unwrap();
+        let abs = fs
+            .create_symlink(1, OsStr::new("abs"), Path::new("/dir/up"), 0, 0)
+            .unwrap()
+            .ino;
+
+        assert_eq!(fs.read_link(abs).unwrap(), Path::new("/dir/up"));
+        assert_eq!(fs.get_attr(abs).unwrap().size, 7);
+        assert!(matches!(
+            fs.read_link(file),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+        assert_eq!(fs.resolve_path(Path::new("/to_dir/file")).unwrap(), file);
+        assert_eq!(fs.resolve_path(Path::new("/abs")).unwrap(), file);
+        assert_eq!(fs.resolve_path(Path::new("to_dir")).unwrap(), dir);
+
+        fs.create_symlink(1, OsStr::new("self"), Path::new("self"), 0, 0)
+            .unwrap();
+        fs.create_symlink(1, OsStr::new("a"), Path::new("b"), 0, 0)
+            .unwrap();
+        fs.create_symlink(1, OsStr::new("b"), Path::new("/a"), 0, 0)
+            .unwrap();
+        assert!(matches!(
+            fs.resolve_path(Path::new("/self")),
+            Err(nsfs::Error::TooManyLinks)
+        ));
+        assert!(matches!(
+            fs.resolve_path(Path::new("/a/file")),
+            Err(nsfs::Error::TooManyLinks)
+        ));
+        assert_eq!(c_int::from(nsfs::Error::TooManyLinks), libc::ELOOP);
+    }
+
+    #[test]
+    fn test_set_attr_ctime() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH);
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(clock.clone());
+        let ino = fs
+            .create_file(1, OsStr::new("copy"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let created = fs.get_attr(ino).unwrap().ctime;
+
+        clock.advance(Duration::from_secs(10));
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(3);
+        let attrs = fs
+            .set_attr(
+                ino,
+                nsfs::SetAttr {
+                    atime: Some(TimeOrNow::SpecificTime(mtime)),
+                    mtime: Some(TimeOrNow::SpecificTime(mtime)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(attrs.mtime, mtime);
+        assert_eq!(attrs.atime, mtime);
+        assert_eq!(attrs.ctime, created + Duration::from_secs(10));
+
+        clock.advance(Duration::from_secs(10));
+        let attrs = fs.set_attr(ino, nsfs::SetAttr::default()).unwrap();
+        assert_eq!(attrs.ctime, created + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_set_attr_single_time() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(clock.clone());
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let atime = SystemTime::UNIX_EPOCH + Duration::new(1, 111);
+        let mtime = SystemTime::UNIX_EPOCH + Duration::new(2, 222);
+        fs.touch(
+            ino,
+            Some(TimeOrNow::SpecificTime(atime)),
+            Some(TimeOrNow::SpecificTime(mtime)),
+        )
+        .unwrap();
+
+        // atime only, as `touch -a`
+        clock.advance(Duration::from_secs(1));
+        let new_atime = SystemTime::UNIX_EPOCH + Duration::new(3, 333);
+        let changes = nsfs::SetAttr {
+            atime: Some(TimeOrNow::SpecificTime(new_atime)),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (new_atime, mtime));
+        let changes = nsfs::SetAttr {
+            atime: Some(TimeOrNow::Now),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (clock.now(), mtime));
+
+        // mtime only, as `touch -m`
+        clock.advance(Duration::from_secs(1));
+        let atime = attrs.atime;
+        let new_mtime = SystemTime::UNIX_EPOCH + Duration::new(4, 444);
+        let changes = nsfs::SetAttr {
+            mtime: Some(TimeOrNow::SpecificTime(new_mtime)),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (atime, new_mtime));
+        let changes = nsfs::SetAttr {
+            mtime: Some(TimeOrNow::Now),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (atime, clock.now()));
+
+        // both
+        clock.advance(Duration::from_secs(1));
+        let changes = nsfs::SetAttr {
+            atime: Some(TimeOrNow::SpecificTime(new_atime)),
+            mtime: Some(TimeOrNow::SpecificTime(new_mtime)),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (new_atime, new_mtime));
+        let changes = nsfs::SetAttr {
+            atime: Some(TimeOrNow::Now),
+            mtime: Some(TimeOrNow::Now),
+            ..Default::default()
+        };
+        let attrs = fs.set_attr(ino, changes).unwrap();
+        assert_eq!((attrs.atime, attrs.mtime), (clock.now(), clock.now()));
+        assert_eq!(attrs.ctime, clock.now());
+    }
+
+    #[test]
+    fn test_export_json() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, Some(0o750), 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 0, 0, Some(0o640), 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+
+        let tree: serde_json::Value = serde_json::from_str(&fs.to_json()).unwrap();
+        assert_eq!(tree["name"], "/");
+        assert_eq!(tree["ino"], 1);
+        assert_eq!(tree["kind"], "directory");
+        let dir_json = &tree["children"][0];
+        assert_eq!(dir_json["name"], "dir");
+        assert_eq!(dir_json["ino"], dir);
+        assert_eq!(dir_json["perm"], 0o750);
+        let file_json = &dir_json["children"][0];
+        assert_eq!(file_json["name"], "file");
+        assert_eq!(file_json["ino"], file);
+        assert_eq!(file_json["kind"], "file");
+        assert_eq!(file_json["size"], 11);
+        assert_eq!(file_json["perm"], 0o640);
+        assert!(file_json.get("content").is_none());
+
+        let tree: serde_json::Value = serde_json::from_str(&fs.to_json_with_content()).unwrap();
+        assert_eq!(
+            tree["children"][0]["children"][0]["content"],
+            "SGVsbG8sIFJ1c3Q="
+        );
+
+        let args: Vec<String> = [
+            "nsfs",
+            "--state",
+            "state",
+            "--export-json",
+            "--with-content",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let options = parse_args(&args).unwrap();
+        assert!(options.export_json && options.with_content);
+    }
+
+    #[test]
+    fn test_import_json() {
+        let json = r#"{
+            "name": "/", "kind": "directory", "perm": 493,
+            "children": [
+                {"name": "dir", "kind": "directory", "perm": 448, "children": [
+                    {"name": "file", "kind": "file", "content": "SGVsbG8sIFJ1c3Q="},
+                    {"name": "sparse", "kind": "file", "size": 4096}
+                ]},
+                {"name": "link", "kind": "symlink", "target": "dir/file"}
+            ]
+        }"#;
+        let mut fs = nsfs::NsFS::from_json(json).unwrap();
+
+        let dir = fs.find_node(1, OsStr::new("dir")).unwrap().index;
+        assert_eq!(fs.get_attr(dir).unwrap().perm, 0o700);
+        let file = fs.find_node(dir, OsStr::new("file")).unwrap().index;
+        assert_eq!(fs.read_file(file, 64, 0).unwrap(), b"Hello, Rust");
+        let sparse = fs.find_node(dir, OsStr::new("sparse")).unwrap().index;
+        assert_eq!(fs.get_attr(sparse).unwrap().size, 4096);
+        let link = fs.find_node(1, OsStr::new("link")).unwrap().index;
+        assert_eq!(fs.resolve_path(Path::new("/link")).unwrap(), file);
+        assert_eq!(fs.read_link(link).unwrap(), Path::new("dir/file"));
+
+        let exported = nsfs::NsFS::from_json(&fs.to_json_with_content()).unwrap();
+        assert_eq!(exported.to_json_with_content(), fs.to_json_with_content());
+
+        let duplicate = r#"{"kind": "directory", "children": [
+            {"name": "a", "kind": "file"}, {"name": "a", "kind": "directory"}
+        ]}"#;
+        let err = nsfs::NsFS::from_json(duplicate).err().unwrap();
+        assert_eq!(err.to_string(), "duplicate name \"/a\"");
+        let err = nsfs::NsFS::from_json("[]").err().unwrap();
+        assert_eq!(err.to_string(), "missing root directory");
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut fs = nsfs::NsFS::new();
+        fs.max_depth = Some(2);
+        let a = fs
+            .create_dir(1, OsStr::new("a"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let b = fs
+            .create_dir(a, OsStr::new("b"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        assert!(matches!(
+            fs.create_dir(b, OsStr::new("c"), 0, 0, None, 0),
+            Err(nsfs::Error::TooDeep)
+        ));
+        assert_eq!(c_int::from(nsfs::Error::TooDeep), libc::ENOSPC);
+        // only directories count
+        fs.create_file(b, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap();
+
+        // moving a directory up makes room below it
+        fs.rename_ex(a, OsStr::new("b"), 1, OsStr::new("b"), 0, 0)
+            .unwrap();
+        let c = fs
+            .create_dir(b, OsStr::new("c"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        assert!(matches!(
+            fs.create_dir(c, OsStr::new("d"), 0, 0, None, 0),
+            Err(nsfs::Error::TooDeep)
+        ));
+
+        // moving a directory down counts everything below it
+        assert!(matches!(
+            fs.rename_ex(1, OsStr::new("b"), a, OsStr::new("b"), 0, 0),
+            Err(nsfs::Error::TooDeep)
+        ));
+        let x = fs
+            .create_dir(a, OsStr::new("x"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        assert!(matches!(
+            fs.rename_ex(
+                a,
+                OsStr::new("x"),
+                1,
+                OsStr::new("b"),
+                libc::RENAME_EXCHANGE,
+                0
+            ),
+            Err(nsfs::Error::TooDeep)
+        ));
+        assert_eq!(fs.find_node(1, OsStr::new("b")).unwrap().index, b);
+        assert_eq!(fs.find_node(a, OsStr::new("x")).unwrap().index, x);
+        fs.rename_ex(b, OsStr::new("file"), a, OsStr::new("file"), 0, 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut fs = nsfs::NsFS::new();
+        let src = fs
+            .create_file(1, OsStr::new("src"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let dst = fs
+            .create_file(1, OsStr::new("dst"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        fs.write_file(src, &data, 0).unwrap();
+        assert!(is_whole_copy(
+            fs.get_attr(src).unwrap(),
+            fs.get_attr(dst).unwrap(),
+            0,
+            0,
+            1 << 30
+        ));
+        assert!(!is_whole_copy(
+            fs.get_attr(src).unwrap(),
+            fs.get_attr(dst).unwrap(),
+            0,
+            4096,
+            1 << 30
+        ));
+
+        assert_eq!(fs.transfer(src, dst).unwrap().size, 8192);
+        assert_eq!(&fs.read_file_shared(dst, 8192, 0).unwrap()[..], &data[..]);
+        assert_eq!(fs.dedup_savings(), 8192);
+
+        // the copy is on write
+        fs.write_file(dst, b"Hello, Rust", 0).unwrap();
+        assert_eq!(fs.dedup_savings(), 4096);
+        assert_eq!(&fs.read_file_shared(src, 8192, 0).unwrap()[..], &data[..]);
+        assert!(matches!(
+            fs.transfer(1, dst),
+            Err(nsfs::Error::IsADirectory)
+        ));
+    }
+
+    #[test]
+    fn test_sparse_write() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("sparse"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        fs.write_file(ino, b"x", 1 << 30).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().size, (1 << 30) + 1);
+        let info = fs.storage_info(ino).unwrap();
+        assert_eq!((info.segments, info.resident_bytes), (1, 4096));
+        assert_eq!(fs.read_file(ino, 8, 0).unwrap(), [0; 8]);
+        assert_eq!(
+            fs.read_file(ino, 8, (1 << 30) - 7).unwrap(),
+            b"\0\0\0\0\0\0\0x"
+        );
+
+        // zeroes written into holes don't take memory either
+        fs.write_file(ino, &[0; 3 * 4096], 4096).unwrap();
+        assert_eq!(fs.storage_info(ino).unwrap().segments, 1);
+        fs.write_file(ino, &[0, 1, 0], 4096).unwrap();
+        assert_eq!(fs.storage_info(ino).unwrap().segments, 2);
+        assert_eq!(fs.read_file(ino, 4, 4096).unwrap(), [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_selftest() {
+        let mut fs = nsfs::NsFS::new();
+        selftest::run(&mut fs).unwrap();
+        assert!(fs.find_node(1, OsStr::new("selftest")).is_err());
+
+        // a failing step is reported by name
+        fs.create_dir(1, OsStr::new("selftest"), 0, 0, None, 0)
+            .unwrap();
+        let err = selftest::run(&mut fs).unwrap_err();
+        assert!(err.starts_with("mkdir: "), "{}", err);
+
+        let dir = fs.find_node(1, OsStr::new("selftest")).unwrap().index;
+        fs.create_file(dir, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap();
+        assert!(matches!(
+            fs.remove_dir(1, OsStr::new("selftest"), 0),
+            Err(nsfs::Error::NotEmpty)
+        ));
+        assert!(matches!(
+            fs.remove_dir(dir, OsStr::new("file"), 0),
+            Err(nsfs::Error::NotADirectory)
+        ));
+        let child = fs.find_node(dir, OsStr::new("file")).unwrap().index;
+        assert!(matches!(
+            fs.remove_file(1, OsStr::new("selftest"), 0),
+            Err(nsfs::Error::IsADirectory)
+        ));
+        assert!(fs.get_attr(child).is_ok());
+        assert_eq!(fs.path_of(child).unwrap(), Path::new("/selftest/file"));
+
+        let args: Vec<String> = ["nsfs", "--selftest"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert!(parse_args(&args).unwrap().selftest);
+    }
+
+    #[test]
+    fn test_ctime_on_content_change() {
+        let clock = nsfs::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let mut fs = nsfs::NsFS::new();
+        fs.clock = Box::new(clock.clone());
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+
+        // mtime held back, as `touch -d` or `cp -p` would
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        fs.touch(ino, None, Some(TimeOrNow::SpecificTime(old)))
+            .unwrap();
+        clock.advance(Duration::from_secs(10));
+        fs.write_file(ino, b"Hello, Rust", 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().ctime, clock.now());
+
+        clock.advance(Duration::from_secs(10));
+        fs.allocate(ino, 0, 8192, 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().ctime, clock.now());
+
+        clock.advance(Duration::from_secs(10));
+        fs.link_node(ino, 1, OsStr::new("link")).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().ctime, clock.now());
+
+        clock.advance(Duration::from_secs(10));
+        fs.remove_file(1, OsStr::new("link"), 0).unwrap();
+        let attrs = fs.get_attr(ino).unwrap();
+        assert_eq!((attrs.nlink, attrs.ctime), (1, clock.now()));
+    }
+
+    #[test]
+    fn test_tree_xattr() {
+        let mut fs = nsfs::NsFS::new();
+        let name = OsStr::new("user.tree");
+        let src = fs
+            .create_dir(1, OsStr::new("src"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let nested = fs
+            .create_dir(src, OsStr::new("nested"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        for (parent, file) in [(src, "main.rs"), (nested, "mod.rs"), (1, "Cargo.toml")] {
+            fs.create_file(parent, OsStr::new(file), 0, 0, 0, None, 0)
+                .unwrap();
+        }
+
+        assert_eq!(
+            &*fs.get_xattr(1, name).unwrap(),
+            b"Cargo.toml\nsrc\nsrc/main.rs\nsrc/nested\nsrc/nested/mod.rs\n"
+        );
+        assert_eq!(
+            &*fs.get_xattr(src, name).unwrap(),
+            b"main.rs\nnested\nnested/mod.rs\n"
+        );
+        assert!(fs.get_xattr(nested, name).unwrap().ends_with(b"mod.rs\n"));
+        assert!(fs.list_xattr(src).unwrap().is_empty());
+
+        let file = fs.find_node(src, OsStr::new("main.rs")).unwrap().index;
+        assert!(matches!(
+            fs.get_xattr(file, name),
+            Err(nsfs::Error::NoAttribute)
+        ));
+        assert!(matches!(
+            fs.set_xattr(src, name, b"", 0),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.remove_xattr(src, name),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn test_sync_nsfs() {
+        let fs = sync::SyncNsFS::new(nsfs::NsFS::new());
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let fs = fs.clone();
+                std::thread::spawn(move || {
+                    let name = format!("file{}", thread);
+                    let ino = fs
+                        .lock()
+                        .create_file(1, OsStr::new(&name), 0, 0, 0, None, 0)
+                        .unwrap()
+                        .0
+                        .ino;
+                    for round in 0..100 {
+                        let data = format!("{}:{}", thread, round);
+                        fs.lock().write_file(ino, data.as_bytes(), 0).unwrap();
+                        let read = fs.lock().read_file(ino, data.len(), 0).unwrap().to_vec();
+                        assert_eq!(read, data.as_bytes());
+                        fs.lock().write_file(1, b"", 0).unwrap_err();
+                    }
+                    ino
+                })
+            })
+            .collect();
+        let inodes: Vec<u64> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        let mut fs = fs.lock();
+        assert_eq!(fs.list_dir(1).unwrap().len(), 8 + 1);
+        for (thread, ino) in inodes.into_iter().enumerate() {
+            let data = format!("{}:99", thread);
+            assert_eq!(fs.read_file(ino, 64, 0).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_rename_ex() {
+        let mut fs = nsfs::NsFS::new();
+        let create = |fs: &mut nsfs::NsFS, name: &str| {
+            fs.create_file(1, OsStr::new(name), 0, 0, 0, None, 0)
+                .unwrap()
+                .0
+                .ino
+        };
+        let first = create(&mut fs, "first");
+        let second = create(&mut fs, "second");
+        let third = create(&mut fs, "third");
+
+        let replaced = fs
+            .rename_ex(1, OsStr::new("first"), 1, OsStr::new("second"), 0, 0)
+            .unwrap();
+        assert_eq!(replaced, Some(second));
+        assert_eq!(fs.find_node(1, OsStr::new("second")).unwrap().index, first);
+
+        // nothing is lost by moving to a new name or swapping
+        let moved = fs.rename_ex(1, OsStr::new("second"), 1, OsStr::new("new"), 0, 0);
+        assert_eq!(moved.unwrap(), None);
+        let swapped = fs.rename_ex(
+            1,
+            OsStr::new("new"),
+            1,
+            OsStr::new("third"),
+            libc::RENAME_EXCHANGE,
+            0,
+        );
+        assert_eq!(swapped.unwrap(), None);
+        assert_eq!(fs.find_node(1, OsStr::new("new")).unwrap().index, third);
+    }
+
+    #[test]
+    fn test_cross_device() {
+        let mut fs = nsfs::NsFS::new();
+        let mnt = fs
+            .create_dir(1, OsStr::new("mnt"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let inner = fs
+            .create_dir(mnt, OsStr::new("inner"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        let volume = OsStr::new("user.nsfs.volume");
+        // only root and the owner of the root directory mount anything
+        assert!(matches!(
+            fs.control(mnt, volume, b"2", 1000),
+            Err(nsfs::Error::NotPermitted)
+        ));
+        fs.control(mnt, volume, b"2", 0).unwrap();
+        assert!(matches!(
+            fs.control(inner, volume, b"two", 0),
+            Err(nsfs::Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            fs.control(file, volume, b"3", 0),
+            Err(nsfs::Error::NotADirectory)
+        ));
+
+        assert!(matches!(
+            fs.rename_ex(1, OsStr::new("file"), inner, OsStr::new("file"), 0, 0),
+            Err(nsfs::Error::CrossDevice)
+        ));
+        assert!(matches!(
+            fs.link_node(file, inner, OsStr::new("link")),
+            Err(nsfs::Error::CrossDevice)
+        ));
+        assert_eq!(c_int::from(nsfs::Error::CrossDevice), libc::EXDEV);
+
+        // within one volume both work
+        fs.link_node(file, 1, OsStr::new("link")).unwrap();
+        let moved = fs
+            .create_file(inner, OsStr::new("moved"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.rename_ex(inner, OsStr::new("moved"), mnt, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        assert_eq!(fs.find_node(mnt, OsStr::new("moved")).unwrap().index, moved);
+    }
+
+    #[test]
+    fn test_replay_wal() {
+        let dir = std::env::temp_dir().join(format!("nsfs-wal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        let mut fs = nsfs::NsFS::new();
+        fs.state_file = Some(path.clone());
+        fs.open_wal().unwrap();
+
+        let docs = fs
+            .create_dir(1, OsStr::new("docs"), 0, 0, Some(0o750), 0)
+            .unwrap()
+            .ino;
+        let draft = fs
+            .create_file(docs, OsStr::new("draft"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(draft, b"Hello, Rust", 0).unwrap();
+        fs.write_file(draft, b"nsfs", 7).unwrap();
+        fs.create_symlink(1, OsStr::new("latest"), Path::new("docs/final"), 0, 0)
+            .unwrap();
+        let scratch = fs
+            .create_file(1, OsStr::new("scratch"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.remove_file(1, OsStr::new("scratch"), 0).unwrap();
+        fs.forget_inode(scratch, 1);
+        fs.create_dir(1, OsStr::new("empty"), 0, 0, None, 0)
+            .unwrap();
+        fs.remove_dir(1, OsStr::new("empty"), 0).unwrap();
+        fs.rename_ex(docs, OsStr::new("draft"), docs, OsStr::new("final"), 0, 0)
+            .unwrap();
+        fs.link_node(draft, 1, OsStr::new("hardlink")).unwrap();
+        let changes = nsfs::SetAttr {
+            size: Some(9),
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        fs.set_attr(draft, changes).unwrap();
+        let opaque = OsStr::new("trusted.overlay.opaque");
+        fs.set_xattr(docs, opaque, b"y", 0).unwrap();
+        fs.set_xattr(draft, OsStr::new("user.kept"), b"1", 0)
+            .unwrap();
+        fs.set_xattr(draft, OsStr::new("user.dropped"), b"2", 0)
+            .unwrap();
+        fs.remove_xattr(draft, OsStr::new("user.dropped")).unwrap();
+        let mnt = fs
+            .create_dir(1, OsStr::new("mnt"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        fs.control(mnt, OsStr::new("user.nsfs.volume"), b"2", 0)
+            .unwrap();
+        let mounted = |fs: &mut nsfs::NsFS| {
+            matches!(
+                fs.link_node(draft, mnt, OsStr::new("link")),
+                Err(nsfs::Error::CrossDevice)
+            )
+        };
+
+        // nothing was saved since the log was opened, as after a crash
+        let mut replayed = nsfs::NsFS::load_from(&path).unwrap();
+        assert!(mounted(&mut replayed));
+        assert_eq!(replayed.to_json_with_content(), fs.to_json_with_content());
+        assert_eq!(replayed.get_attr(draft).unwrap().nlink, 2);
+        assert_eq!(&*replayed.get_xattr(docs, opaque).unwrap(), b"y");
+        assert_eq!(
+            replayed.list_xattr(draft).unwrap(),
+            fs.list_xattr(draft).unwrap()
+        );
+        assert!(matches!(
+            replayed.get_xattr(draft, OsStr::new("user.dropped")),
+            Err(nsfs::Error::NoAttribute)
+        ));
+
+        // a checkpoint takes the records into the snapshot, which keeps a
+        // single name per inode
+        fs.remove_file(1, OsStr::new("hardlink"), 0).unwrap();
+        fs.checkpoint().unwrap();
+        let mut loaded = nsfs::NsFS::load_from(&path).unwrap();
+        assert_eq!(loaded.to_json_with_content(), fs.to_json_with_content());
+        assert!(mounted(&mut loaded));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_negative_lookups() {
+        let mut fs = nsfs::NsFS::new();
+        let queue = nsfs::InvalidationQueue::default();
+        fs.invalidations = Some(queue.clone());
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert!(fs.negative_entry(dir, OsStr::new("missing")).is_none());
+
+        fs.negative_ttl = Some(Duration::from_secs(5));
+        let (ttl, attrs) = fs.negative_entry(dir, OsStr::new("missing")).unwrap();
+        assert_eq!(ttl, Duration::from_secs(5));
+        assert_eq!(attrs.ino, 0);
+        // a missing parent isn't a missing name
+        assert!(fs.negative_entry(file, OsStr::new("missing")).is_none());
+        assert!(fs.negative_entry(99, OsStr::new("missing")).is_none());
+        fs.negative_entry(1, OsStr::new("moved")).unwrap();
+        fs.negative_entry(1, OsStr::new("link")).unwrap();
+
+        fs.create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap();
+        assert!(queue.drain_entries().is_empty());
+        fs.create_file(dir, OsStr::new("missing"), 0, 0, 0, None, 0)
+            .unwrap();
+        fs.rename_ex(1, OsStr::new("other"), 1, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        fs.link_node(file, 1, OsStr::new("link")).unwrap();
+        assert_eq!(
+            queue.drain_entries(),
+            [
+                (dir, std::ffi::OsString::from("missing")),
+                (1, std::ffi::OsString::from("moved")),
+                (1, std::ffi::OsString::from("link")),
+            ]
+        );
+
+        // only names answered as missing are invalidated, and only once
+        fs.remove_file(dir, OsStr::new("missing"), 0).unwrap();
+        fs.create_file(dir, OsStr::new("missing"), 0, 0, 0, None, 0)
+            .unwrap();
+        assert!(queue.drain_entries().is_empty());
+    }
+
+    #[test]
+    fn test_checksum_xattr() {
+        // bit by bit, without the table the filesystem uses
+        fn crc32(data: &[u8]) -> String {
+            let mut crc = !0u32;
+            for &byte in data {
+                crc ^= byte as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 == 1 {
+                        (crc >> 1) ^ 0xedb8_8320
+                    } else {
+                        crc >> 1
+                    };
+                }
+            }
+            format!("{:08x}", !crc)
+        }
+        let checksum = |fs: &nsfs::NsFS, ino| {
+            let value = fs.get_xattr(ino, OsStr::new("user.checksum")).unwrap();
+            String::from_utf8(value.to_vec()).unwrap()
+        };
+
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        assert_eq!(checksum(&fs, ino), "00000000");
+        fs.write_file(ino, b"123456789", 0).unwrap();
+        assert_eq!(checksum(&fs, ino), "cbf43926");
+
+        // appending past the end, overwriting and truncating
+        let mut expected = b"123456789".to_vec();
+        fs.write_file(ino, b"Hello, Rust", 5000).unwrap();
+        expected.resize(5000, 0);
+        expected.extend_from_slice(b"Hello, Rust");
+        assert_eq!(checksum(&fs, ino), crc32(&expected));
+        fs.write_file(ino, b"nsfs", 5007).unwrap();
+        expected[5007..5011].copy_from_slice(b"nsfs");
+        assert_eq!(checksum(&fs, ino), crc32(&expected));
+        let truncate = nsfs::SetAttr {
+            size: Some(4),
+            ..Default::default()
+        };
+        fs.set_attr(ino, truncate).unwrap();
+        assert_eq!(checksum(&fs, ino), crc32(b"1234"));
+
+        assert!(matches!(
+            fs.set_xattr(ino, OsStr::new("user.checksum"), b"0", 0),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.get_xattr(1, OsStr::new("user.checksum")),
+            Err(nsfs::Error::NoAttribute)
+        ));
+    }
+
+    #[test]
+    fn test_create_existing() {
+        let mut fs = nsfs::NsFS::new();
+        let create = (libc::O_CREAT | libc::O_WRONLY) as u32;
+        let (attrs, first) = fs
+            .create_file(1, OsStr::new("file"), create, 1000, 1000, Some(0o644), 0)
+            .unwrap();
+        let ino = attrs.ino;
+        fs.write_file(ino, b"Hello", 0).unwrap();
+
+        // without O_EXCL the existing file is opened, as it is
+        let (attrs, second) = fs
+            .create_file(1, OsStr::new("file"), create, 0, 0, Some(0o600), 0)
+            .unwrap();
+        assert_eq!(attrs.ino, ino);
+        assert_eq!((attrs.size, attrs.perm, attrs.uid), (5, 0o644, 1000));
+        assert_ne!(second, first);
+        assert_eq!(fs.list_dir(1).unwrap().len(), 2);
+
+        let exclusive = create | libc::O_EXCL as u32;
+        assert!(matches!(
+            fs.create_file(1, OsStr::new("file"), exclusive, 0, 0, None, 0),
+            Err(nsfs::Error::AlreadyExists)
+        ));
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        assert!(matches!(
+            fs.create_file(1, OsStr::new("dir"), create, 0, 0, None, 0),
+            Err(nsfs::Error::IsADirectory)
+        ));
+        assert!(matches!(
+            fs.create_file(1, OsStr::new("dir"), exclusive, 0, 0, None, 0),
+            Err(nsfs::Error::AlreadyExists)
+        ));
+        let (attrs, _) = fs
+            .create_file(dir, OsStr::new("new"), exclusive, 0, 0, None, 0)
+            .unwrap();
+        assert_eq!(attrs.size, 0);
+    }
+
+    #[test]
+    fn test_shadow_root() {
+        let root = std::env::temp_dir().join(format!("nsfs-shadow-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut fs = nsfs::NsFS::new();
+        fs.shadow_root = Some(root.clone());
+
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let file = fs
+            .create_file(dir, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(file, b"Hello, Rust", 0).unwrap();
+        fs.write_file(file, b"nsfs", 7).unwrap();
+        let gone = fs
+            .create_file(1, OsStr::new("gone"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(gone, b"bye", 0).unwrap();
+        fs.remove_file(1, OsStr::new("gone"), 0).unwrap();
+
+        let moved = fs
+            .create_file(1, OsStr::new("a"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(moved, b"Hello, Rust", 0).unwrap();
+        fs.rename_ex(1, OsStr::new("a"), dir, OsStr::new("b"), 0, 0)
+            .unwrap();
+        fs.write_file(moved, b"nsfs", 7).unwrap();
+        let truncate = nsfs::SetAttr {
+            size: Some(5),
+            ..Default::default()
+        };
+        fs.set_attr(file, truncate).unwrap();
+        fs.sync_shadow();
+
+        assert_eq!(
+            std::fs::read(root.join("dir/file")).unwrap(),
+            fs.read_file(file, 64, 0).unwrap()
+        );
+        assert_eq!(std::fs::read(root.join("dir/file")).unwrap(), b"Hello");
+        assert!(!root.join("gone").exists());
+        assert!(!root.join("a").exists());
+        assert_eq!(std::fs::read(root.join("dir/b")).unwrap(), b"Hello, nsfs");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_find_by_prefix() {
+        let mut fs = nsfs::NsFS::new();
+        let dir = fs
+            .create_dir(1, OsStr::new("src"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        for name in ["main.rs", "mod.rs", "m", "lib.rs", "main.o", "ma"] {
+            fs.create_file(dir, OsStr::new(name), 0, 0, 0, None, 0)
+                .unwrap();
+        }
+        let names = |fs: &nsfs::NsFS, prefix: &str| -> Vec<String> {
+            fs.find_by_prefix(dir, OsStr::new(prefix))
+                .unwrap()
+                .into_iter()
+                .map(|name| name.into_string().unwrap())
+                .collect()
+        };
+
+        let expected = ["ma", "main.o", "main.rs"];
+        assert_eq!(names(&fs, "ma"), expected);
+        fs.set_sorted_index(true);
+        assert_eq!(names(&fs, "ma"), expected);
+        assert_eq!(
+            names(&fs, ""),
+            ["lib.rs", "m", "ma", "main.o", "main.rs", "mod.rs"]
+        );
+        assert!(names(&fs, "x").is_empty());
+
+        // the index follows changes, including in new directories
+        fs.remove_file(dir, OsStr::new("main.o"), 0).unwrap();
+        fs.rename_ex(dir, OsStr::new("lib.rs"), dir, OsStr::new("macro.rs"), 0, 0)
+            .unwrap();
+        assert_eq!(names(&fs, "ma"), ["ma", "macro.rs", "main.rs"]);
+        let sub = fs
+            .create_dir(dir, OsStr::new("sub"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        fs.create_file(sub, OsStr::new("mask"), 0, 0, 0, None, 0)
+            .unwrap();
+        assert_eq!(fs.find_by_prefix(sub, OsStr::new("ma")).unwrap(), ["mask"]);
+
+        let file = fs.find_node(dir, OsStr::new("m")).unwrap().index;
+        assert!(matches!(
+            fs.find_by_prefix(file, OsStr::new("")),
+            Err(nsfs::Error::NotADirectory)
+        ));
+
+        // the kernel gets them as attributes of the directory
+        let listing = fs.get_xattr(dir, OsStr::new("user.prefix.ma")).unwrap();
+        assert_eq!(&*listing, b"ma\nmacro.rs\nmain.rs\n");
+        assert!(fs
+            .get_xattr(dir, OsStr::new("user.prefix.x"))
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            fs.set_xattr(dir, OsStr::new("user.prefix.ma"), b"", 0),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn test_recursive_size() {
+        let mut fs = nsfs::NsFS::new();
+        fs.set_block_size(512).unwrap();
+        let top = fs
+            .create_dir(1, OsStr::new("top"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let sub = fs
+            .create_dir(top, OsStr::new("sub"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let mut file = |parent, name: &str, size: usize| {
+            let ino = fs
+                .create_file(parent, OsStr::new(name), 0, 0, 0, None, 0)
+                .unwrap()
+                .0
+                .ino;
+            fs.write_file(ino, &vec![b'x'; size], 0).unwrap();
+            ino
+        };
+        file(top, "one", 1);
+        file(top, "block", 512);
+        let linked = file(sub, "more", 513);
+        file(sub, "empty", 0);
+        fs.link_node(linked, top, OsStr::new("link")).unwrap();
+
+        // top holds 4 entries and sub 2, 32 bytes each; files and
+        // directories take whole 512 byte blocks, the linked file once
+        let expected = 512 + 512 + 512 + 512 + 1024;
+        assert_eq!(fs.recursive_size(top).unwrap(), expected);
+        assert_eq!(fs.recursive_size(sub).unwrap(), 512 + 1024);
+        assert_eq!(fs.recursive_size(linked).unwrap(), 1024);
+        assert_eq!(
+            *fs.get_xattr(top, OsStr::new("user.du")).unwrap(),
+            *expected.to_string().as_bytes()
+        );
+        assert!(matches!(
+            fs.get_xattr(linked, OsStr::new("user.du")),
+            Err(nsfs::Error::NoAttribute)
+        ));
+        assert!(matches!(fs.recursive_size(99), Err(nsfs::Error::NotFound)));
+    }
+
+    #[test]
+    fn test_block_size() {
+        let mut fs = nsfs::NsFS::new();
+        fs.capacity_bytes = Some(1 << 20);
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, &[1; 1500], 0).unwrap();
+        assert_eq!(fs.get_attr(ino).unwrap().blocks, 8);
+
+        fs.set_block_size(1024).unwrap();
+        assert_eq!(fs.block_size(), 1024);
+        assert_eq!(fs.statfs_blocks(), (1024, 1022));
+        let attrs = fs.get_attr(ino).unwrap();
+        assert_eq!((attrs.blksize, attrs.blocks), (1024, 4));
+        assert_eq!(fs.get_attr(1).unwrap().blksize, 1024);
+        let statx = fs.statx(ino).unwrap();
+        assert_eq!((statx.blksize, statx.blocks), (1024, 4));
+
+        let other = fs
+            .create_file(1, OsStr::new("other"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(other, &[1; 1025], 0).unwrap();
+        let attrs = fs.get_attr(other).unwrap();
+        assert_eq!((attrs.blksize, attrs.blocks), (1024, 4));
+
+        for invalid in [0, 256, 1000] {
+            assert!(matches!(
+                fs.set_block_size(invalid),
+                Err(nsfs::Error::InvalidArgument)
+            ));
+        }
+        let args = |list: &[&str]| -> Vec<String> { list.iter().map(|a| a.to_string()).collect() };
+        let options = parse_args(&args(&["nsfs", "--block-size", "1024", "/mnt"])).unwrap();
+        assert_eq!(options.block_size, Some(1024));
+        assert!(parse_args(&args(&["nsfs", "--block-size", "1000", "/mnt"])).is_err());
+    }
+
+    #[test]
+    fn test_access() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 1000, 1000, Some(0o640), 0)
+            .unwrap()
+            .0
+            .ino;
+
+        assert!(fs
+            .check_access(ino, 1000, 1000, libc::R_OK | libc::W_OK)
+            .is_ok());
+        assert!(fs.check_access(ino, 2000, 1000, libc::R_OK).is_ok());
+        assert!(matches!(
+            fs.check_access(ino, 2000, 1000, libc::W_OK),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.check_access(ino, 2000, 2000, libc::R_OK),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(fs.check_access(ino, 2000, 2000, libc::F_OK).is_ok());
+    }
+
+    #[test]
+    fn test_access_root() {
+        let mut fs = nsfs::NsFS::new();
+        let ino = fs
+            .create_file(1, OsStr::new("file"), 0, 1000, 1000, Some(0o000), 0)
+            .unwrap()
+            .0
+            .ino;
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 1000, 1000, Some(0o000), 0)
+            .unwrap()
+            .ino;
+
+        assert!(fs.check_access(ino, 0, 0, libc::R_OK | libc::W_OK).is_ok());
+        assert!(matches!(
+            fs.check_access(ino, 0, 0, libc::X_OK),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+        assert!(fs.check_access(dir, 0, 0, libc::R_OK | libc::X_OK).is_ok());
+
+        // any execute bit is enough for root
+        let chmod = nsfs::SetAttr {
+            mode: Some(0o001),
+            ..Default::default()
+        };
+        fs.set_attr(ino, chmod).unwrap();
+        assert!(fs.check_access(ino, 0, 0, libc::X_OK).is_ok());
+        assert!(matches!(
+            fs.check_access(ino, 1000, 1000, libc::X_OK),
+            Err(nsfs::Error::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn test_events() {
+        use nsfs::FsEvent;
+
+        let mut fs = nsfs::NsFS::new();
+        let events = fs.subscribe();
+        let dir = fs
+            .create_dir(1, OsStr::new("dir"), 0, 0, None, 0)
+            .unwrap()
+            .ino;
+        let ino = fs
+            .create_file(dir, OsStr::new("file"), 0, 0, 0, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, b"hello", 0).unwrap();
+        fs.rename_ex(dir, OsStr::new("file"), 1, OsStr::new("moved"), 0, 0)
+            .unwrap();
+        fs.remove_file(1, OsStr::new("moved"), 0).unwrap();
+
+        let received: Vec<FsEvent> = events.try_iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                FsEvent::Created {
+                    ino: dir,
+                    path: "/dir".into()
+                },
+                FsEvent::Created {
+                    ino,
+                    path: "/dir/file".into()
+                },
+                FsEvent::Modified {
+                    ino,
+                    path: "/dir/file".into()
+                },
+                FsEvent::Renamed {
+                    ino,
+                    from: "/dir/file".into(),
+                    to: "/moved".into()
+                },
+                FsEvent::Removed {
+                    ino,
+                    path: "/moved".into()
+                },
+            ]
+        );
+
+        // a dropped receiver is unsubscribed
+        drop(events);
+        fs.create_dir(1, OsStr::new("other"), 0, 0, None, 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_create_existing_lookup_count() {
+        let mut fs = nsfs::NsFS::new();
+        let create = (libc::O_CREAT | libc::O_WRONLY) as u32;
+        let (attrs, first) = fs
+            .create_file(1, OsStr::new("file"), create, 0, 0, None, 0)
+            .unwrap();
+        let ino = attrs.ino;
+        let (_, second) = fs
+            .create_file(1, OsStr::new("file"), create, 0, 0, None, 0)
+            .unwrap();
+        fs.close_file(first).unwrap();
+        fs.close_file(second).unwrap();
+        fs.remove_file(1, OsStr::new("file"), 0).unwrap();
+
+        // both replies counted as a lookup, so it takes two forgets
+        fs.forget_inode(ino, 1);
+        assert!(fs.get_attr(ino).is_ok());
+        fs.forget_inode(ino, 1);
+        assert!(fs.get_attr(ino).is_err());
+    }
+
+    #[test]
+    fn test_create_existing_truncate() {
+        let mut fs = nsfs::NsFS::new();
+        fs.set_quota(1000, 100);
+        let create = (libc::O_CREAT | libc::O_WRONLY) as u32;
+        let ino = fs
+            .create_file(1, OsStr::new("file"), create, 1000, 1000, None, 0)
+            .unwrap()
+            .0
+            .ino;
+        fs.write_file(ino, &[1; 80], 0).unwrap();
+
+        let truncate = create | libc::O_TRUNC as u32;
+        let (attrs, _) = fs
+            .create_file(1, OsStr::new("file"), truncate, 1000, 1000, None, 0)
+            .unwrap();
+        assert_eq!((attrs.ino, attrs.size, attrs.blocks), (ino, 0, 0));
+        assert!(fs.read_file(ino, 10, 0).unwrap().is_empty());
+        // the truncated bytes no longer count against the quota
+        assert_eq!(fs.write_file(ino, &[2; 80], 0).unwrap(), 80);
+
+        fs.create_special(1, OsStr::new("fifo"), libc::S_IFIFO | 0o644, 0, 0, 0, 0)
+            .unwrap();
+        fs.create_symlink(1, OsStr::new("link"), Path::new("file"), 0, 0)
+            .unwrap();
+        for name in ["fifo", "link"] {
+            assert!(matches!(
+                fs.create_file(1, OsStr::new(name), create, 0, 0, None, 0),
+                Err(nsfs::Error::AlreadyExists)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let mut fs = nsfs::NsFS::new();
+        let parent = 1;
+        let flags = 0;
+
+        let first = {
+            let (attrs, _) = fs
+                .create_file(parent, OsStr::new("first"), flags, 0, 0, None, 0)
+                .unwrap();
+            attrs.ino
+        };
+        let second = {
+            let (attrs, _) = fs
+                .create_file(parent, OsStr::new("second"), flags, 0, 0, None, 0)
+                .unwrap();
+            attrs.ino
+        };
+
+        fs.write_file(first, b"Hello, Rust", 0).unwrap();
+        fs.write_file(second, b"Hello", 0).unwrap();
+
+        let stats = fs.memory_stats();
+        assert_eq!(
+            stats,
+            nsfs::MemoryStats {
+                file_bytes: 16,
+                inodes: 3,
+                open_files: 2,
+                dir_entries: 2,
+            }
+        );
+    }
+}
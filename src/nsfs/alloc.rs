@@ -0,0 +1,88 @@
+use crate::nsfs::INode;
+
+use std::path::Path;
+
+/// Hands out inode numbers for new nodes and takes back the ones whose
+/// nodes are gone.
+pub(crate) trait InodeAllocator: Send {
+    fn next(&mut self) -> INode;
+    fn free(&mut self, ino: INode);
+
+    /// The inode for a new node at `path`. Allocators that don't care about
+    /// paths hand out the next one.
+    fn next_for(&mut self, _path: &Path) -> INode {
+        self.next()
+    }
+}
+
+/// Counts up from the last inode handed out and never reuses freed ones.
+pub(crate) struct MonotonicAllocator {
+    current: INode,
+}
+
+impl MonotonicAllocator {
+    /// An allocator whose first inode is `last + 1`.
+    pub(crate) fn after(last: INode) -> Self {
+        Self { current: last }
+    }
+}
+
+impl InodeAllocator for MonotonicAllocator {
+    fn next(&mut self) -> INode {
+        self.current += 1;
+        self.current
+    }
+
+    fn free(&mut self, _ino: INode) {}
+}
+
+/// Derives inodes from the path of the node, so the same tree gets the same
+/// inodes no matter in which order it's built. Collisions are resolved by
+/// taking the next free inode.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct PathHashAllocator {
+    used: std::collections::HashSet<INode>,
+}
+
+#[cfg(test)]
+impl PathHashAllocator {
+    /// Inodes are taken from `[FIRST, crate::nsfs::HANDLES_INO)`, after the root.
+    const FIRST: INode = 2;
+
+    // FNV-1a, which unlike the std hashers is guaranteed to stay the same
+    fn hash(path: &Path) -> u64 {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str()
+            .as_bytes()
+            .iter()
+            .fold(0xcbf29ce484222325, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+
+    fn probe(&mut self, start: INode) -> INode {
+        let range = crate::nsfs::HANDLES_INO - Self::FIRST;
+        let mut ino = start;
+        while !self.used.insert(ino) {
+            ino = Self::FIRST + (ino - Self::FIRST + 1) % range;
+        }
+        ino
+    }
+}
+
+#[cfg(test)]
+impl InodeAllocator for PathHashAllocator {
+    fn next(&mut self) -> INode {
+        self.probe(Self::FIRST)
+    }
+
+    fn free(&mut self, ino: INode) {
+        self.used.remove(&ino);
+    }
+
+    fn next_for(&mut self, path: &Path) -> INode {
+        let range = crate::nsfs::HANDLES_INO - Self::FIRST;
+        self.probe(Self::FIRST + Self::hash(path) % range)
+    }
+}
@@ -0,0 +1,69 @@
+use crate::nsfs::{Error, File, INode};
+
+use std::path::PathBuf;
+
+/// Durable storage that file content is persisted to.
+pub(crate) trait Backing: Send {
+    fn persist(&mut self, ino: INode, file: &File) -> Result<(), Error>;
+}
+
+/// Keeps nothing; the filesystem only lives in memory.
+pub(crate) struct NoBacking;
+
+impl Backing for NoBacking {
+    fn persist(&mut self, _ino: INode, _file: &File) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Stores the content of every file as `<root>/<ino>`.
+pub(crate) struct DirBacking {
+    root: PathBuf,
+}
+
+impl DirBacking {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Backing for DirBacking {
+    fn persist(&mut self, ino: INode, file: &File) -> Result<(), Error> {
+        std::fs::write(self.root.join(ino.to_string()), file.to_vec()).map_err(|err| {
+            log::warn!("persisting inode {} failed: {}", ino, err);
+            Error::Io
+        })
+    }
+}
+
+/// A backing that remembers which inodes were persisted, in order, and can be
+/// told to fail. Clones share the same record.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct RecordingBacking {
+    persisted: std::sync::Arc<std::sync::Mutex<Vec<INode>>>,
+    failing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(test)]
+impl RecordingBacking {
+    pub(crate) fn persisted(&self) -> Vec<INode> {
+        self.persisted.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_failing(&self, failing: bool) {
+        self.failing
+            .store(failing, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Backing for RecordingBacking {
+    fn persist(&mut self, ino: INode, _file: &File) -> Result<(), Error> {
+        if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(Error::Io);
+        }
+        self.persisted.lock().unwrap().push(ino);
+        Ok(())
+    }
+}
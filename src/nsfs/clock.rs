@@ -0,0 +1,41 @@
+use std::time::SystemTime;
+
+/// Source of the current time for timestamps, replaceable in tests.
+pub(crate) trait Clock: Send {
+    fn now(&self) -> SystemTime;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct ManualClock {
+    now: std::sync::Arc<std::sync::Mutex<SystemTime>>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub(crate) fn new(now: SystemTime) -> Self {
+        Self {
+            now: std::sync::Arc::new(std::sync::Mutex::new(now)),
+        }
+    }
+
+    pub(crate) fn advance(&self, duration: std::time::Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,163 @@
+use crate::nsfs::{blocks_for, charge, Error, INode, NsFS, WalRecord};
+
+use fuser::{FileAttr, FileType};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+
+impl NsFS {
+    /// Copies the subtree rooted at `src` to `name` under `dst_parent`. Every
+    /// copied node gets a fresh inode along with the permissions, ownership,
+    /// contents and extended attributes of its source. Files linked several
+    /// times within the subtree are copied once and linked the same way.
+    pub(crate) fn clone_subtree(
+        &mut self,
+        src: INode,
+        dst_parent: INode,
+        name: &OsStr,
+    ) -> Result<&FileAttr, Error> {
+        if !self.nodes.contains_key(&src) {
+            return Err(Error::NotFound);
+        }
+        // the copy would keep growing the tree it's copied from
+        if self.is_within(dst_parent, src) {
+            return Err(Error::InvalidArgument);
+        }
+        self.check_clone_space(src)?;
+
+        let mut copies = HashMap::new();
+        let root = self.clone_node(src, dst_parent, name, &mut copies)?;
+        let mut pending = vec![(src, root)];
+        while let Some((src, dst)) = pending.pop() {
+            let mut children: Vec<(OsString, INode)> = match self.nodes.get(&src) {
+                Some(node) => node
+                    .children
+                    .iter()
+                    .map(|(name, &ino)| (name.clone(), ino))
+                    .collect(),
+                None => continue,
+            };
+            children.sort();
+
+            for (name, child) in children {
+                let copy = self.clone_node(child, dst, &name, &mut copies)?;
+                if self.nodes.get(&child).map(|node| node.kind) == Some(FileType::Directory) {
+                    pending.push((child, copy));
+                }
+            }
+        }
+
+        self.attrs.get(&root).ok_or(Error::NotFound)
+    }
+
+    /// Fails unless the content below `src`, each file counted once, fits in
+    /// the free space and in the quotas of the owners the copies keep.
+    fn check_clone_space(&self, src: INode) -> Result<(), Error> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![src];
+        let mut total = 0;
+        let mut by_uid: HashMap<u32, u64> = HashMap::new();
+        while let Some(ino) = pending.pop() {
+            if !seen.insert(ino) {
+                continue;
+            }
+            if let (Some(attrs), Some(file)) = (self.attrs.get(&ino), self.files.get(&ino)) {
+                total += file.len() as u64;
+                *by_uid.entry(attrs.uid).or_default() += file.len() as u64;
+            }
+            if let Some(node) = self.nodes.get(&ino) {
+                pending.extend(node.children.values());
+            }
+        }
+
+        if self.free_bytes().is_some_and(|free| total > free) {
+            return Err(Error::NoSpace);
+        }
+        for (uid, bytes) in by_uid {
+            self.check_quota(uid, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the single node `src` to `name` in `parent`, or links the copy
+    /// made of it before, as listed in `copies`.
+    fn clone_node(
+        &mut self,
+        src: INode,
+        parent: INode,
+        name: &OsStr,
+        copies: &mut HashMap<INode, INode>,
+    ) -> Result<INode, Error> {
+        if let Some(&copy) = copies.get(&src) {
+            self.link_node(copy, parent, name)?;
+            // nothing was handed to the kernel
+            self.lookup_counts.remove(&copy);
+            return Ok(copy);
+        }
+        let attrs = match self.attrs.get(&src) {
+            Some(attrs) => *attrs,
+            None => return Err(Error::NotFound),
+        };
+        self.fault_in(src)?;
+
+        let ino = match self.symlinks.get(&src).cloned() {
+            Some(target) => {
+                self.create_symlink(parent, name, &target, attrs.uid, attrs.gid)?
+                    .ino
+            }
+            None => {
+                let ino = self.insert_node(
+                    parent,
+                    name,
+                    attrs.kind,
+                    attrs.rdev,
+                    attrs.flags,
+                    attrs.uid,
+                    attrs.gid,
+                )?;
+                self.apply_mode(ino, attrs.perm as u32, 0);
+                self.log_create(parent, name, ino);
+                ino
+            }
+        };
+        self.lookup_counts.remove(&ino);
+        copies.insert(src, ino);
+
+        if let Some(file) = self.files.get(&src).cloned() {
+            let len = file.len() as u64;
+            charge(&mut self.usage_by_uid, attrs.uid, 0, len);
+            if let Some(copy) = self.attrs.get_mut(&ino) {
+                copy.size = len;
+                copy.blocks = blocks_for(len, self.block_size);
+            }
+            let mirrored = self.wal.is_some() || self.shadow_root.is_some();
+            let data = (mirrored && len > 0).then(|| file.to_vec());
+            self.files.insert(ino, file);
+            self.shadow_create(parent, name);
+            if let Some(data) = data {
+                self.shadow_content(ino, &data);
+                self.log_change(WalRecord::Content {
+                    ino,
+                    data: Cow::Owned(data),
+                });
+            }
+            self.persist_content(ino)?;
+        }
+        if let Some(xattrs) = self.xattrs.get(&src).cloned() {
+            for (name, value) in &xattrs {
+                self.log_change(WalRecord::SetXattr {
+                    ino,
+                    name: Cow::Borrowed(name),
+                    value: Cow::Borrowed(value),
+                });
+            }
+            self.xattrs.insert(ino, xattrs);
+        }
+        // a symlink was reported as it was made
+        if attrs.kind != FileType::Symlink {
+            self.emit_created(parent, name, ino);
+        }
+
+        Ok(ino)
+    }
+}
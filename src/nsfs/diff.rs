@@ -0,0 +1,95 @@
+use crate::nsfs::{INode, NsFS};
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// A way two trees differ at a path. Volatile metadata such as timestamps
+/// and inode numbers is not compared.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Difference {
+    /// The path only exists in the first tree.
+    OnlyInSelf(PathBuf),
+    /// The path only exists in the second tree.
+    OnlyInOther(PathBuf),
+    Kind(PathBuf),
+    Perm(PathBuf),
+    Size(PathBuf),
+    Content(PathBuf),
+}
+
+impl NsFS {
+    /// Whether both filesystems hold the same tree.
+    pub(crate) fn tree_eq(&self, other: &NsFS) -> bool {
+        self.tree_diff(other).is_empty()
+    }
+
+    /// Everything that differs between the two trees, ordered by path.
+    pub(crate) fn tree_diff(&self, other: &NsFS) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        self.diff_dir(other, 1, 1, Path::new("/"), &mut differences);
+        differences
+    }
+
+    fn diff_dir(
+        &self,
+        other: &NsFS,
+        ino: INode,
+        other_ino: INode,
+        path: &Path,
+        differences: &mut Vec<Difference>,
+    ) {
+        let children = match self.nodes.get(&ino) {
+            Some(node) => &node.children,
+            None => return,
+        };
+        let other_children = match other.nodes.get(&other_ino) {
+            Some(node) => &node.children,
+            None => return,
+        };
+
+        let names: BTreeSet<&OsString> = children.keys().chain(other_children.keys()).collect();
+        for name in names {
+            let path = path.join(name);
+            match (children.get(name), other_children.get(name)) {
+                (Some(&ino), Some(&other_ino)) => {
+                    self.diff_entry(other, ino, other_ino, &path, differences)
+                }
+                (Some(_), None) => differences.push(Difference::OnlyInSelf(path)),
+                (None, Some(_)) => differences.push(Difference::OnlyInOther(path)),
+                (None, None) => unreachable!(),
+            }
+        }
+    }
+
+    fn diff_entry(
+        &self,
+        other: &NsFS,
+        ino: INode,
+        other_ino: INode,
+        path: &Path,
+        differences: &mut Vec<Difference>,
+    ) {
+        let (attrs, other_attrs) = match (self.attrs.get(&ino), other.attrs.get(&other_ino)) {
+            (Some(attrs), Some(other_attrs)) => (attrs, other_attrs),
+            _ => return,
+        };
+
+        if attrs.kind != other_attrs.kind {
+            differences.push(Difference::Kind(path.to_path_buf()));
+            return;
+        }
+        if attrs.perm != other_attrs.perm {
+            differences.push(Difference::Perm(path.to_path_buf()));
+        }
+        if attrs.size != other_attrs.size {
+            differences.push(Difference::Size(path.to_path_buf()));
+        }
+
+        if self.files.get(&ino) != other.files.get(&other_ino) {
+            differences.push(Difference::Content(path.to_path_buf()));
+        }
+
+        self.diff_dir(other, ino, other_ino, path, differences);
+    }
+}
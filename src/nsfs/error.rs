@@ -1,12 +1,36 @@
-use libc::{c_int, EEXIST, ENOENT, EOF};
+use libc::{
+    c_int, EACCES, EAGAIN, EBADF, EDQUOT, EEXIST, EFBIG, EINTR, EINVAL, EIO, EISDIR, ELOOP, EMFILE,
+    ENODATA, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENXIO, EOF, EOPNOTSUPP, EPERM, ETXTBSY, EXDEV,
+};
 
 #[derive(Debug)]
 pub enum Error {
     NotFound,
     FileNotFound,
     AttrsNotFound,
-    EOF,
+    Eof,
     AlreadyExists,
+    BadFileDescriptor,
+    NotADirectory,
+    InvalidArgument,
+    NoAttribute,
+    Io,
+    NotSupported,
+    PermissionDenied,
+    FileTooBig,
+    IsADirectory,
+    NoSpace,
+    TextBusy,
+    QuotaExceeded,
+    NotEmpty,
+    NoSuchAddress,
+    Interrupted,
+    DirFull,
+    WouldBlock,
+    NotPermitted,
+    TooManyLinks,
+    TooDeep,
+    CrossDevice,
 }
 
 impl std::fmt::Display for Error {
@@ -15,8 +39,29 @@ impl std::fmt::Display for Error {
             Error::NotFound => write!(f, "not found"),
             Error::AttrsNotFound => write!(f, "attributes not found"),
             Error::FileNotFound => write!(f, "file not found"),
-            Error::EOF => write!(f, "eof"),
+            Error::Eof => write!(f, "eof"),
             Error::AlreadyExists => write!(f, "already exists"),
+            Error::BadFileDescriptor => write!(f, "bad file descriptor"),
+            Error::NotADirectory => write!(f, "not a directory"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::NoAttribute => write!(f, "no such attribute"),
+            Error::Io => write!(f, "input/output error"),
+            Error::NotSupported => write!(f, "operation not supported"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::FileTooBig => write!(f, "file too big"),
+            Error::IsADirectory => write!(f, "is a directory"),
+            Error::NoSpace => write!(f, "no space left on device"),
+            Error::TextBusy => write!(f, "text file busy"),
+            Error::QuotaExceeded => write!(f, "disk quota exceeded"),
+            Error::NotEmpty => write!(f, "directory not empty"),
+            Error::NoSuchAddress => write!(f, "no such device or address"),
+            Error::Interrupted => write!(f, "interrupted"),
+            Error::DirFull => write!(f, "directory full"),
+            Error::WouldBlock => write!(f, "resource temporarily unavailable"),
+            Error::NotPermitted => write!(f, "operation not permitted"),
+            Error::TooManyLinks => write!(f, "too many levels of symbolic links"),
+            Error::TooDeep => write!(f, "directories nested too deep"),
+            Error::CrossDevice => write!(f, "cross-device link"),
         }
     }
 }
@@ -27,8 +72,29 @@ impl From<Error> for c_int {
     fn from(value: Error) -> Self {
         match value {
             Error::NotFound | Error::AttrsNotFound | Error::FileNotFound => ENOENT,
-            Error::EOF => EOF,
+            Error::Eof => EOF,
             Error::AlreadyExists => EEXIST,
+            Error::BadFileDescriptor => EBADF,
+            Error::NotADirectory => ENOTDIR,
+            Error::InvalidArgument => EINVAL,
+            Error::NoAttribute => ENODATA,
+            Error::Io => EIO,
+            Error::NotSupported => EOPNOTSUPP,
+            Error::PermissionDenied => EACCES,
+            Error::FileTooBig => EFBIG,
+            Error::IsADirectory => EISDIR,
+            Error::NoSpace => ENOSPC,
+            Error::TextBusy => ETXTBSY,
+            Error::QuotaExceeded => EDQUOT,
+            Error::NotEmpty => ENOTEMPTY,
+            Error::NoSuchAddress => ENXIO,
+            Error::Interrupted => EINTR,
+            Error::DirFull => EMFILE,
+            Error::WouldBlock => EAGAIN,
+            Error::NotPermitted => EPERM,
+            Error::TooManyLinks => ELOOP,
+            Error::TooDeep => ENOSPC,
+            Error::CrossDevice => EXDEV,
         }
     }
 }
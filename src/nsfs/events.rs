@@ -0,0 +1,87 @@
+use crate::nsfs::{INode, NsFS};
+
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+
+/// A change made to the filesystem, as sent to subscribers. Paths are
+/// absolute within the mount.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FsEvent {
+    /// A file, directory, special file or symlink was created, or a hard
+    /// link to an existing inode was made.
+    Created { ino: INode, path: PathBuf },
+    /// The content or the size of a file changed.
+    Modified { ino: INode, path: PathBuf },
+    /// A name was removed. The inode may live on through other links or
+    /// open handles.
+    Removed { ino: INode, path: PathBuf },
+    Renamed {
+        ino: INode,
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl NsFS {
+    /// Returns a channel receiving every change made from now on, in the
+    /// order they were made. Dropping the receiver unsubscribes.
+    #[allow(dead_code)]
+    pub(crate) fn subscribe(&mut self) -> Receiver<FsEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit_created(&mut self, parent: INode, name: &OsStr, ino: INode) {
+        if let Some(path) = self.entry_path(parent, name) {
+            self.emit(FsEvent::Created { ino, path });
+        }
+    }
+
+    /// Reports a change to `ino` by the path it's reachable from, if it's
+    /// still linked anywhere.
+    pub(crate) fn emit_modified(&mut self, ino: INode) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        if let Some(path) = self.path_of(ino) {
+            self.emit(FsEvent::Modified { ino, path });
+        }
+    }
+
+    pub(crate) fn emit_removed(&mut self, parent: INode, name: &OsStr, ino: INode) {
+        if let Some(path) = self.entry_path(parent, name) {
+            self.emit(FsEvent::Removed { ino, path });
+        }
+    }
+
+    pub(crate) fn emit_renamed(
+        &mut self,
+        ino: INode,
+        parent: INode,
+        name: &OsStr,
+        newparent: INode,
+        newname: &OsStr,
+    ) {
+        let from = self.entry_path(parent, name);
+        let to = self.entry_path(newparent, newname);
+        if let (Some(from), Some(to)) = (from, to) {
+            self.emit(FsEvent::Renamed { ino, from, to });
+        }
+    }
+
+    /// The path of `name` in `parent`, or `None` if nobody listens.
+    fn entry_path(&self, parent: INode, name: &OsStr) -> Option<PathBuf> {
+        if self.subscribers.is_empty() {
+            return None;
+        }
+        Some(self.path_of(parent)?.join(name))
+    }
+
+    /// Sends `event` to every subscriber, forgetting those that went away.
+    fn emit(&mut self, event: FsEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
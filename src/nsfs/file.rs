@@ -0,0 +1,265 @@
+use crate::nsfs::checksum::crc32_update;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, OnceLock, Weak};
+
+/// Size of the segments file content is split into.
+pub(crate) const SEGMENT_SIZE: usize = 4096;
+
+/// What holes read as.
+static ZEROES: [u8; SEGMENT_SIZE] = [0; SEGMENT_SIZE];
+
+type ContentHash = u64;
+
+/// The fewest entries `SegmentIndex` sweeps at.
+const MIN_SWEEP: usize = 1024;
+
+/// Segments by content, so files holding the same data can share it.
+#[derive(Default)]
+pub(crate) struct SegmentIndex {
+    segments: HashMap<ContentHash, Weak<Vec<u8>>>,
+    /// Segments that no file holds any more are dropped from the index once
+    /// it has this many entries, twice as many as the last sweep left, so
+    /// sweeping takes constant time per write on average.
+    sweep_at: usize,
+}
+
+impl SegmentIndex {
+    /// Number of segments known, including ones not swept yet.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Replaces `segment` with an identical one that's already known, or
+    /// makes it known otherwise.
+    fn share(&mut self, segment: &mut Arc<Vec<u8>>) {
+        let mut hasher = DefaultHasher::new();
+        segment.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        match self.segments.get(&hash).and_then(Weak::upgrade) {
+            Some(known) if Arc::ptr_eq(&known, segment) => {}
+            Some(known) if known == *segment => *segment = known,
+            // a hash collision, the segment just stays private
+            Some(_) => {}
+            None => {
+                self.segments.insert(hash, Arc::downgrade(segment));
+                if self.segments.len() >= self.sweep_at.max(MIN_SWEEP) {
+                    self.segments.retain(|_, known| known.strong_count() > 0);
+                    self.sweep_at = 2 * self.segments.len();
+                }
+            }
+        }
+    }
+}
+
+/// The content of a regular file, split into fixed-size segments keyed by
+/// their index so a write only touches the segments it overlaps. Segments
+/// that were never written are holes and read as zeroes. Segments are shared
+/// copy-on-write between files with the same content.
+#[derive(Clone, Default)]
+pub(crate) struct File {
+    segments: BTreeMap<usize, Arc<Vec<u8>>>,
+    len: usize,
+    /// CRC-32 of the content, once computed. Appending extends it, other
+    /// changes drop it.
+    checksum: OnceLock<u32>,
+}
+
+impl File {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn from_vec(data: Vec<u8>) -> Self {
+        let mut file = Self::new();
+        file.write(0, &data);
+        file
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub(crate) fn resident_bytes(&self) -> usize {
+        self.segments.values().map(|segment| segment.len()).sum()
+    }
+
+    pub(crate) fn segments(&self) -> impl Iterator<Item = &Arc<Vec<u8>>> {
+        self.segments.values()
+    }
+
+    /// Shares the segments overlapping `[offset, offset + len)` with other
+    /// files holding the same content.
+    pub(crate) fn share_segments(&mut self, offset: usize, len: usize, index: &mut SegmentIndex) {
+        let first = offset / SEGMENT_SIZE;
+        let last = (offset + len).div_ceil(SEGMENT_SIZE);
+        for (_, segment) in self.segments.range_mut(first..last) {
+            index.share(segment);
+        }
+    }
+
+    /// Borrows `[offset, offset + size)` if it lies within a single segment.
+    pub(crate) fn slice(&self, offset: usize, size: usize) -> Option<&[u8]> {
+        let index = offset / SEGMENT_SIZE;
+        let start = offset % SEGMENT_SIZE;
+        if start + size > SEGMENT_SIZE {
+            return None;
+        }
+        self.segments
+            .get(&index)
+            .map(|segment| &segment[start..start + size])
+    }
+
+    /// Copies `[offset, offset + size)` into `buf`, replacing its content.
+    pub(crate) fn read_into(&self, offset: usize, size: usize, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.resize(size, 0);
+        let end = offset + size;
+        let first = offset / SEGMENT_SIZE;
+        let last = end.div_ceil(SEGMENT_SIZE);
+        for (&index, segment) in self.segments.range(first..last) {
+            let segment_start = index * SEGMENT_SIZE;
+            let from = offset.max(segment_start);
+            let to = end.min(segment_start + SEGMENT_SIZE);
+            buf[from - offset..to - offset]
+                .copy_from_slice(&segment[from - segment_start..to - segment_start]);
+        }
+    }
+
+    /// Borrows `[offset, offset + size)` as consecutive pieces of at most one
+    /// segment each, without copying.
+    pub(crate) fn chunks(&self, offset: usize, size: usize) -> impl Iterator<Item = &[u8]> {
+        let end = offset + size;
+        let first = offset / SEGMENT_SIZE;
+        let last = end.div_ceil(SEGMENT_SIZE);
+        (first..last).map(move |index| {
+            let segment_start = index * SEGMENT_SIZE;
+            let from = offset.max(segment_start) - segment_start;
+            let to = end.min(segment_start + SEGMENT_SIZE) - segment_start;
+            match self.segments.get(&index) {
+                Some(segment) => &segment[from..to],
+                None => &ZEROES[from..to],
+            }
+        })
+    }
+
+    /// Stores `data` at `offset`, growing the file if it ends past the end.
+    /// Zeroes falling into a hole leave it a hole.
+    pub(crate) fn write(&mut self, offset: usize, data: &[u8]) {
+        match self.checksum.get_mut() {
+            Some(crc) if offset >= self.len => {
+                *crc = crc32_zeroes(*crc, offset - self.len);
+                *crc = crc32_update(*crc, data);
+            }
+            Some(_) => self.checksum = OnceLock::new(),
+            None => {}
+        }
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written;
+            let index = position / SEGMENT_SIZE;
+            let start = position % SEGMENT_SIZE;
+            let count = (SEGMENT_SIZE - start).min(data.len() - written);
+            let chunk = &data[written..written + count];
+            if !self.segments.contains_key(&index) && chunk.iter().all(|&byte| byte == 0) {
+                written += count;
+                continue;
+            }
+            let segment = self
+                .segments
+                .entry(index)
+                .or_insert_with(|| Arc::new(vec![0; SEGMENT_SIZE]));
+            Arc::make_mut(segment)[start..start + count].copy_from_slice(chunk);
+            written += count;
+        }
+        self.len = self.len.max(offset + data.len());
+    }
+
+    /// Segments `compact` would drop: ones past the end and ones holding
+    /// only zeroes.
+    pub(crate) fn compactable(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|(&index, segment)| !keeps(self.len, index, segment))
+            .count()
+    }
+
+    /// Turns segments holding only zeroes back into holes and drops segments
+    /// past the end, returning how many were dropped. The content is
+    /// unchanged.
+    pub(crate) fn compact(&mut self) -> usize {
+        let before = self.segments.len();
+        let len = self.len;
+        self.segments
+            .retain(|&index, segment| keeps(len, index, segment));
+        before - self.segments.len()
+    }
+
+    /// Truncates or extends the file to `len` bytes; extending leaves a hole.
+    pub(crate) fn resize(&mut self, len: usize) {
+        match self.checksum.get_mut() {
+            Some(crc) if len >= self.len => *crc = crc32_zeroes(*crc, len - self.len),
+            Some(_) => self.checksum = OnceLock::new(),
+            None => {}
+        }
+        if len < self.len {
+            self.segments.split_off(&len.div_ceil(SEGMENT_SIZE));
+            // bytes past the end must read as zeroes if the file grows again
+            let start = len % SEGMENT_SIZE;
+            if start != 0 {
+                if let Some(segment) = self.segments.get_mut(&(len / SEGMENT_SIZE)) {
+                    Arc::make_mut(segment)[start..].fill(0);
+                }
+            }
+        }
+        self.len = len;
+    }
+
+    /// The CRC-32 of the whole content.
+    pub(crate) fn checksum(&self) -> u32 {
+        *self
+            .checksum
+            .get_or_init(|| self.chunks(0, self.len).fold(0, crc32_update))
+    }
+
+    /// The whole content as one contiguous buffer.
+    pub(crate) fn to_vec(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.read_into(0, self.len, &mut data);
+        data
+    }
+}
+
+/// Continues `crc` over `count` zeroes.
+fn crc32_zeroes(mut crc: u32, mut count: usize) -> u32 {
+    while count > 0 {
+        let chunk = count.min(SEGMENT_SIZE);
+        crc = crc32_update(crc, &ZEROES[..chunk]);
+        count -= chunk;
+    }
+    crc
+}
+
+/// Whether segment `index` holds any of the first `len` bytes that aren't
+/// zero.
+fn keeps(len: usize, index: usize, segment: &[u8]) -> bool {
+    index * SEGMENT_SIZE < len && segment.iter().any(|&byte| byte != 0)
+}
+
+impl PartialEq for File {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.to_vec() == other.to_vec()
+    }
+}
@@ -0,0 +1,92 @@
+use crate::nsfs::{blocks_for, FileDescriptor, INode, NsFS};
+
+use fuser::{FileAttr, FileType};
+use std::ffi::{OsStr, OsString};
+
+/// Name of the synthetic directory listing open file handles, mirroring
+/// `/proc/<pid>/fd` for the mount.
+pub(crate) const HANDLES_DIR: &str = ".handles";
+
+/// Inode of the synthetic directory. Its entries follow it, so the whole range
+/// stays far above anything the regular inode counter hands out.
+pub(crate) const HANDLES_INO: INode = 1 << 48;
+
+fn handle_ino(fd: FileDescriptor) -> INode {
+    HANDLES_INO + 1 + fd
+}
+
+impl NsFS {
+    pub(crate) fn is_handle_inode(&self, ino: INode) -> bool {
+        self.debug_handles && ino >= HANDLES_INO
+    }
+
+    /// Entries of the synthetic directory, one per open file handle.
+    pub(crate) fn handle_entries(&self) -> Vec<(INode, OsString)> {
+        self.open_files
+            .keys()
+            .map(|fd| (handle_ino(fd), OsString::from(fd.to_string())))
+            .collect()
+    }
+
+    pub(crate) fn lookup_handle(&self, parent: INode, name: &OsStr) -> Option<FileAttr> {
+        if !self.debug_handles {
+            return None;
+        }
+
+        if parent == 1 && name == HANDLES_DIR {
+            return self.handle_attr(HANDLES_INO);
+        }
+
+        if parent == HANDLES_INO {
+            let fd: FileDescriptor = name.to_str()?.parse().ok()?;
+            return self.handle_attr(handle_ino(fd));
+        }
+
+        None
+    }
+
+    pub(crate) fn handle_attr(&self, ino: INode) -> Option<FileAttr> {
+        if !self.is_handle_inode(ino) {
+            return None;
+        }
+
+        let (kind, perm, size, nlink) = if ino == HANDLES_INO {
+            (FileType::Directory, 0o555, 0, 2)
+        } else {
+            let content = self.handle_content(ino)?;
+            (FileType::RegularFile, 0o444, content.len() as u64, 1)
+        };
+
+        let now = self.clock.now();
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: blocks_for(size, self.block_size),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm,
+            nlink,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: self.block_size,
+            flags: 0,
+        })
+    }
+
+    /// Describes the open file handle behind a synthetic entry.
+    pub(crate) fn handle_content(&self, ino: INode) -> Option<String> {
+        if !self.is_handle_inode(ino) || ino == HANDLES_INO {
+            return None;
+        }
+
+        let open_file = self.open_files.get(&(ino - HANDLES_INO - 1))?;
+        Some(format!(
+            "ino: {}\nflags: {:#o}\noffset: {}\n",
+            open_file.ino, open_file.flags, open_file.offset
+        ))
+    }
+}
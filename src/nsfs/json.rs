@@ -0,0 +1,251 @@
+use crate::nsfs::{Error, INode, NsFS, SetAttr};
+
+use fuser::FileType;
+use serde_json::{json, Map, Value};
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+impl NsFS {
+    /// The directory tree as nested JSON objects with the name, inode, kind,
+    /// size and permissions of every node, directories listing their
+    /// children sorted by name.
+    pub(crate) fn to_json(&self) -> String {
+        self.export_json(false)
+    }
+
+    /// Like `to_json`, with the content of regular files in base64.
+    pub(crate) fn to_json_with_content(&self) -> String {
+        self.export_json(true)
+    }
+
+    /// Builds a filesystem from a document written by `to_json`, with file
+    /// content where it was exported and holes of the exported size
+    /// otherwise. Inode numbers are allocated anew.
+    pub(crate) fn from_json(json: &str) -> io::Result<NsFS> {
+        let tree: Value = serde_json::from_str(json)
+            .map_err(|err| invalid(format!("not a JSON document: {}", err)))?;
+        let root = match tree.as_object() {
+            Some(root) if root.get("kind") == Some(&json!("directory")) => root,
+            _ => return Err(invalid("missing root directory".to_string())),
+        };
+
+        let mut fs = NsFS::new();
+        fs.import_node(1, Path::new("/"), root)?;
+        Ok(fs)
+    }
+
+    fn export_json(&self, with_content: bool) -> String {
+        let tree = self.node_json(1, with_content).unwrap_or(Value::Null);
+        serde_json::to_string_pretty(&tree).unwrap()
+    }
+
+    /// Applies the attributes of the JSON object `node` to `ino` and, for a
+    /// directory, creates its children.
+    fn import_node(
+        &mut self,
+        ino: INode,
+        path: &Path,
+        node: &Map<String, Value>,
+    ) -> io::Result<()> {
+        if let Some(perm) = node.get("perm") {
+            let perm = perm
+                .as_u64()
+                .filter(|&perm| perm <= 0o7777)
+                .ok_or_else(|| invalid(format!("{:?} has invalid permissions", path)))?;
+            self.attrs.get_mut(&ino).unwrap().perm = perm as u16;
+        }
+
+        let kind = self.attrs[&ino].kind;
+        if kind == FileType::RegularFile {
+            match (
+                node.get("content"),
+                node.get("size").and_then(Value::as_u64),
+            ) {
+                (Some(content), _) => {
+                    let data = content
+                        .as_str()
+                        .and_then(from_base64)
+                        .ok_or_else(|| invalid(format!("{:?} has invalid content", path)))?;
+                    self.write_file(ino, &data, 0)
+                        .map_err(|err| failed(path, err))?;
+                }
+                (None, Some(size)) => {
+                    let changes = SetAttr {
+                        size: Some(size),
+                        ..Default::default()
+                    };
+                    self.set_attr(ino, changes)
+                        .map_err(|err| failed(path, err))?;
+                }
+                (None, None) => {}
+            }
+        }
+        if kind != FileType::Directory {
+            return Ok(());
+        }
+
+        let children = match node.get("children") {
+            Some(Value::Array(children)) => children,
+            Some(_) => return Err(invalid(format!("children of {:?} aren't a list", path))),
+            None => return Ok(()),
+        };
+        for child in children {
+            let child = child
+                .as_object()
+                .ok_or_else(|| invalid(format!("an entry of {:?} isn't an object", path)))?;
+            let name = match child.get("name").and_then(Value::as_str) {
+                Some(name)
+                    if !name.is_empty() && name != "." && name != ".." && !name.contains('/') =>
+                {
+                    name
+                }
+                _ => return Err(invalid(format!("an entry of {:?} has no valid name", path))),
+            };
+            let child_path = path.join(name);
+            let kind = child
+                .get("kind")
+                .and_then(Value::as_str)
+                .and_then(kind_from_name)
+                .ok_or_else(|| invalid(format!("{:?} has an unknown kind", child_path)))?;
+
+            let created = match kind {
+                FileType::Symlink => {
+                    let target = child
+                        .get("target")
+                        .and_then(Value::as_str)
+                        .ok_or_else(|| invalid(format!("{:?} has no target", child_path)))?;
+                    self.create_symlink(ino, OsStr::new(name), Path::new(target), 0, 0)
+                        .map(|attrs| attrs.ino)
+                }
+                _ => self.insert_node(ino, OsStr::new(name), kind, 0, 0, 0, 0),
+            };
+            let child_ino = match created {
+                Ok(child_ino) => child_ino,
+                Err(Error::AlreadyExists) => {
+                    return Err(invalid(format!("duplicate name {:?}", child_path)))
+                }
+                Err(err) => return Err(failed(&child_path, err)),
+            };
+            self.import_node(child_ino, &child_path, child)?;
+        }
+        Ok(())
+    }
+
+    fn node_json(&self, ino: INode, with_content: bool) -> Option<Value> {
+        let node = self.nodes.get(&ino)?;
+        let attrs = self.attrs.get(&ino)?;
+        let name = match ino {
+            1 => "/".into(),
+            _ => node.name.to_string_lossy(),
+        };
+
+        let mut object = Map::new();
+        object.insert("name".into(), json!(name));
+        object.insert("ino".into(), json!(ino));
+        object.insert("kind".into(), json!(kind_name(attrs.kind)));
+        object.insert("size".into(), json!(attrs.size));
+        object.insert("perm".into(), json!(attrs.perm));
+        if let Some(target) = self.symlinks.get(&ino) {
+            object.insert("target".into(), json!(target.to_string_lossy()));
+        }
+        if with_content {
+            if let Some(file) = self.files.get(&ino) {
+                object.insert("content".into(), json!(base64(&file.to_vec())));
+            }
+        }
+        if attrs.kind == FileType::Directory {
+            let mut children: Vec<_> = node.children.iter().collect();
+            children.sort();
+            let children: Vec<Value> = children
+                .into_iter()
+                .filter_map(|(_, &child)| self.node_json(child, with_content))
+                .collect();
+            object.insert("children".into(), Value::Array(children));
+        }
+        Some(Value::Object(object))
+    }
+}
+
+fn kind_name(kind: FileType) -> &'static str {
+    match kind {
+        FileType::NamedPipe => "fifo",
+        FileType::CharDevice => "char_device",
+        FileType::BlockDevice => "block_device",
+        FileType::Directory => "directory",
+        FileType::RegularFile => "file",
+        FileType::Symlink => "symlink",
+        FileType::Socket => "socket",
+    }
+}
+
+fn kind_from_name(name: &str) -> Option<FileType> {
+    [
+        FileType::NamedPipe,
+        FileType::CharDevice,
+        FileType::BlockDevice,
+        FileType::Directory,
+        FileType::RegularFile,
+        FileType::Symlink,
+        FileType::Socket,
+    ]
+    .into_iter()
+    .find(|&kind| kind_name(kind) == name)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn failed(path: &Path, err: Error) -> io::Error {
+    invalid(format!("creating {:?} failed: {}", path, err))
+}
+
+/// Standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard base64 with padding.
+fn from_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut data = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let last = i == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let index = BASE64_ALPHABET.iter().position(|&a| a == c)?;
+            bits = bits << 6 | index as u32;
+        }
+        bits <<= 6 * padding;
+        data.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(data)
+}
@@ -1,75 +1,555 @@
+mod alloc;
+mod backing;
+mod checksum;
+mod clock;
+mod clone;
+#[cfg(test)]
+mod diff;
 mod error;
+mod events;
+mod file;
+mod handles;
+mod json;
+mod notify;
+mod persist;
+mod shadow;
+mod slab;
+mod snapshot;
+mod spill;
+mod storage;
+mod symlink;
+mod wal;
+mod xattr;
 
-use crate::nsfs;
-use crate::nsfs::error::Error;
+#[cfg(test)]
+pub(crate) use crate::nsfs::alloc::PathHashAllocator;
+pub(crate) use crate::nsfs::alloc::{InodeAllocator, MonotonicAllocator};
+#[cfg(test)]
+pub(crate) use crate::nsfs::backing::RecordingBacking;
+pub(crate) use crate::nsfs::backing::{Backing, DirBacking, NoBacking};
+#[cfg(test)]
+pub(crate) use crate::nsfs::clock::ManualClock;
+pub(crate) use crate::nsfs::clock::{Clock, SystemClock};
+#[cfg(test)]
+pub(crate) use crate::nsfs::diff::Difference;
+pub(crate) use crate::nsfs::error::Error;
+pub(crate) use crate::nsfs::events::FsEvent;
+pub(crate) use crate::nsfs::handles::{HANDLES_DIR, HANDLES_INO};
+pub(crate) use crate::nsfs::notify::InvalidationQueue;
+#[cfg(test)]
+pub(crate) use crate::nsfs::storage::RecordingStorage;
+pub(crate) use crate::nsfs::storage::Storage;
+pub(crate) use crate::nsfs::wal::WalRecord;
+pub(crate) use crate::nsfs::xattr::is_control;
 
-use fuser::{FileAttr, FileType};
-use std::collections::HashMap;
+use crate::nsfs::file::{File, SegmentIndex};
+use crate::nsfs::shadow::ShadowWorker;
+use crate::nsfs::slab::Slab;
+use crate::nsfs::snapshot::Snapshot;
+use crate::nsfs::wal::Wal;
+
+use fuser::{FileAttr, FileType, TimeOrNow};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::time::SystemTime;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
+#[derive(Clone)]
 pub(crate) struct Node {
     pub(crate) index: INode,
     pub(crate) parent: INode,
     pub(crate) name: OsString,
     pub(crate) kind: FileType,
-    pub(crate) children: HashMap<OsString, Node>,
+    pub(crate) children: HashMap<OsString, INode>,
+    /// How many directories lie between the root and this node, the root
+    /// itself being at depth 0.
+    depth: u32,
+    /// Stable `readdir` offsets of the children, in the order they were added.
+    cookies: HashMap<OsString, u64>,
+    next_cookie: u64,
+    /// The names of the children in order, if the filesystem keeps them so.
+    sorted: Option<BTreeSet<OsString>>,
 }
 
+/// `readdir` offsets of `.` and `..`; children are numbered after them.
+const CURRENT_DIR_COOKIE: u64 = 1;
+const PARENT_DIR_COOKIE: u64 = 2;
+
 impl Node {
-    pub(crate) fn new_directory(index: INode, parent: INode, name: &OsStr) -> Self {
+    fn new(index: INode, parent: INode, name: &OsStr, kind: FileType, depth: u32) -> Self {
         Self {
             index,
             parent,
             name: name.to_os_string(),
             children: Default::default(),
-            kind: FileType::Directory,
+            depth,
+            kind,
+            cookies: Default::default(),
+            next_cookie: PARENT_DIR_COOKIE + 1,
+            sorted: None,
         }
     }
 
-    fn new_file(index: INode, parent: INode, name: &OsStr) -> Self {
-        Self {
-            index,
-            parent,
-            name: name.to_os_string(),
-            children: Default::default(),
-            kind: FileType::RegularFile,
+    /// Adds or replaces the entry `name`. It gets a cookie greater than any
+    /// handed out before, so a listing in progress can't return it twice.
+    fn add_child(&mut self, name: &OsStr, ino: INode) {
+        self.children.insert(name.to_os_string(), ino);
+        self.cookies.insert(name.to_os_string(), self.next_cookie);
+        self.next_cookie += 1;
+        if let Some(sorted) = &mut self.sorted {
+            sorted.insert(name.to_os_string());
+        }
+    }
+
+    fn remove_child(&mut self, name: &OsStr) -> Option<INode> {
+        self.cookies.remove(name);
+        if let Some(sorted) = &mut self.sorted {
+            sorted.remove(name);
+        }
+        self.children.remove(name)
+    }
+
+    /// Names of the children starting with `prefix`, in order.
+    fn names_with_prefix(&self, prefix: &OsStr) -> Vec<OsString> {
+        let matches = |name: &OsString| {
+            name.as_encoded_bytes()
+                .starts_with(prefix.as_encoded_bytes())
+        };
+        match &self.sorted {
+            Some(sorted) => sorted
+                .range::<OsStr, _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|name| matches(name))
+                .cloned()
+                .collect(),
+            None => {
+                let mut names: Vec<OsString> = self
+                    .children
+                    .keys()
+                    .filter(|name| matches(name))
+                    .cloned()
+                    .collect();
+                names.sort();
+                names
+            }
+        }
+    }
+}
+
+/// When file content reaches the backing store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WritePolicy {
+    /// Every write is persisted before it's acknowledged.
+    WriteThrough,
+    /// Writes only mark the file dirty; dirty files are persisted on fsync or
+    /// once `WRITE_BACK_INTERVAL` has passed since the last sync.
+    WriteBack,
+}
+
+const WRITE_BACK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Setting `<REFLINK_XATTR_PREFIX><name>` on a directory to the inode of a
+/// file, in decimal, creates `name` in it as a reflink of that file.
+pub(crate) const REFLINK_XATTR_PREFIX: &str = "user.nsfs.reflink.";
+
+/// Default for `NsFS::flush_threshold`.
+const FLUSH_THRESHOLD: usize = 1 << 20;
+
+/// Default for `NsFS::spill_high_water`.
+const SPILL_HIGH_WATER: usize = 1 << 30;
+
+/// Default for `NsFS::compact_threshold`.
+const COMPACT_THRESHOLD: usize = 16;
+
+/// When reads update a file's access time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AtimePolicy {
+    /// Every read updates atime.
+    Strict,
+    /// Reads update atime only if it's not newer than mtime or ctime, or if
+    /// it's more than a day old.
+    Relatime,
+    /// Reads never update atime.
+    Noatime,
+}
+
+impl AtimePolicy {
+    fn should_update(&self, attrs: &FileAttr, now: SystemTime) -> bool {
+        match self {
+            AtimePolicy::Strict => true,
+            AtimePolicy::Noatime => false,
+            AtimePolicy::Relatime => {
+                attrs.atime <= attrs.mtime
+                    || attrs.atime <= attrs.ctime
+                    || now
+                        .duration_since(attrs.atime)
+                        .is_ok_and(|age| age > RELATIME_MAX_AGE)
+            }
+        }
+    }
+}
+
+const RELATIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How the content of a file is held in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Representation {
+    /// Fixed-size segments, with holes where nothing was written.
+    Segmented,
+    /// Moved to the spill directory until the file is used again.
+    Spilled,
+    /// Kept by `storage`, only the length is held in memory.
+    Stored,
+}
+
+/// Storage details of a single file, for debugging and tuning.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct StorageInfo {
+    pub(crate) representation: Representation,
+    /// Bytes actually allocated for the content, which may exceed its size.
+    pub(crate) resident_bytes: u64,
+    /// Number of separately allocated blocks or segments.
+    pub(crate) segments: usize,
+}
+
+/// Attributes as reported by `statx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Statx {
+    pub(crate) ino: INode,
+    pub(crate) kind: FileType,
+    pub(crate) perm: u16,
+    /// For a directory, its entry in the parent, its own `.` and the `..` of
+    /// each subdirectory.
+    pub(crate) nlink: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) rdev: u32,
+    pub(crate) size: u64,
+    /// Number of 512-byte blocks.
+    pub(crate) blocks: u64,
+    pub(crate) blksize: u32,
+    pub(crate) atime: SystemTime,
+    pub(crate) mtime: SystemTime,
+    pub(crate) ctime: SystemTime,
+    /// When the file was created.
+    pub(crate) btime: SystemTime,
+    pub(crate) flags: u32,
+}
+
+impl From<Statx> for FileAttr {
+    fn from(statx: Statx) -> Self {
+        FileAttr {
+            ino: statx.ino,
+            size: statx.size,
+            blocks: statx.blocks,
+            atime: statx.atime,
+            mtime: statx.mtime,
+            ctime: statx.ctime,
+            crtime: statx.btime,
+            kind: statx.kind,
+            perm: statx.perm,
+            nlink: statx.nlink,
+            uid: statx.uid,
+            gid: statx.gid,
+            rdev: statx.rdev,
+            blksize: statx.blksize,
+            flags: statx.flags,
         }
     }
 }
 
-struct File {
+/// A write accepted in write-back mode but not yet merged into the file.
+struct PendingWrite {
+    offset: usize,
     data: Vec<u8>,
 }
 
-impl File {
-    fn new() -> Self {
-        Self { data: Vec::new() }
+pub(crate) struct OpenFile {
+    pub(crate) ino: INode,
+    /// Set when the file was opened with `O_DIRECT`, which bypasses the page
+    /// cache so read results are handed to the caller as is.
+    pub(crate) direct_io: bool,
+    pub(crate) flags: i32,
+    /// Where the last read or write through this handle ended.
+    pub(crate) offset: u64,
+    /// Set to cancel the next read or write through this handle.
+    interrupted: Arc<AtomicBool>,
+    /// The kernel released the handle while its buffered writes couldn't be
+    /// flushed. It's kept until they are, see `flush_released`.
+    released: bool,
+}
+
+/// Snapshot of how much memory the filesystem is holding on to.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct MemoryStats {
+    pub(crate) file_bytes: u64,
+    pub(crate) inodes: usize,
+    pub(crate) open_files: usize,
+    pub(crate) dir_entries: usize,
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "file bytes: {}, inodes: {}, open files: {}, directory entries: {}",
+            self.file_bytes, self.inodes, self.open_files, self.dir_entries
+        )
+    }
+}
+
+/// `_IOR('N', 1, struct nsfs_stats)`: reads `NsFS::packed_stats` from any
+/// file of a mount.
+pub(crate) const STATS_IOCTL: u32 =
+    (2 << 30) | ((STATS_SIZE as u32) << 16) | ((b'N' as u32) << 8) | 1;
+
+/// Size of `struct nsfs_stats`, six little-endian `u64`s: file bytes, inodes,
+/// open files, directory entries, dedup savings and operations handled.
+pub(crate) const STATS_SIZE: usize = 6 * 8;
+
+/// Attribute changes requested by `setattr`; `None` leaves a field untouched.
+#[derive(Default)]
+pub(crate) struct SetAttr {
+    pub(crate) mode: Option<u32>,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) size: Option<u64>,
+    pub(crate) atime: Option<TimeOrNow>,
+    pub(crate) mtime: Option<TimeOrNow>,
+    pub(crate) crtime: Option<SystemTime>,
+}
+
+impl SetAttr {
+    fn is_empty(&self) -> bool {
+        self.mode.is_none()
+            && self.uid.is_none()
+            && self.gid.is_none()
+            && self.size.is_none()
+            && self.atime.is_none()
+            && self.mtime.is_none()
+            && self.crtime.is_none()
+    }
+}
+
+/// Like tmpfs, a write that grows a file by `growth` bytes writes as much
+/// of `data` as fits in `free` and fails only if nothing does.
+fn fit_in(data: &[u8], growth: u64, free: Option<u64>) -> Result<&[u8], Error> {
+    match free {
+        Some(free) if growth > free => {
+            let fits = data.len().saturating_sub((growth - free) as usize);
+            if fits == 0 {
+                return Err(Error::NoSpace);
+            }
+            Ok(&data[..fits])
+        }
+        _ => Ok(data),
+    }
+}
+
+/// Moves the usage of `uid` from a file of `old` bytes to one of `new` bytes.
+fn charge(usage: &mut HashMap<u32, u64>, uid: u32, old: u64, new: u64) {
+    let used = usage.entry(uid).or_default();
+    *used = (*used + new).saturating_sub(old);
+}
+
+/// Unit of `FileAttr::blocks`, whatever the block size.
+const SECTOR_SIZE: u64 = 512;
+
+/// Block size of a new filesystem.
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
+
+/// Open flag the kernel passes on when a file is opened to be executed.
+const FMODE_EXEC: i32 = 0x20;
+
+fn is_full(dir: &Node, max_entries: Option<usize>) -> bool {
+    max_entries.is_some_and(|max| dir.children.len() >= max)
+}
+
+/// The `FileAttr::blocks` taken by `size` bytes stored in whole blocks of
+/// `block_size` bytes.
+fn blocks_for(size: u64, block_size: u32) -> u64 {
+    size.div_ceil(block_size as u64) * (block_size as u64 / SECTOR_SIZE)
+}
+
+/// Bytes a directory entry adds to the size of its directory, so a directory
+/// reports `entries * DIR_ENTRY_SIZE` without counting `.` and `..`.
+const DIR_ENTRY_SIZE: u64 = 32;
+
+fn dir_size(node: &Node) -> u64 {
+    node.children.len() as u64 * DIR_ENTRY_SIZE
+}
+
+/// Number of bytes a read of `size` at `offset` gets out of `file`.
+fn clamp_read(file: &File, size: usize, offset: usize) -> Result<usize, Error> {
+    if offset == 0 && file.is_empty() {
+        return Ok(0);
+    }
+
+    if offset >= file.len() {
+        return Err(Error::Eof);
     }
+
+    if offset + size >= file.len() {
+        return Ok(file.len() - offset); // TODO: а может и не нужно??
+    }
+
+    Ok(size)
 }
 
+static CURRENT_DIR: &str = ".";
+static PARENT_DIR: &str = "..";
+
 type FileDescriptor = u64;
 type INode = u64;
-pub(crate) struct NsFS {
+/// An in-memory filesystem, served by `mount`.
+pub struct NsFS {
     pub(crate) attrs: HashMap<INode, FileAttr>,
     pub(crate) nodes: HashMap<INode, Node>,
-    pub(crate) open_files: HashMap<FileDescriptor, INode>,
+    /// Open file handles; a handle is the index of its slot, which is reused
+    /// once the handle is released.
+    pub(crate) open_files: Slab<OpenFile>,
     files: HashMap<INode, File>,
-    current_inode: u64,
-    current_file_descriptor: FileDescriptor,
+    lookup_counts: HashMap<INode, u64>,
+    /// Bumped whenever an inode number is reused, so handles to the node
+    /// that had it before (e.g. over NFS) don't resolve to the new one.
+    generations: HashMap<INode, u64>,
+    xattrs: HashMap<INode, HashMap<OsString, Vec<u8>>>,
+    /// Snapshots taken through `SNAPSHOT_XATTR`, by name.
+    snapshots: HashMap<OsString, Snapshot>,
+    /// Where each symlink points to.
+    symlinks: HashMap<INode, PathBuf>,
+    /// Upper bound for the number of bytes accepted by a single write.
+    pub(crate) max_write: Option<usize>,
+    /// Upper bound for the total size of all files.
+    pub(crate) capacity_bytes: Option<u64>,
+    /// Upper bound for the number of entries in a single directory.
+    pub(crate) max_entries_per_dir: Option<usize>,
+    /// Upper bound for the depth of a new directory, the root being at 0.
+    pub(crate) max_depth: Option<u32>,
+    /// What content is allocated in, as far as `statfs` and the `blocks` of
+    /// the attributes tell; also reported as the preferred I/O size.
+    block_size: u32,
+    /// Every directory keeps the names of its children sorted, for
+    /// `find_by_prefix`.
+    sorted_index: bool,
+    /// The volume the filesystem is. Hard links and renames only work
+    /// within a volume.
+    pub(crate) device_id: u64,
+    /// Directories holding another volume than their parent, with its id.
+    volumes: HashMap<INode, u64>,
+    /// Upper bound for the size of a single file.
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) atime_policy: AtimePolicy,
+    pub(crate) clock: Box<dyn Clock>,
+    /// How long the kernel may cache entries and attributes.
+    pub(crate) ttl: Duration,
+    /// How long the kernel may cache that a name doesn't exist. Failed
+    /// lookups are only answered with an error if not set.
+    pub(crate) negative_ttl: Option<Duration>,
+    /// Names answered as missing, which the kernel may still have cached.
+    negative_entries: HashSet<(INode, OsString)>,
+    /// Write-back mode: writes through a handle are kept in a per-handle
+    /// buffer and only reach the file on flush, fsync or release.
+    pub(crate) buffered: bool,
+    /// Rejects a write overlapping one that was accepted but hasn't reached
+    /// the file yet, rather than letting the later flush decide the result.
+    pub(crate) strict_writes: bool,
+    /// A handle's buffer is flushed once it holds more bytes than this.
+    pub(crate) flush_threshold: usize,
+    pending_writes: HashMap<FileDescriptor, Vec<PendingWrite>>,
+    /// Buffered writes that reached a file by being flushed.
+    coalesced_writes: u64,
+    segment_index: SegmentIndex,
+    /// A file is compacted when its last handle is closed if at least this
+    /// many of its segments could be dropped.
+    pub(crate) compact_threshold: usize,
+    /// Inodes read through `read_file_shared` and when.
+    accessed: Mutex<HashMap<INode, SystemTime>>,
+    /// Holds the result of a read spanning several segments.
+    read_buf: Vec<u8>,
+    pub(crate) write_policy: WritePolicy,
+    pub(crate) backing: Box<dyn Backing>,
+    /// Where inodes whose attributes changed through a write or `setattr` are
+    /// queued for the kernel to drop its cached attributes, if anyone listens.
+    pub(crate) invalidations: Option<InvalidationQueue>,
+    /// Keeps file content instead of memory, if set.
+    pub(crate) storage: Option<Box<dyn Storage>>,
+    /// A directory the content of the least recently used files is moved to
+    /// once file content takes more than `spill_high_water` bytes of memory.
+    /// It's moved back when the file is used again.
+    pub(crate) spill_path: Option<PathBuf>,
+    pub(crate) spill_high_water: usize,
+    /// When each file was last used, in ticks of `lru_tick`.
+    last_used: HashMap<INode, u64>,
+    lru_tick: u64,
+    /// Files whose content is in the spill directory.
+    spilled: HashSet<INode>,
+    dirty: HashSet<INode>,
+    last_sync: Option<SystemTime>,
+    /// Where the whole filesystem is saved to when it's unmounted.
+    pub(crate) state_file: Option<PathBuf>,
+    /// A host directory that created and written files and removals are
+    /// mirrored to by path, in the background. Failing to mirror something
+    /// is only logged.
+    pub(crate) shadow_root: Option<PathBuf>,
+    shadow_worker: Option<ShadowWorker>,
+    /// Where changes are reported to, see `subscribe`.
+    subscribers: Vec<Sender<FsEvent>>,
+    /// Where changes made since the last save of `state_file` are logged.
+    wal: Option<Wal>,
+    /// Counts the saves of `state_file`, so a log can be matched with the
+    /// snapshot it continues.
+    checkpoint_id: u64,
+    /// Exposes open file handles in a synthetic `/.handles` directory.
+    pub(crate) debug_handles: bool,
+    /// Logs every operation with the path it resolved to.
+    pub(crate) trace: bool,
+    /// Permissions of new files and directories whose creator didn't ask for
+    /// specific ones.
+    pub(crate) default_file_mode: u32,
+    pub(crate) default_dir_mode: u32,
+    /// The kernel checks permissions (`-o default_permissions`), so they
+    /// aren't checked again here.
+    pub(crate) default_permissions: bool,
+    /// How many times each FUSE operation was called.
+    ops: HashMap<&'static str, u64>,
+    /// Upper bounds for the bytes owned by a uid.
+    quotas: HashMap<u32, u64>,
+    /// Bytes of file content owned by each uid.
+    usage_by_uid: HashMap<u32, u64>,
+    /// How many handles have each inode open for execution.
+    exec_opens: HashMap<INode, u32>,
+    allocator: Box<dyn InodeAllocator>,
+}
+
+impl Default for NsFS {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NsFS {
-    pub(crate) fn new() -> Self {
-        let root = Node {
-            index: 1,
-            parent: 0,
-            name: OsString::from("/"),
-            children: Default::default(),
-            kind: FileType::Directory,
-        };
+    pub fn new() -> Self {
+        Self::with_allocator(Box::new(MonotonicAllocator::after(1)))
+    }
+
+    /// An empty filesystem whose inodes only depend on the paths of the
+    /// nodes, for reproducible tests.
+    #[cfg(test)]
+    pub(crate) fn new_deterministic() -> Self {
+        Self::with_allocator(Box::new(PathHashAllocator::default()))
+    }
 
-        let now = SystemTime::now();
+    /// An empty filesystem taking inode numbers for new nodes from `allocator`.
+    pub(crate) fn with_allocator(allocator: Box<dyn InodeAllocator>) -> Self {
+        let root = Node::new(1, 0, OsStr::new("/"), FileType::Directory, 0);
+
+        let clock: Box<dyn Clock> = Box::new(SystemClock);
+        let now = clock.now();
         let mut attrs: HashMap<u64, FileAttr> = Default::default();
         attrs.insert(
             1,
@@ -82,12 +562,13 @@ impl NsFS {
                 ctime: now,
                 crtime: now,
                 kind: FileType::Directory,
-                perm: 0,
-                nlink: 0,
-                uid: 0,
-                gid: 0,
+                perm: 0o755,
+                nlink: 2,
+                // the root belongs to whoever mounts the filesystem
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
                 rdev: 0,
-                blksize: 0,
+                blksize: DEFAULT_BLOCK_SIZE,
                 flags: 0,
             },
         );
@@ -98,37 +579,423 @@ impl NsFS {
         Self {
             attrs,
             nodes,
-            current_inode: 1, // 1 is root TODO: add root to attrs
+            allocator,
             open_files: Default::default(),
             files: Default::default(),
-            current_file_descriptor: 0,
+            lookup_counts: Default::default(),
+            generations: Default::default(),
+            xattrs: Default::default(),
+            snapshots: HashMap::new(),
+            symlinks: Default::default(),
+            max_write: None,
+            max_file_size: None,
+            max_entries_per_dir: None,
+            max_depth: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            sorted_index: false,
+            device_id: 0,
+            volumes: Default::default(),
+            capacity_bytes: None,
+            atime_policy: AtimePolicy::Relatime,
+            clock,
+            ttl: Duration::from_secs(1),
+            negative_ttl: None,
+            negative_entries: HashSet::new(),
+            buffered: false,
+            strict_writes: false,
+            flush_threshold: FLUSH_THRESHOLD,
+            pending_writes: Default::default(),
+            coalesced_writes: 0,
+            segment_index: Default::default(),
+            compact_threshold: COMPACT_THRESHOLD,
+            accessed: Default::default(),
+            read_buf: Vec::new(),
+            write_policy: WritePolicy::WriteThrough,
+            backing: Box::new(NoBacking),
+            invalidations: None,
+            storage: None,
+            spill_path: None,
+            spill_high_water: SPILL_HIGH_WATER,
+            last_used: Default::default(),
+            lru_tick: 0,
+            spilled: Default::default(),
+            dirty: Default::default(),
+            last_sync: None,
+            state_file: None,
+            shadow_root: None,
+            shadow_worker: None,
+            subscribers: Vec::new(),
+            wal: None,
+            checkpoint_id: 0,
+            debug_handles: false,
+            trace: false,
+            default_file_mode: 0o777,
+            default_dir_mode: 0o777,
+            default_permissions: false,
+            ops: HashMap::new(),
+            quotas: HashMap::new(),
+            usage_by_uid: HashMap::new(),
+            exec_opens: HashMap::new(),
         }
     }
 
-    pub(crate) fn next_inode(&mut self) -> u64 {
-        self.current_inode += 1;
-        self.current_inode
+    pub(crate) fn next_inode(&mut self, path: &Path) -> u64 {
+        self.allocator.next_for(path)
     }
 
-    pub(crate) fn open_file(&mut self, ino: INode) -> FileDescriptor {
-        let fd = self.current_file_descriptor;
-        self.current_file_descriptor += 1;
-        self.open_files.insert(fd, ino);
-        fd
+    /// Opens `ino` as the `open` request does. A directory opened with
+    /// `O_TMPFILE` is refused: kernels that support it on FUSE send a
+    /// separate `TMPFILE` request instead, which fuser 0.12 doesn't know and
+    /// answers with `ENOSYS`, and an open reply can't carry a new inode. So
+    /// the flag only shows up here from a kernel that ignored it, and opening
+    /// the directory would be wrong.
+    pub(crate) fn open_inode(&mut self, ino: INode, flags: i32) -> Result<FileDescriptor, Error> {
+        if flags & libc::O_TMPFILE == libc::O_TMPFILE {
+            return Err(Error::NotSupported);
+        }
+        Ok(self.open_file(ino, flags))
     }
 
-    pub(crate) fn find_node(&self, parent: INode, name: &OsStr) -> Result<&Node, Error> {
-        let parent = match self.nodes.get(&parent) {
+    pub(crate) fn open_file(&mut self, ino: INode, flags: i32) -> FileDescriptor {
+        if flags & FMODE_EXEC != 0 {
+            *self.exec_opens.entry(ino).or_default() += 1;
+        }
+        self.open_files.insert(OpenFile {
+            ino,
+            direct_io: flags & libc::O_DIRECT != 0,
+            flags,
+            offset: 0,
+            interrupted: Default::default(),
+            released: false,
+        })
+    }
+
+    /// The generation of `ino`, which together with it identifies a node for
+    /// the lifetime of the filesystem.
+    pub(crate) fn generation(&self, ino: INode) -> u64 {
+        self.generations.get(&ino).copied().unwrap_or_default()
+    }
+
+    /// Counts a call of the FUSE operation `op`.
+    pub(crate) fn count_op(&mut self, op: &'static str) {
+        *self.ops.entry(op).or_default() += 1;
+    }
+
+    /// Calls per FUSE operation and other counters, sorted by name. Counters
+    /// that are still zero are left out.
+    pub(crate) fn metrics(&self) -> Vec<(&'static str, u64)> {
+        let mut ops: Vec<_> = self.ops.iter().map(|(&op, &count)| (op, count)).collect();
+        if self.coalesced_writes > 0 {
+            ops.push(("coalesced_writes", self.coalesced_writes));
+        }
+        ops.sort_unstable();
+        ops
+    }
+
+    /// Memory stats, dedup savings and the number of operations handled,
+    /// laid out as `struct nsfs_stats`.
+    pub(crate) fn packed_stats(&self) -> [u8; STATS_SIZE] {
+        let stats = self.memory_stats();
+        let ops: u64 = self.ops.values().sum();
+        let fields = [
+            stats.file_bytes,
+            stats.inodes as u64,
+            stats.open_files as u64,
+            stats.dir_entries as u64,
+            self.dedup_savings(),
+            ops,
+        ];
+
+        let mut packed = [0; STATS_SIZE];
+        for (chunk, field) in packed.chunks_exact_mut(8).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        packed
+    }
+
+    pub(crate) fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            file_bytes: self.used_bytes(),
+            inodes: self.attrs.len(),
+            open_files: self.open_files.len(),
+            dir_entries: self.nodes.values().map(|n| n.children.len()).sum(),
+        }
+    }
+
+    /// Bytes taken by file content, the sum of all file sizes.
+    pub(crate) fn used_bytes(&self) -> u64 {
+        self.files.values().map(|f| f.len() as u64).sum()
+    }
+
+    /// Limits the file content owned by `uid` to `bytes`.
+    pub(crate) fn set_quota(&mut self, uid: u32, bytes: u64) {
+        self.quotas.insert(uid, bytes);
+    }
+
+    /// Bytes of file content owned by `uid`.
+    pub(crate) fn usage(&self, uid: u32) -> u64 {
+        self.usage_by_uid.get(&uid).copied().unwrap_or_default()
+    }
+
+    /// Recomputes the usage of every uid from the files.
+    pub(crate) fn recount_usage(&mut self) {
+        self.usage_by_uid.clear();
+        for (ino, file) in &self.files {
+            if let Some(attrs) = self.attrs.get(ino) {
+                charge(&mut self.usage_by_uid, attrs.uid, 0, file.len() as u64);
+            }
+        }
+    }
+
+    /// Fails if `uid` growing its files by `growth` bytes, on top of what its
+    /// buffered writes reserved, would exceed its quota.
+    fn check_quota(&self, uid: u32, growth: u64) -> Result<(), Error> {
+        match self.quotas.get(&uid) {
+            Some(&quota) if self.usage(uid) + self.buffered_growth(Some(uid)) + growth > quota => {
+                Err(Error::QuotaExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Bytes left before reaching `capacity_bytes`, if there is a capacity.
+    /// What buffered writes will take once flushed is already taken.
+    pub(crate) fn free_bytes(&self) -> Option<u64> {
+        self.capacity_bytes
+            .map(|capacity| capacity.saturating_sub(self.used_bytes() + self.buffered_growth(None)))
+    }
+
+    /// Total and free blocks as reported by `statfs`. Without a capacity the
+    /// filesystem is as large as what it holds.
+    pub(crate) fn statfs_blocks(&self) -> (u64, u64) {
+        let block_size = self.block_size as u64;
+        match self.capacity_bytes {
+            Some(capacity) => (
+                capacity / block_size,
+                self.free_bytes().unwrap_or_default() / block_size,
+            ),
+            None => (self.used_bytes().div_ceil(block_size), 0),
+        }
+    }
+
+    pub(crate) fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Changes the block size, a power of two of at least 512 bytes, and the
+    /// attributes that depend on it.
+    pub(crate) fn set_block_size(&mut self, block_size: u32) -> Result<(), Error> {
+        if block_size < SECTOR_SIZE as u32 || !block_size.is_power_of_two() {
+            return Err(Error::InvalidArgument);
+        }
+        self.block_size = block_size;
+        for attrs in self.attrs.values_mut() {
+            attrs.blocks = blocks_for(attrs.size, block_size);
+            attrs.blksize = block_size;
+        }
+        Ok(())
+    }
+
+    /// Bytes not allocated because files share segments with the same content.
+    pub(crate) fn dedup_savings(&self) -> u64 {
+        let mut refs: HashMap<*const Vec<u8>, (u64, u64)> = HashMap::new();
+        for segment in self.files.values().flat_map(File::segments) {
+            let entry = refs
+                .entry(Arc::as_ptr(segment))
+                .or_insert((0, segment.len() as u64));
+            entry.0 += 1;
+        }
+        refs.values().map(|(count, len)| (count - 1) * len).sum()
+    }
+
+    /// Number of entries of the index segments are shared through.
+    #[cfg(test)]
+    pub(crate) fn indexed_segments(&self) -> usize {
+        self.segment_index.len()
+    }
+
+    pub(crate) fn storage_info(&self, ino: INode) -> Result<StorageInfo, Error> {
+        let file = match self.files.get(&ino) {
+            Some(file) => file,
+            None => return Err(Error::FileNotFound),
+        };
+
+        let representation = if self.spilled.contains(&ino) {
+            Representation::Spilled
+        } else if self.storage.is_some() {
+            Representation::Stored
+        } else {
+            Representation::Segmented
+        };
+        Ok(StorageInfo {
+            representation,
+            resident_bytes: file.resident_bytes() as u64,
+            segments: file.segment_count(),
+        })
+    }
+
+    /// Lists the entries of a directory, including `.` and `..`.
+    #[cfg(test)]
+    pub(crate) fn list_dir(&self, ino: INode) -> Result<Vec<(INode, FileType, OsString)>, Error> {
+        let entries = self.read_dir(ino, 0)?;
+        Ok(entries
+            .into_iter()
+            .map(|(_, ino, kind, name)| (ino, kind, name))
+            .collect())
+    }
+
+    /// The entries of directory `ino` whose cookie is greater than `offset`,
+    /// ordered by cookie. An entry keeps its cookie while it exists, so
+    /// listing can resume from the cookie of the last entry seen even if the
+    /// directory changed in between.
+    pub(crate) fn read_dir(
+        &self,
+        ino: INode,
+        offset: u64,
+    ) -> Result<Vec<(u64, INode, FileType, OsString)>, Error> {
+        if self.debug_handles && ino == HANDLES_INO {
+            let mut entries = vec![
+                (ino, FileType::Directory, OsString::from(CURRENT_DIR)),
+                (1, FileType::Directory, OsString::from(PARENT_DIR)),
+            ];
+            for (ino, name) in self.handle_entries() {
+                entries.push((ino, FileType::RegularFile, name));
+            }
+            return Ok(entries
+                .into_iter()
+                .zip(1..)
+                .map(|((ino, kind, name), cookie)| (cookie, ino, kind, name))
+                .filter(|(cookie, ..)| *cookie > offset)
+                .collect());
+        }
+
+        let node = match self.nodes.get(&ino) {
             Some(node) => node,
             None => return Err(Error::NotFound),
         };
+        if node.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let mut entries = vec![(
+            CURRENT_DIR_COOKIE,
+            ino,
+            FileType::Directory,
+            OsString::from(CURRENT_DIR),
+        )];
+        if node.parent != 0 {
+            entries.push((
+                PARENT_DIR_COOKIE,
+                node.parent,
+                FileType::Directory,
+                OsString::from(PARENT_DIR),
+            ));
+        }
+        for (name, child) in node.children.iter() {
+            if let (Some(child), Some(&cookie)) = (self.nodes.get(child), node.cookies.get(name)) {
+                entries.push((cookie, child.index, child.kind, name.clone()));
+            }
+        }
+        if self.debug_handles && ino == 1 {
+            entries.push((
+                i64::MAX as u64,
+                HANDLES_INO,
+                FileType::Directory,
+                OsString::from(HANDLES_DIR),
+            ));
+        }
+
+        entries.retain(|(cookie, ..)| *cookie > offset);
+        entries.sort_unstable_by_key(|(cookie, ..)| *cookie);
+        Ok(entries)
+    }
+
+    /// Makes every directory keep the names of its children sorted, so
+    /// `find_by_prefix` doesn't have to look at all of them, or stops it.
+    pub(crate) fn set_sorted_index(&mut self, enabled: bool) {
+        self.sorted_index = enabled;
+        for node in self.nodes.values_mut() {
+            node.sorted = match enabled && node.kind == FileType::Directory {
+                true => Some(node.children.keys().cloned().collect()),
+                false => None,
+            };
+        }
+    }
+
+    /// The names in directory `dir` starting with `prefix`, sorted. With
+    /// the sorted index that takes O(log n + k) for k matches of n entries,
+    /// otherwise every entry is looked at.
+    pub(crate) fn find_by_prefix(
+        &self,
+        dir: INode,
+        prefix: &OsStr,
+    ) -> Result<Vec<OsString>, Error> {
+        match self.nodes.get(&dir) {
+            Some(node) if node.kind == FileType::Directory => Ok(node.names_with_prefix(prefix)),
+            Some(_) => Err(Error::NotADirectory),
+            None => Err(Error::NotFound),
+        }
+    }
 
-        let node = match parent.children.get(name) {
+    pub(crate) fn find_node(&self, parent: INode, name: &OsStr) -> Result<&Node, Error> {
+        let parent = match self.nodes.get(&parent) {
             Some(node) => node,
             None => return Err(Error::NotFound),
         };
+        if parent.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        // the root is its own parent
+        if name == CURRENT_DIR || (name == PARENT_DIR && parent.index == 1) {
+            return Ok(parent);
+        }
+        if name == PARENT_DIR {
+            return self.nodes.get(&parent.parent).ok_or(Error::NotFound);
+        }
+
+        match parent
+            .children
+            .get(name)
+            .and_then(|ino| self.nodes.get(ino))
+        {
+            Some(node) => Ok(node),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Every inode with its type and size, ordered by inode.
+    pub(crate) fn list_inodes(&self) -> Vec<(INode, FileType, u64)> {
+        let mut inodes: Vec<_> = self
+            .attrs
+            .values()
+            .map(|attrs| (attrs.ino, attrs.kind, attrs.size))
+            .collect();
+        inodes.sort_unstable_by_key(|(ino, ..)| *ino);
+        inodes
+    }
+
+    /// Builds the absolute path of `ino` by following parent links up to the
+    /// root. Returns `None` for inodes that aren't reachable from the root.
+    pub(crate) fn path_of(&self, ino: INode) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut current = ino;
+
+        // every step moves one level up, so a walk longer than the number of
+        // nodes can only mean a cycle
+        for _ in 0..=self.nodes.len() {
+            if current == 1 {
+                let mut path = PathBuf::from("/");
+                path.extend(names.iter().rev());
+                return Some(path);
+            }
 
-        return Ok(node);
+            let node = self.nodes.get(&current)?;
+            names.push(&node.name);
+            current = node.parent;
+        }
+
+        None
     }
 
     pub(crate) fn get_attr(&self, ino: INode) -> Result<&FileAttr, Error> {
@@ -138,43 +1005,191 @@ impl NsFS {
         }
     }
 
-    pub(crate) fn read_file(
-        &mut self,
-        ino: INode,
-        size: usize,
-        offset: usize,
-    ) -> Result<&[u8], Error> {
-        let file = match self.files.get(&ino) {
-            Some(file) => file,
-            None => return Err(Error::FileNotFound),
+    /// All attributes of `ino`, with the link count of a directory counted
+    /// from its subdirectories.
+    pub(crate) fn statx(&self, ino: INode) -> Result<Statx, Error> {
+        let attrs = self.get_attr(ino)?;
+        let nlink = match self.nodes.get(&ino) {
+            Some(node) if node.kind == FileType::Directory => {
+                let subdirs = node
+                    .children
+                    .values()
+                    .filter(|child| {
+                        self.nodes
+                            .get(child)
+                            .is_some_and(|child| child.kind == FileType::Directory)
+                    })
+                    .count();
+                2 + subdirs as u32
+            }
+            _ => attrs.nlink,
+        };
+        Ok(Statx {
+            ino,
+            kind: attrs.kind,
+            perm: attrs.perm,
+            nlink,
+            uid: attrs.uid,
+            gid: attrs.gid,
+            rdev: attrs.rdev,
+            size: attrs.size,
+            blocks: blocks_for(attrs.size, self.block_size),
+            blksize: self.block_size,
+            atime: attrs.atime,
+            mtime: attrs.mtime,
+            ctime: attrs.ctime,
+            btime: attrs.crtime,
+            flags: attrs.flags,
+        })
+    }
+
+    pub(crate) fn set_attr(&mut self, ino: INode, changes: SetAttr) -> Result<&FileAttr, Error> {
+        // a pending read mustn't override the times set here
+        self.apply_access_times();
+        // ctime only changes along with something else, it can't be set
+        if changes.is_empty() {
+            return self.attrs.get(&ino).ok_or(Error::AttrsNotFound);
+        }
+        if changes.size.is_some() {
+            self.fault_in(ino)?;
+        }
+        let len = self.files.get(&ino).map_or(0, File::len) as u64;
+        if let Some(size) = changes.size {
+            self.check_file_size(size)?;
+            if size > len && self.free_bytes().is_some_and(|free| size - len > free) {
+                return Err(Error::NoSpace);
+            }
+        }
+
+        let owner = match self.attrs.get(&ino) {
+            Some(attrs) => attrs.uid,
+            None => return Err(Error::AttrsNotFound),
         };
+        let new_owner = changes.uid.unwrap_or(owner);
+        let new_len = match self.files.contains_key(&ino) {
+            true => changes.size.unwrap_or(len),
+            false => 0,
+        };
+        // a new owner takes over the whole file
+        if new_owner == owner {
+            self.check_quota(owner, new_len.saturating_sub(len))?;
+        } else {
+            self.check_quota(new_owner, new_len)?;
+        }
+        if let (Some(size), Some(storage)) = (changes.size, &mut self.storage) {
+            if self.files.contains_key(&ino) {
+                storage.truncate(ino, size as usize)?;
+            }
+        }
+        charge(&mut self.usage_by_uid, owner, len, 0);
+        charge(&mut self.usage_by_uid, new_owner, 0, new_len);
 
         let attrs = match self.attrs.get_mut(&ino) {
             Some(attrs) => attrs,
             None => return Err(Error::AttrsNotFound),
         };
-        attrs.atime = SystemTime::now();
 
-        let mut size = size as usize;
-        let offset = offset as usize;
+        if let Some(mode) = changes.mode {
+            attrs.perm = (mode & 0o7777) as u16;
+        }
+        if let Some(uid) = changes.uid {
+            attrs.uid = uid;
+        }
+        if let Some(gid) = changes.gid {
+            attrs.gid = gid;
+        }
+        if let Some(size) = changes.size {
+            if let Some(file) = self.files.get_mut(&ino) {
+                file.resize(size as usize);
+            }
+            attrs.size = size;
+            attrs.blocks = blocks_for(size, self.block_size);
+        }
+        if let Some(crtime) = changes.crtime {
+            attrs.crtime = crtime;
+        }
+        if let Some(invalidations) = &self.invalidations {
+            invalidations.push(ino);
+        }
+        if changes.mode.is_some()
+            || changes.uid.is_some()
+            || changes.gid.is_some()
+            || changes.size.is_some()
+        {
+            self.log_attrs(ino);
+        }
+        if let Some(size) = changes.size {
+            self.shadow_truncate(ino, size);
+            self.emit_modified(ino);
+        }
+
+        self.touch(ino, changes.atime, changes.mtime)
+    }
+
+    /// Sets the access and modification times like `utimensat`. Specific
+    /// times are kept as given, down to the nanosecond.
+    pub(crate) fn touch(
+        &mut self,
+        ino: INode,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<&FileAttr, Error> {
+        self.apply_access_times();
+        let attrs = match self.attrs.get_mut(&ino) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
 
-        if offset >= file.data.len() {
-            return Err(Error::EOF);
+        // one timestamp for the whole call, so times set together are equal
+        let now = self.clock.now();
+        let resolve = |time| match time {
+            TimeOrNow::Now => now,
+            TimeOrNow::SpecificTime(time) => time,
+        };
+        attrs.ctime = now;
+        if let Some(atime) = atime {
+            attrs.atime = resolve(atime);
         }
+        if let Some(mtime) = mtime {
+            attrs.mtime = resolve(mtime);
+        }
+        Ok(attrs)
+    }
 
-        if offset + size >= file.data.len() {
-            size = file.data.len() - offset; // TODO: а может и не нужно??
+    /// Maps block `idx` of a file to a block of the device. The content is
+    /// treated as laid out in contiguous blocks of `blocksize` bytes, so a
+    /// block maps to itself as long as it's within the file.
+    pub(crate) fn bmap(&self, ino: INode, blocksize: u32, idx: u64) -> Result<u64, Error> {
+        let file = match self.files.get(&ino) {
+            Some(file) => file,
+            None => return Err(Error::FileNotFound),
+        };
+        if blocksize == 0 {
+            return Err(Error::InvalidArgument);
         }
 
-        Ok(&file.data[offset..offset + size])
+        let blocks = (file.len() as u64).div_ceil(blocksize as u64);
+        if idx >= blocks {
+            return Err(Error::NoSuchAddress);
+        }
+        Ok(idx)
     }
 
-    pub(crate) fn write_file(
+    /// Reserves `length` bytes from `offset`, growing the file with zeroes
+    /// unless `FALLOC_FL_KEEP_SIZE` is set. Other modes aren't supported.
+    pub(crate) fn allocate(
         &mut self,
         ino: INode,
-        data: &[u8],
-        offset: usize,
-    ) -> Result<usize, Error> {
+        offset: u64,
+        length: u64,
+        mode: i32,
+    ) -> Result<(), Error> {
+        self.fault_in(ino)?;
+        let free = self.free_bytes();
+        let reserved = match self.attrs.get(&ino) {
+            Some(attrs) => self.buffered_growth(Some(attrs.uid)),
+            None => 0,
+        };
         let file = match self.files.get_mut(&ino) {
             Some(file) => file,
             None => return Err(Error::FileNotFound),
@@ -185,46 +1200,946 @@ impl NsFS {
             None => return Err(Error::AttrsNotFound),
         };
 
-        let offset: usize = offset as usize;
-
-        if offset >= data.len() {
-            // extend with zeroes until we are at least at offset
-            file.data
-                .extend(std::iter::repeat(0).take(offset - file.data.len()));
+        match mode {
+            0 => {}
+            libc::FALLOC_FL_KEEP_SIZE => return Ok(()),
+            _ => return Err(Error::NotSupported),
         }
 
-        if offset + data.len() > file.data.len() {
-            file.data.splice(offset.., data.iter().cloned());
-        } else {
-            file.data
-                .splice(offset..offset + data.len(), data.iter().cloned());
+        let end = offset + length;
+        if self.max_file_size.is_some_and(|max| end > max) {
+            return Err(Error::FileTooBig);
+        }
+        if end > attrs.size && free.is_some_and(|free| end - attrs.size > free) {
+            return Err(Error::NoSpace);
+        }
+        if end > attrs.size {
+            if let Some(&quota) = self.quotas.get(&attrs.uid) {
+                let usage = self
+                    .usage_by_uid
+                    .get(&attrs.uid)
+                    .copied()
+                    .unwrap_or_default();
+                if usage + reserved + end - attrs.size > quota {
+                    return Err(Error::QuotaExceeded);
+                }
+            }
+            if let Some(storage) = &mut self.storage {
+                storage.truncate(ino, end as usize)?;
+            }
+            charge(&mut self.usage_by_uid, attrs.uid, attrs.size, end);
+            file.resize(end as usize);
+            attrs.size = end;
+            attrs.blocks = blocks_for(end, self.block_size);
+            let now = self.clock.now();
+            attrs.mtime = now;
+            attrs.ctime = now;
+            self.log_attrs(ino);
+            self.shadow_truncate(ino, end);
+            self.emit_modified(ino);
         }
 
-        let now = SystemTime::now();
-        attrs.atime = now;
-        attrs.mtime = now;
-        attrs.size = file.data.len() as u64;
-
-        Ok(data.len())
+        Ok(())
     }
 
-    pub(crate) fn create_file(
+    /// Reads with exclusive access, updating the access time right away.
+    pub(crate) fn read_file(
         &mut self,
-        parent: INode,
-        name: &OsStr,
-        flags: u32,
-    ) -> Result<(&FileAttr, FileDescriptor), Error> {
-        let ino = self.next_inode();
-        let parent_node = match self.nodes.get_mut(&parent) {
-            Some(node) => node,
-            None => return Err(Error::NotFound),
+        ino: INode,
+        size: usize,
+        offset: usize,
+    ) -> Result<&[u8], Error> {
+        self.check_not_dir(ino)?;
+        self.page_in(ino)?;
+        let file = match self.files.get(&ino) {
+            Some(file) => file,
+            None => return Err(Error::FileNotFound),
         };
 
-        if parent_node.children.contains_key(name) {
-            return Err(Error::AlreadyExists);
-        }
+        let attrs = match self.attrs.get_mut(&ino) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
+        let now = self.clock.now();
+        if self.atime_policy.should_update(attrs, now) {
+            attrs.atime = now;
+        }
+
+        let size = clamp_read(file, size, offset)?;
+        if let Some(storage) = &self.storage {
+            storage.read(ino, offset, size, &mut self.read_buf)?;
+            return Ok(&self.read_buf);
+        }
+        if let Some(data) = file.slice(offset, size) {
+            return Ok(data);
+        }
+        file.read_into(offset, size, &mut self.read_buf);
+        Ok(&self.read_buf)
+    }
+
+    /// Like `read_file`, but borrows the data straight from the segments it's
+    /// kept in, piece by piece, instead of assembling it in one buffer.
+    #[cfg(test)]
+    pub(crate) fn read_file_chunks(
+        &mut self,
+        ino: INode,
+        size: usize,
+        offset: usize,
+    ) -> Result<Vec<&[u8]>, Error> {
+        self.check_not_dir(ino)?;
+        self.page_in(ino)?;
+        let file = match self.files.get(&ino) {
+            Some(file) => file,
+            None => return Err(Error::FileNotFound),
+        };
+
+        let attrs = match self.attrs.get_mut(&ino) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
+        let now = self.clock.now();
+        if self.atime_policy.should_update(attrs, now) {
+            attrs.atime = now;
+        }
+
+        let size = clamp_read(file, size, offset)?;
+        if let Some(storage) = &self.storage {
+            storage.read(ino, offset, size, &mut self.read_buf)?;
+            return Ok(vec![&self.read_buf]);
+        }
+        Ok(file.chunks(offset, size).collect())
+    }
+
+    /// Like `read_file`, but without exclusive access: the access time is
+    /// only recorded and lands in the attributes with `apply_access_times`.
+    pub(crate) fn read_file_shared(
+        &self,
+        ino: INode,
+        size: usize,
+        offset: usize,
+    ) -> Result<Cow<'_, [u8]>, Error> {
+        self.check_not_dir(ino)?;
+        let file = match self.files.get(&ino) {
+            Some(file) => file,
+            None => return Err(Error::FileNotFound),
+        };
+        self.accessed.lock().unwrap().insert(ino, self.clock.now());
+
+        let size = clamp_read(file, size, offset)?;
+        let mut data = Vec::new();
+        if let Some(storage) = &self.storage {
+            storage.read(ino, offset, size, &mut data)?;
+            return Ok(Cow::Owned(data));
+        }
+        if let Some(data) = file.slice(offset, size) {
+            return Ok(Cow::Borrowed(data));
+        }
+        file.read_into(offset, size, &mut data);
+        Ok(Cow::Owned(data))
+    }
+
+    /// Updates the access times recorded by `read_file_shared`, following the
+    /// atime policy as of the time of each read.
+    pub(crate) fn apply_access_times(&mut self) {
+        let accessed = std::mem::take(self.accessed.get_mut().unwrap());
+        for (ino, time) in accessed {
+            if let Some(attrs) = self.attrs.get_mut(&ino) {
+                if self.atime_policy.should_update(attrs, time) {
+                    attrs.atime = time;
+                }
+            }
+        }
+    }
+
+    /// Reads through an open file handle.
+    ///
+    /// Buffered handles go through the page cache: a short read means the end
+    /// of file and the kernel fills the rest of the page with zeroes itself, so
+    /// reading at or past the end reports `Error::Eof` just like `read_file`.
+    /// With `direct_io` the result is returned to the caller unchanged, so the
+    /// data is clamped to the file tail and a read past the end is simply empty.
+    pub(crate) fn read_handle(
+        &mut self,
+        fh: FileDescriptor,
+        size: usize,
+        offset: usize,
+    ) -> Result<Cow<'_, [u8]>, Error> {
+        self.check_interrupted(fh)?;
+        let file_size = match self.open_files.get(&fh) {
+            Some(open_file) => self.attrs.get(&open_file.ino).map_or(0, |attrs| attrs.size),
+            None => return Err(Error::BadFileDescriptor),
+        };
+
+        let open_file = self.open_files.get_mut(&fh).unwrap();
+        let end = ((offset + size) as u64).min(file_size);
+        open_file.offset = end.max(offset as u64);
+        let (ino, direct_io) = (open_file.ino, open_file.direct_io);
+        self.page_in(ino)?;
+
+        match self.read_file_shared(ino, size, offset) {
+            Err(Error::Eof) if direct_io => Ok(Cow::Borrowed(&[])),
+            result => result,
+        }
+    }
+
+    /// A flag that cancels the next read or write through `fh` when set,
+    /// possibly from another thread. In-memory operations never block, so
+    /// they're only cancelled before they start. The kernel can't set it:
+    /// fuser 0.12 drops `FUSE_INTERRUPT` requests without handing them on.
+    #[cfg(test)]
+    pub(crate) fn interrupt_token(&self, fh: FileDescriptor) -> Option<Arc<AtomicBool>> {
+        self.open_files
+            .get(&fh)
+            .map(|open_file| Arc::clone(&open_file.interrupted))
+    }
+
+    fn check_interrupted(&self, fh: FileDescriptor) -> Result<(), Error> {
+        match self.open_files.get(&fh) {
+            Some(open_file) if open_file.interrupted.swap(false, Ordering::SeqCst) => {
+                Err(Error::Interrupted)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes through an open file handle.
+    pub(crate) fn write_handle(
+        &mut self,
+        fh: FileDescriptor,
+        data: &[u8],
+        offset: usize,
+    ) -> Result<usize, Error> {
+        self.check_interrupted(fh)?;
+        let ino = match self.open_files.get(&fh) {
+            Some(open_file) => open_file.ino,
+            None => return Err(Error::BadFileDescriptor),
+        };
+
+        let written = if self.buffered {
+            let data = self.clamp_write(data);
+            self.check_file_size((offset + data.len()) as u64)?;
+            self.check_not_executing(ino)?;
+            self.check_in_flight(ino, Some(fh), offset, data.len())?;
+            self.page_in(ino)?;
+
+            // the write is reported as done, so the space it needs is
+            // reserved now rather than found missing on flush
+            let end = self.buffered_end(ino);
+            let growth = ((offset + data.len()) as u64).saturating_sub(end);
+            let data = fit_in(data, growth, self.free_bytes())?;
+            let growth = ((offset + data.len()) as u64).saturating_sub(end);
+            if let Some(uid) = self.attrs.get(&ino).map(|attrs| attrs.uid) {
+                self.check_quota(uid, growth)?;
+            }
+
+            let pending = self.pending_writes.entry(fh).or_default();
+            pending.push(PendingWrite {
+                offset,
+                data: data.to_vec(),
+            });
+            let pending_bytes: usize = pending.iter().map(|write| write.data.len()).sum();
+            if pending_bytes > self.flush_threshold {
+                self.flush_handle(fh)?;
+            }
+            data.len()
+        } else {
+            self.write_file(ino, data, offset)?
+        };
+
+        if let Some(open_file) = self.open_files.get_mut(&fh) {
+            open_file.offset = (offset + written) as u64;
+        }
+        Ok(written)
+    }
+
+    /// Merges the writes buffered for `fh` into the file content, in the
+    /// order they were issued. Whatever couldn't be written stays buffered
+    /// for the next flush.
+    pub(crate) fn flush_handle(&mut self, fh: FileDescriptor) -> Result<(), Error> {
+        let ino = match self.open_files.get(&fh) {
+            Some(open_file) => open_file.ino,
+            None => return Err(Error::BadFileDescriptor),
+        };
+
+        let mut pending = match self.pending_writes.remove(&fh) {
+            Some(pending) => pending.into_iter(),
+            None => return Ok(()),
+        };
+        while let Some(write) = pending.next() {
+            let (unwritten, err) = match self.write_file(ino, &write.data, write.offset) {
+                Ok(written) if written == write.data.len() => {
+                    self.coalesced_writes += 1;
+                    continue;
+                }
+                Ok(written) => (
+                    PendingWrite {
+                        offset: write.offset + written,
+                        data: write.data[written..].to_vec(),
+                    },
+                    Error::NoSpace,
+                ),
+                Err(err) => (write, err),
+            };
+            let unwritten = std::iter::once(unwritten).chain(pending).collect();
+            self.pending_writes.insert(fh, unwritten);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Where `ino` ends once the writes buffered for it are flushed.
+    fn buffered_end(&self, ino: INode) -> u64 {
+        let len = self.files.get(&ino).map_or(0, File::len) as u64;
+        self.pending_writes
+            .iter()
+            .filter(|(fh, _)| self.open_files.get(fh).is_some_and(|file| file.ino == ino))
+            .flat_map(|(_, pending)| pending)
+            .map(|write| (write.offset + write.data.len()) as u64)
+            .fold(len, u64::max)
+    }
+
+    /// How many bytes the buffered writes add once flushed, only counting
+    /// the files owned by `uid` if given.
+    fn buffered_growth(&self, uid: Option<u32>) -> u64 {
+        let inos: HashSet<INode> = self
+            .pending_writes
+            .keys()
+            .filter_map(|fh| self.open_files.get(fh))
+            .map(|file| file.ino)
+            .collect();
+        inos.into_iter()
+            .filter(|ino| uid.is_none() || self.attrs.get(ino).map(|attrs| attrs.uid) == uid)
+            .map(|ino| {
+                let len = self.files.get(&ino).map_or(0, File::len) as u64;
+                self.buffered_end(ino) - len
+            })
+            .sum()
+    }
+
+    /// Flushes the writes buffered for `fh` and persists its file.
+    pub(crate) fn sync_handle(&mut self, fh: FileDescriptor) -> Result<(), Error> {
+        self.flush_handle(fh)?;
+        match self.open_files.get(&fh) {
+            Some(open_file) => self.sync_inode(open_file.ino),
+            None => Err(Error::BadFileDescriptor),
+        }
+    }
+
+    /// Commits changes to the entries of a directory. The tree only lives in
+    /// memory unless there's a `state_file`, which is then rewritten.
+    pub(crate) fn sync_dir(&mut self, ino: INode) -> Result<(), Error> {
+        match self.nodes.get(&ino) {
+            Some(node) if node.kind == FileType::Directory => {}
+            Some(_) => return Err(Error::NotADirectory),
+            None => return Err(Error::NotFound),
+        }
+        self.checkpoint()
+    }
+
+    /// Persists every file changed since it was last persisted, after
+    /// flushing what released handles still hold.
+    pub(crate) fn sync_all(&mut self) -> Result<(), Error> {
+        self.flush_released()?;
+        let dirty: Vec<INode> = self.dirty.iter().copied().collect();
+        for ino in dirty {
+            self.sync_inode(ino)?;
+        }
+        self.last_sync = Some(self.clock.now());
+        Ok(())
+    }
+
+    fn sync_inode(&mut self, ino: INode) -> Result<(), Error> {
+        if !self.dirty.contains(&ino) {
+            return Ok(());
+        }
+
+        self.fault_in(ino)?;
+        if let Some(file) = self.files.get(&ino) {
+            self.backing.persist(ino, file)?;
+        }
+        self.dirty.remove(&ino);
+        Ok(())
+    }
+
+    fn sync_if_due(&mut self) -> Result<(), Error> {
+        let now = self.clock.now();
+        let last_sync = *self.last_sync.get_or_insert(now);
+        let due = now
+            .duration_since(last_sync)
+            .is_ok_and(|elapsed| elapsed >= WRITE_BACK_INTERVAL);
+        if due {
+            self.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and forgets a file handle. Writes that were reported as done
+    /// but can't be flushed aren't dropped: the handle stays around, released,
+    /// until `sync_all` manages to flush them.
+    pub(crate) fn close_file(&mut self, fh: FileDescriptor) -> Result<(), Error> {
+        let result = self.flush_handle(fh);
+        let open_file = match self.open_files.get_mut(&fh) {
+            Some(open_file) => open_file,
+            None => return result,
+        };
+        let ino = open_file.ino;
+        if open_file.flags & FMODE_EXEC != 0 {
+            open_file.flags &= !FMODE_EXEC;
+            if let Some(count) = self.exec_opens.get_mut(&ino) {
+                *count -= 1;
+                if *count == 0 {
+                    self.exec_opens.remove(&ino);
+                }
+            }
+        }
+        if result.is_err() && self.pending_writes.contains_key(&fh) {
+            if !open_file.released {
+                open_file.released = true;
+                log::warn!(
+                    "inode {}: keeping writes through released handle {} until they can be flushed",
+                    ino,
+                    fh
+                );
+            }
+            return result;
+        }
+
+        self.open_files.remove(&fh);
+        self.compact_if_fragmented(ino);
+        result
+    }
+
+    /// Tries again to flush and close the handles `close_file` had to keep.
+    fn flush_released(&mut self) -> Result<(), Error> {
+        let released: Vec<FileDescriptor> = self
+            .open_files
+            .keys()
+            .filter(|fh| self.open_files.get(fh).is_some_and(|file| file.released))
+            .collect();
+        for fh in released {
+            self.close_file(fh)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the segments of a file that hold only zeroes or lie past its
+    /// end, returning how many were dropped. Its content is unchanged.
+    pub(crate) fn compact(&mut self, ino: INode) -> Result<usize, Error> {
+        self.check_not_dir(ino)?;
+        self.fault_in(ino)?;
+        let file = self.files.get_mut(&ino).ok_or(Error::FileNotFound)?;
+        let dropped = file.compact();
+        if dropped > 0 {
+            log::debug!("compacted inode {}: dropped {} segments", ino, dropped);
+        }
+        Ok(dropped)
+    }
+
+    /// Compaction runs on the FUSE session thread, which owns the
+    /// filesystem, rather than on a thread of its own: requests are handled
+    /// one at a time, so nothing else can touch the file while it's
+    /// compacted. Running it in the background would mean sharing `NsFS`
+    /// behind a `Mutex` that every request takes. It's scheduled when a
+    /// file's last handle is closed, so it never races a writer.
+    fn compact_if_fragmented(&mut self, ino: INode) {
+        if self
+            .open_files
+            .values()
+            .any(|open_file| open_file.ino == ino)
+        {
+            return;
+        }
+        let fragmented = self
+            .files
+            .get(&ino)
+            .is_some_and(|file| file.compactable() >= self.compact_threshold);
+        if fragmented {
+            let _ = self.compact(ino);
+        }
+    }
+
+    /// With `strict_writes`, fails if `[offset, offset + len)` of `ino`
+    /// overlaps a write buffered for a handle other than `fh`.
+    fn check_in_flight(
+        &self,
+        ino: INode,
+        fh: Option<FileDescriptor>,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), Error> {
+        if !self.strict_writes {
+            return Ok(());
+        }
+        let overlaps = self
+            .pending_writes
+            .iter()
+            .filter(|(&other, _)| Some(other) != fh)
+            .filter(|(other, _)| {
+                self.open_files
+                    .get(other)
+                    .is_some_and(|file| file.ino == ino)
+            })
+            .flat_map(|(_, pending)| pending)
+            .any(|write| write.offset < offset + len && offset < write.offset + write.data.len());
+        if overlaps {
+            return Err(Error::WouldBlock);
+        }
+        Ok(())
+    }
+
+    /// A file that's being executed can't be written to.
+    fn check_not_executing(&self, ino: INode) -> Result<(), Error> {
+        if self.exec_opens.contains_key(&ino) {
+            return Err(Error::TextBusy);
+        }
+        Ok(())
+    }
+
+    fn check_not_dir(&self, ino: INode) -> Result<(), Error> {
+        match self.attrs.get(&ino) {
+            Some(attrs) if attrs.kind == FileType::Directory => Err(Error::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_file_size(&self, size: u64) -> Result<(), Error> {
+        match self.max_file_size {
+            Some(max_file_size) if size > max_file_size => Err(Error::FileTooBig),
+            _ => Ok(()),
+        }
+    }
+
+    // a short write makes the kernel send the remainder in another request
+    fn clamp_write<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        match self.max_write {
+            Some(max_write) if data.len() > max_write => &data[..max_write],
+            _ => data,
+        }
+    }
+
+    pub(crate) fn write_file(
+        &mut self,
+        ino: INode,
+        data: &[u8],
+        offset: usize,
+    ) -> Result<usize, Error> {
+        let data = self.clamp_write(data);
+        self.check_file_size((offset + data.len()) as u64)?;
+        self.check_not_dir(ino)?;
+        self.check_not_executing(ino)?;
+        self.check_in_flight(ino, None, offset, data.len())?;
+        self.page_in(ino)?;
+
+        let len = self.files.get(&ino).map_or(0, File::len) as u64;
+        let growth = ((offset + data.len()) as u64).saturating_sub(len);
+        let data = fit_in(data, growth, self.free_bytes())?;
+        let growth = ((offset + data.len()) as u64).saturating_sub(len);
+        if let Some(attrs) = self.attrs.get(&ino) {
+            self.check_quota(attrs.uid, growth)?;
+        }
+
+        let file = match self.files.get_mut(&ino) {
+            Some(file) => file,
+            None => return Err(Error::FileNotFound),
+        };
+
+        let attrs = match self.attrs.get_mut(&ino) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
+
+        match &mut self.storage {
+            Some(storage) => {
+                storage.write(ino, offset, data)?;
+                file.resize(file.len().max(offset + data.len()));
+            }
+            None => {
+                file.write(offset, data);
+                file.share_segments(offset, data.len(), &mut self.segment_index);
+            }
+        }
+
+        let now = self.clock.now();
+        attrs.atime = now;
+        attrs.mtime = now;
+        attrs.ctime = now;
+        charge(&mut self.usage_by_uid, attrs.uid, len, file.len() as u64);
+        attrs.size = file.len() as u64;
+        attrs.blocks = blocks_for(attrs.size, self.block_size);
+        if let Some(invalidations) = &self.invalidations {
+            invalidations.push(ino);
+        }
+
+        // the write is made, so it's logged even if persisting it fails
+        self.log_change(WalRecord::Write {
+            ino,
+            offset: offset as u64,
+            data: Cow::Borrowed(data),
+        });
+        self.shadow_write(ino, offset, data);
+        self.emit_modified(ino);
+        self.persist_content(ino)?;
+
+        Ok(data.len())
+    }
+
+    /// Persists the changed content of `ino` as `write_policy` says, once
+    /// the change was logged. A file that fails to persist stays dirty, to
+    /// be persisted again by the next sync.
+    fn persist_content(&mut self, ino: INode) -> Result<(), Error> {
+        match self.write_policy {
+            WritePolicy::WriteThrough => {
+                let result = match self.files.get(&ino) {
+                    Some(file) => self.backing.persist(ino, file),
+                    None => Ok(()),
+                };
+                if result.is_err() {
+                    self.dirty.insert(ino);
+                }
+                result
+            }
+            WritePolicy::WriteBack => {
+                self.dirty.insert(ino);
+                self.sync_if_due()
+            }
+        }
+    }
+
+    /// Replaces the whole content of a file with `data` in one step, so it's
+    /// never seen truncated or half written. The old content is dropped as a
+    /// whole; files sharing its segments keep them.
+    pub(crate) fn replace_content(&mut self, ino: INode, data: &[u8]) -> Result<&FileAttr, Error> {
+        let len = self.check_new_content(ino, data.len() as u64)?;
+        let content = match &mut self.storage {
+            Some(storage) => {
+                storage.truncate(ino, 0)?;
+                storage.write(ino, 0, data)?;
+                let mut content = File::new();
+                content.resize(data.len());
+                content
+            }
+            None => {
+                let mut content = File::from_vec(data.to_vec());
+                content.share_segments(0, data.len(), &mut self.segment_index);
+                content
+            }
+        };
+        self.commit_content(ino, content, len)?;
+        self.log_change(WalRecord::Content {
+            ino,
+            data: Cow::Borrowed(data),
+        });
+        self.shadow_content(ino, data);
+        self.emit_modified(ino);
+        self.persist_content(ino)?;
+        self.attrs.get(&ino).ok_or(Error::AttrsNotFound)
+    }
+
+    /// Copies the whole content of `src` over `dst`, as `cp` within the
+    /// mount would. The copy shares the segments of `src` copy-on-write, so
+    /// nothing is copied until one of them is written to.
+    pub(crate) fn transfer(&mut self, src: INode, dst: INode) -> Result<&FileAttr, Error> {
+        self.check_not_dir(src)?;
+        self.fault_in(src)?;
+        let content = match self.files.get(&src) {
+            Some(file) => file.clone(),
+            None => return Err(Error::FileNotFound),
+        };
+        if src == dst {
+            return self.attrs.get(&dst).ok_or(Error::AttrsNotFound);
+        }
+        // the content is only in the storage, so it's copied there
+        if let Some(storage) = &self.storage {
+            let mut data = Vec::new();
+            storage.read(src, 0, content.len(), &mut data)?;
+            return self.replace_content(dst, &data);
+        }
+
+        let len = self.check_new_content(dst, content.len() as u64)?;
+        let mirrored = self.wal.is_some() || self.shadow_root.is_some();
+        let data = mirrored.then(|| content.to_vec());
+        self.commit_content(dst, content, len)?;
+        if let Some(data) = data {
+            self.shadow_content(dst, &data);
+            self.log_change(WalRecord::Content {
+                ino: dst,
+                data: Cow::Owned(data),
+            });
+        }
+        self.emit_modified(dst);
+        self.persist_content(dst)?;
+        self.attrs.get(&dst).ok_or(Error::AttrsNotFound)
+    }
+
+    /// Checks that the content of `ino` can be replaced with `new_len` bytes
+    /// and returns its current length.
+    fn check_new_content(&mut self, ino: INode, new_len: u64) -> Result<u64, Error> {
+        self.check_file_size(new_len)?;
+        self.check_not_dir(ino)?;
+        self.check_not_executing(ino)?;
+        self.fault_in(ino)?;
+
+        let len = match self.files.get(&ino) {
+            Some(file) => file.len() as u64,
+            None => return Err(Error::FileNotFound),
+        };
+        let growth = new_len.saturating_sub(len);
+        if self.free_bytes().is_some_and(|free| growth > free) {
+            return Err(Error::NoSpace);
+        }
+        if let Some(attrs) = self.attrs.get(&ino) {
+            self.check_quota(attrs.uid, growth)?;
+        }
+        Ok(len)
+    }
+
+    /// Swaps in `content` for the content of `ino`, `len` bytes long so far.
+    /// It's persisted separately, see `persist_content`.
+    fn commit_content(&mut self, ino: INode, content: File, len: u64) -> Result<(), Error> {
+        let file = match self.files.get_mut(&ino) {
+            Some(file) => file,
+            None => return Err(Error::FileNotFound),
+        };
+        let attrs = match self.attrs.get_mut(&ino) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
+        let new_len = content.len() as u64;
+        *file = content;
+
+        let now = self.clock.now();
+        attrs.mtime = now;
+        attrs.ctime = now;
+        charge(&mut self.usage_by_uid, attrs.uid, len, new_len);
+        attrs.size = new_len;
+        attrs.blocks = blocks_for(attrs.size, self.block_size);
+        if let Some(invalidations) = &self.invalidations {
+            invalidations.push(ino);
+        }
+        Ok(())
+    }
+
+    /// Creates and opens a regular file. Its permissions are `mode`, or
+    /// `default_file_mode` if not given, without the bits set in `umask`.
+    /// Like `open` with `O_CREAT`, an existing file of that name is opened
+    /// instead unless `flags` has `O_EXCL`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn create_file(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        flags: u32,
+        uid: u32,
+        gid: u32,
+        mode: Option<u32>,
+        umask: u32,
+    ) -> Result<(&FileAttr, FileDescriptor), Error> {
+        if flags as i32 & libc::O_EXCL == 0 {
+            if let Ok(node) = self.find_node(parent, name) {
+                match node.kind {
+                    FileType::RegularFile => {}
+                    FileType::Directory => return Err(Error::IsADirectory),
+                    _ => return Err(Error::AlreadyExists),
+                }
+                let ino = node.index;
+                if flags as i32 & libc::O_TRUNC != 0 {
+                    let truncate = SetAttr {
+                        size: Some(0),
+                        ..Default::default()
+                    };
+                    self.set_attr(ino, truncate)?;
+                }
+                // the kernel counts a lookup for every entry it's sent
+                self.remember_lookup(ino);
+                let fh = self.open_file(ino, flags as i32);
+                return Ok((self.attrs.get(&ino).unwrap(), fh));
+            }
+        }
+        let ino = self.insert_node(parent, name, FileType::RegularFile, 0, flags, uid, gid)?;
+        let mode = mode.unwrap_or(self.default_file_mode);
+        self.apply_mode(ino, mode, umask);
+        self.log_create(parent, name, ino);
+        self.shadow_create(parent, name);
+        self.emit_created(parent, name, ino);
+        let fh = self.open_file(ino, flags as i32);
+        Ok((self.attrs.get(&ino).unwrap(), fh))
+    }
+
+    /// Creates a directory. Its permissions are `mode`, or `default_dir_mode`
+    /// if not given, without the bits set in `umask`.
+    pub(crate) fn create_dir(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        uid: u32,
+        gid: u32,
+        mode: Option<u32>,
+        umask: u32,
+    ) -> Result<&FileAttr, Error> {
+        let ino = self.insert_node(parent, name, FileType::Directory, 0, 0, uid, gid)?;
+        let mode = mode.unwrap_or(self.default_dir_mode);
+        self.apply_mode(ino, mode, umask);
+        self.log_create(parent, name, ino);
+        self.emit_created(parent, name, ino);
+        Ok(self.attrs.get(&ino).unwrap())
+    }
+
+    /// Creates a regular file or a special file node, as `mknod` does, with
+    /// the permission bits of `mode` not set in `umask`. A character device
+    /// with `rdev` 0 is how overlayfs marks a whiteout.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn create_special(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        uid: u32,
+        gid: u32,
+        umask: u32,
+    ) -> Result<&FileAttr, Error> {
+        let kind = match mode & libc::S_IFMT {
+            libc::S_IFREG => FileType::RegularFile,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFIFO => FileType::NamedPipe,
+            libc::S_IFSOCK => FileType::Socket,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        let ino = self.insert_node(parent, name, kind, rdev, 0, uid, gid)?;
+        self.apply_mode(ino, mode & 0o7777, umask);
+        self.log_create(parent, name, ino);
+        self.emit_created(parent, name, ino);
+        Ok(self.attrs.get(&ino).unwrap())
+    }
 
-        let ts = SystemTime::now();
+    /// Creates `name` in `parent` as a copy of the regular file `src` that
+    /// shares its content copy-on-write, so nothing is copied until one of
+    /// them is written to. The caller needs to be able to read `src` and to
+    /// add entries to `parent`. The copy is owned by the caller, with `mode`,
+    /// or the permissions of `src` if not given, without the bits set in
+    /// `umask`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn create_reflink(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        src: INode,
+        uid: u32,
+        gid: u32,
+        mode: Option<u32>,
+        umask: u32,
+    ) -> Result<&FileAttr, Error> {
+        self.check_not_dir(src)?;
+        self.check_access(src, uid, gid, libc::R_OK)?;
+        self.check_access(parent, uid, gid, libc::W_OK | libc::X_OK)?;
+        let mode = match mode {
+            Some(mode) => mode,
+            None => self.get_attr(src)?.perm as u32,
+        };
+        self.fault_in(src)?;
+        let file = match self.files.get(&src) {
+            Some(file) => file.clone(),
+            None => return Err(Error::FileNotFound),
+        };
+        let size = file.len() as u64;
+        if self.free_bytes().is_some_and(|free| size > free) {
+            return Err(Error::NoSpace);
+        }
+        self.check_quota(uid, size)?;
+
+        let ino = self.insert_node(parent, name, FileType::RegularFile, 0, 0, uid, gid)?;
+        self.apply_mode(ino, mode & 0o7777, umask);
+        // the kernel learns about the entry by looking it up
+        self.lookup_counts.remove(&ino);
+        charge(&mut self.usage_by_uid, uid, 0, size);
+        if let Some(attrs) = self.attrs.get_mut(&ino) {
+            attrs.size = size;
+            attrs.blocks = blocks_for(size, self.block_size);
+        }
+        let data = self.wal.as_ref().map(|_| file.to_vec());
+        self.files.insert(ino, file);
+        self.log_create(parent, name, ino);
+        if let Some(data) = data {
+            self.log_change(WalRecord::Content {
+                ino,
+                data: Cow::Owned(data),
+            });
+        }
+        self.emit_created(parent, name, ino);
+        self.persist_content(ino)?;
+
+        Ok(self.attrs.get(&ino).unwrap())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn insert_node(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        kind: FileType,
+        rdev: u32,
+        flags: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<INode, Error> {
+        let parent_node = match self.nodes.get(&parent) {
+            Some(node) => node,
+            None => return Err(Error::NotFound),
+        };
+        if parent_node.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        if parent_node.children.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        if is_full(parent_node, self.max_entries_per_dir) {
+            return Err(Error::DirFull);
+        }
+        let depth = parent_node.depth + 1;
+        if kind == FileType::Directory && self.max_depth.is_some_and(|max| depth > max) {
+            return Err(Error::TooDeep);
+        }
+
+        // only allocated once nothing can fail, so a failed create doesn't
+        // use up an inode
+        let path = self.path_of(parent).unwrap_or_default().join(name);
+        let ino = self.new_inode(&path, kind, rdev, flags, uid, gid);
+
+        if let Some(parent_node) = self.nodes.get_mut(&parent) {
+            parent_node.add_child(name, ino);
+        }
+        let mut node = Node::new(ino, parent, name, kind, depth);
+        if self.sorted_index && kind == FileType::Directory {
+            node.sorted = Some(BTreeSet::new());
+        }
+        self.nodes.insert(ino, node);
+        self.touch_dir(parent);
+        self.invalidate_negative(parent, name);
+
+        self.remember_lookup(ino);
+        Ok(ino)
+    }
+
+    /// Allocates an inode for a new node of `kind` at `path`, linked once.
+    fn new_inode(
+        &mut self,
+        path: &Path,
+        kind: FileType,
+        rdev: u32,
+        flags: u32,
+        uid: u32,
+        gid: u32,
+    ) -> INode {
+        let ino = self.next_inode(path);
+        self.generations
+            .entry(ino)
+            .and_modify(|generation| *generation += 1)
+            .or_insert(0);
+        let ts = self.clock.now();
         self.attrs.insert(
             ino,
             FileAttr {
@@ -235,25 +2150,645 @@ impl NsFS {
                 mtime: ts,
                 ctime: ts,
                 crtime: ts,
-                kind: FileType::RegularFile,
+                kind,
                 perm: 0o777,
-                nlink: 0,
-                uid: 0,
-                gid: 0,
-                rdev: 0,
-                blksize: 0,
+                nlink: if kind == FileType::Directory { 2 } else { 1 },
+                uid,
+                gid,
+                rdev,
+                blksize: self.block_size,
                 flags,
             },
         );
-        self.files.insert(ino, File::new());
+        if kind == FileType::RegularFile {
+            self.files.insert(ino, File::new());
+        }
+        ino
+    }
 
-        let key = name.to_os_string();
-        parent_node
-            .children
-            .entry(key)
-            .or_insert(Node::new_file(ino, parent, name));
+    /// Adds `newname` in `newparent` as another link to `ino`, which can't be
+    /// a directory.
+    pub(crate) fn link_node(
+        &mut self,
+        ino: INode,
+        newparent: INode,
+        newname: &OsStr,
+    ) -> Result<&FileAttr, Error> {
+        match self.attrs.get(&ino) {
+            Some(attrs) if attrs.kind == FileType::Directory => return Err(Error::NotPermitted),
+            Some(_) => {}
+            None => return Err(Error::NotFound),
+        }
+        let parent_node = match self.nodes.get(&newparent) {
+            Some(node) => node,
+            None => return Err(Error::NotFound),
+        };
+        if parent_node.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        if parent_node.children.contains_key(newname) {
+            return Err(Error::AlreadyExists);
+        }
+        if is_full(parent_node, self.max_entries_per_dir) {
+            return Err(Error::DirFull);
+        }
+        if self.device_of(ino) != self.device_of(newparent) {
+            return Err(Error::CrossDevice);
+        }
 
-        let fh = self.open_file(ino);
-        Ok((self.attrs.get(&ino).unwrap(), fh))
+        if let Some(parent_node) = self.nodes.get_mut(&newparent) {
+            parent_node.add_child(newname, ino);
+        }
+        self.invalidate_negative(newparent, newname);
+        let now = self.clock.now();
+        if let Some(attrs) = self.attrs.get_mut(&ino) {
+            attrs.nlink += 1;
+            attrs.ctime = now;
+        }
+        self.touch_dir(newparent);
+        self.log_change(WalRecord::Link {
+            ino,
+            parent: newparent,
+            name: Cow::Borrowed(newname),
+        });
+        self.emit_created(newparent, newname, ino);
+
+        self.remember_lookup(ino);
+        Ok(self.attrs.get(&ino).unwrap())
+    }
+
+    /// Resolves `name` under `parent` on behalf of the kernel, which from now on
+    /// holds one more reference to the inode until it's released via `forget`.
+    pub(crate) fn lookup_entry(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        uid: u32,
+        gid: u32,
+    ) -> Result<&FileAttr, Error> {
+        // denied search hides whether the entry exists
+        if self
+            .nodes
+            .get(&parent)
+            .is_some_and(|node| node.kind == FileType::Directory)
+        {
+            self.check_search(parent, uid, gid)?;
+        }
+        let ino = self.find_node(parent, name)?.index;
+        self.remember_lookup(ino);
+        self.get_attr(ino)
+    }
+
+    /// The entry to answer a lookup of `name` in `parent` with when it doesn't
+    /// exist, if `negative_ttl` is set: inode 0, which the kernel caches as
+    /// a missing name for the returned time.
+    pub(crate) fn negative_entry(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+    ) -> Option<(Duration, FileAttr)> {
+        let ttl = self.negative_ttl?;
+        match self.nodes.get(&parent) {
+            Some(node) if node.kind == FileType::Directory => {}
+            _ => return None,
+        }
+        self.negative_entries.insert((parent, name.to_os_string()));
+        let attrs = FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: self.block_size,
+            flags: 0,
+        };
+        Some((ttl, attrs))
+    }
+
+    /// Queues `name` in `parent` for the kernel to drop if it was answered
+    /// as missing before, now that it exists.
+    fn invalidate_negative(&mut self, parent: INode, name: &OsStr) {
+        if self.negative_entries.is_empty()
+            || !self.negative_entries.remove(&(parent, name.to_os_string()))
+        {
+            return;
+        }
+        if let Some(invalidations) = &self.invalidations {
+            invalidations.push_entry(parent, name);
+        }
+    }
+
+    /// Removes `name` from `parent`, which can't be a directory. The inode
+    /// itself stays around while the kernel still references it and is freed
+    /// by the last `forget`.
+    pub(crate) fn remove_file(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        uid: u32,
+    ) -> Result<(), Error> {
+        let parent_node = match self.nodes.get(&parent) {
+            Some(node) => node,
+            None => return Err(Error::NotFound),
+        };
+        if parent_node.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let victim = match parent_node.children.get(name) {
+            Some(&victim) => victim,
+            None => return Err(Error::NotFound),
+        };
+        if self
+            .nodes
+            .get(&victim)
+            .is_some_and(|node| node.kind == FileType::Directory)
+        {
+            return Err(Error::IsADirectory);
+        }
+        self.remove_entry(parent, name, victim, uid)
+    }
+
+    /// Removes the entry `name` of `parent`, which leads to `victim`, after
+    /// the caller checked that it may go.
+    fn remove_entry(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        victim: INode,
+        uid: u32,
+    ) -> Result<(), Error> {
+        self.check_sticky(parent, victim, uid)?;
+        if let Some(node) = self.nodes.get_mut(&parent) {
+            node.remove_child(name);
+        }
+
+        self.touch_dir(parent);
+        self.unlink_inode(victim);
+        self.log_change(WalRecord::Unlink {
+            parent,
+            name: Cow::Borrowed(name),
+        });
+        self.shadow_unlink(parent, name);
+        self.emit_removed(parent, name, victim);
+        Ok(())
+    }
+
+    /// Removes the empty directory `name` from `parent`.
+    pub(crate) fn remove_dir(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        uid: u32,
+    ) -> Result<(), Error> {
+        if name == CURRENT_DIR {
+            return Err(Error::InvalidArgument);
+        }
+        if name == PARENT_DIR {
+            return Err(Error::NotEmpty);
+        }
+        let node = self.find_node(parent, name)?;
+        if node.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        if !node.children.is_empty() {
+            return Err(Error::NotEmpty);
+        }
+        let victim = node.index;
+        self.remove_entry(parent, name, victim, uid)
+    }
+
+    /// Moves `name` from `parent` to `newname` in `newparent`, replacing the
+    /// destination if it exists. `RENAME_NOREPLACE` refuses to replace it and
+    /// `RENAME_EXCHANGE` atomically swaps both entries instead. Returns the
+    /// inode that was replaced, which is already freed if nothing else
+    /// refers to it.
+    pub(crate) fn rename_ex(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        newparent: INode,
+        newname: &OsStr,
+        flags: u32,
+        uid: u32,
+    ) -> Result<Option<INode>, Error> {
+        let ino = self.find_node(parent, name)?.index;
+        let target = match self.nodes.get(&newparent) {
+            Some(node) if node.kind != FileType::Directory => return Err(Error::NotADirectory),
+            Some(node) => node.children.get(newname).copied(),
+            None => return Err(Error::NotFound),
+        };
+        if self.device_of(parent) != self.device_of(newparent) {
+            return Err(Error::CrossDevice);
+        }
+
+        // a directory can't become a descendant of itself
+        if self.is_within(newparent, ino) {
+            return Err(Error::InvalidArgument);
+        }
+        self.check_move_depth(ino, newparent)?;
+
+        // only a new entry in another directory makes it grow
+        if target.is_none()
+            && newparent != parent
+            && flags & libc::RENAME_EXCHANGE == 0
+            && self
+                .nodes
+                .get(&newparent)
+                .is_some_and(|node| is_full(node, self.max_entries_per_dir))
+        {
+            return Err(Error::DirFull);
+        }
+
+        self.check_sticky(parent, ino, uid)?;
+        if let Some(target) = target {
+            if target != ino {
+                self.check_sticky(newparent, target, uid)?;
+            }
+        }
+
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            let target = match target {
+                Some(target) => target,
+                None => return Err(Error::NotFound),
+            };
+            if self.is_within(parent, target) {
+                return Err(Error::InvalidArgument);
+            }
+            self.check_move_depth(target, parent)?;
+
+            self.attach(parent, name, target);
+            self.attach(newparent, newname, ino);
+            self.touch_dir(parent);
+            self.touch_dir(newparent);
+            self.log_rename(parent, name, newparent, newname, flags);
+            self.shadow_rename(parent, name, newparent, newname, true);
+            self.emit_renamed(ino, parent, name, newparent, newname);
+            self.emit_renamed(target, newparent, newname, parent, name);
+            return Ok(None);
+        }
+
+        if let Some(target) = target {
+            if target == ino {
+                return Ok(None);
+            }
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(Error::AlreadyExists);
+            }
+            self.check_replace(ino, target)?;
+
+            if let Some(node) = self.nodes.get_mut(&newparent) {
+                node.remove_child(newname);
+            }
+            self.unlink_inode(target);
+        }
+
+        if let Some(node) = self.nodes.get_mut(&parent) {
+            node.remove_child(name);
+        }
+        self.attach(newparent, newname, ino);
+        self.touch_dir(parent);
+        self.touch_dir(newparent);
+        self.log_rename(parent, name, newparent, newname, flags);
+        self.shadow_rename(parent, name, newparent, newname, false);
+        self.emit_renamed(ino, parent, name, newparent, newname);
+
+        Ok(target)
+    }
+
+    /// Makes directory `dir` and everything below it a volume of its own, as
+    /// if another filesystem was mounted there.
+    pub(crate) fn set_device(&mut self, dir: INode, device_id: u64) -> Result<(), Error> {
+        match self.nodes.get(&dir) {
+            Some(node) if node.kind == FileType::Directory => {}
+            Some(_) => return Err(Error::NotADirectory),
+            None => return Err(Error::NotFound),
+        }
+        self.volumes.insert(dir, device_id);
+        self.log_change(WalRecord::SetVolume {
+            ino: dir,
+            device_id,
+        });
+        Ok(())
+    }
+
+    /// The volume `ino` is on, the one of its closest ancestor holding one
+    /// of its own.
+    fn device_of(&self, ino: INode) -> u64 {
+        let mut current = ino;
+        for _ in 0..=self.nodes.len() {
+            if let Some(&device_id) = self.volumes.get(&current) {
+                return device_id;
+            }
+            match self.nodes.get(&current) {
+                Some(node) if node.parent != 0 => current = node.parent,
+                _ => break,
+            }
+        }
+        self.device_id
+    }
+
+    /// Only a directory may replace a directory, and only an empty one.
+    fn check_replace(&self, ino: INode, target: INode) -> Result<(), Error> {
+        let (node, target) = match (self.nodes.get(&ino), self.nodes.get(&target)) {
+            (Some(node), Some(target)) => (node, target),
+            _ => return Err(Error::NotFound),
+        };
+        match (node.kind, target.kind) {
+            (FileType::Directory, FileType::Directory) if !target.children.is_empty() => {
+                Err(Error::NotEmpty)
+            }
+            (FileType::Directory, FileType::Directory) => Ok(()),
+            (FileType::Directory, _) => Err(Error::NotADirectory),
+            (_, FileType::Directory) => Err(Error::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops `nlookup` kernel references to `ino`.
+    pub(crate) fn forget_inode(&mut self, ino: INode, nlookup: u64) {
+        let count = match self.lookup_counts.get_mut(&ino) {
+            Some(count) => count,
+            None => return,
+        };
+
+        *count = count.saturating_sub(nlookup);
+        if *count == 0 {
+            self.lookup_counts.remove(&ino);
+            self.free_if_unlinked(ino);
+        }
+    }
+
+    /// Looking up an entry needs execute permission on the directory for the
+    /// owner, group or others class `uid` and `gid` fall in. Root is always
+    /// allowed.
+    fn check_search(&self, dir: INode, uid: u32, gid: u32) -> Result<(), Error> {
+        if self.default_permissions {
+            return Ok(());
+        }
+        let attrs = match self.attrs.get(&dir) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
+        let execute = if uid == 0 {
+            return Ok(());
+        } else if uid == attrs.uid {
+            0o100
+        } else if gid == attrs.gid {
+            0o010
+        } else {
+            0o001
+        };
+        if attrs.perm & execute == 0 {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Checks `mask`, a combination of `R_OK`, `W_OK` and `X_OK`, against the
+    /// permission bits of `ino` for the class `uid` and `gid` fall in. Root
+    /// may read and write anything and search any directory, but executing a
+    /// file still needs at least one execute bit.
+    pub(crate) fn check_access(
+        &self,
+        ino: INode,
+        uid: u32,
+        gid: u32,
+        mask: i32,
+    ) -> Result<(), Error> {
+        let attrs = match self.attrs.get(&ino) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
+        if self.default_permissions {
+            return Ok(());
+        }
+
+        let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        if uid == 0 {
+            let execute = mask & libc::X_OK as u16 != 0;
+            if execute && attrs.kind != FileType::Directory && attrs.perm & 0o111 == 0 {
+                return Err(Error::PermissionDenied);
+            }
+            return Ok(());
+        }
+
+        let granted = if uid == attrs.uid {
+            attrs.perm >> 6
+        } else if gid == attrs.gid {
+            attrs.perm >> 3
+        } else {
+            attrs.perm
+        };
+        if mask & !granted & 0o7 != 0 {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// In a sticky directory only root and the owners of the entry or of the
+    /// directory may remove or rename the entry.
+    fn check_sticky(&self, dir: INode, victim: INode, uid: u32) -> Result<(), Error> {
+        if self.default_permissions {
+            return Ok(());
+        }
+        let dir_attrs = match self.attrs.get(&dir) {
+            Some(attrs) => attrs,
+            None => return Err(Error::AttrsNotFound),
+        };
+        if dir_attrs.perm & 0o1000 == 0 || uid == 0 || uid == dir_attrs.uid {
+            return Ok(());
+        }
+
+        match self.attrs.get(&victim) {
+            Some(attrs) if attrs.uid == uid => Ok(()),
+            Some(_) => Err(Error::PermissionDenied),
+            None => Err(Error::AttrsNotFound),
+        }
+    }
+
+    fn apply_mode(&mut self, ino: INode, mode: u32, umask: u32) {
+        if let Some(attrs) = self.attrs.get_mut(&ino) {
+            attrs.perm = (mode & !umask & 0o7777) as u16;
+        }
+    }
+
+    /// Records that the entries of a directory changed.
+    fn touch_dir(&mut self, ino: INode) {
+        let now = self.clock.now();
+        let size = self.nodes.get(&ino).map(dir_size).unwrap_or_default();
+        if let Some(attrs) = self.attrs.get_mut(&ino) {
+            attrs.mtime = now;
+            attrs.ctime = now;
+            attrs.size = size;
+            attrs.blocks = blocks_for(size, self.block_size);
+        }
+    }
+
+    /// Places `ino` under `parent` as `name`, overwriting whatever was there.
+    fn attach(&mut self, parent: INode, name: &OsStr, ino: INode) {
+        if let Some(node) = self.nodes.get_mut(&parent) {
+            node.add_child(name, ino);
+        }
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.parent = parent;
+            node.name = name.to_os_string();
+        }
+        let depth = self.nodes.get(&parent).map_or(0, |node| node.depth + 1);
+        self.set_depth(ino, depth);
+        self.invalidate_negative(parent, name);
+    }
+
+    /// Fails if moving `ino` into `parent` would take a directory below it
+    /// deeper than `max_depth`.
+    fn check_move_depth(&self, ino: INode, parent: INode) -> Result<(), Error> {
+        let (max, node, parent) = match (
+            self.max_depth,
+            self.nodes.get(&ino),
+            self.nodes.get(&parent),
+        ) {
+            (Some(max), Some(node), Some(parent)) => (max, node, parent),
+            _ => return Ok(()),
+        };
+        let mut deepest = None;
+        let mut pending = vec![node];
+        while let Some(node) = pending.pop() {
+            if node.kind != FileType::Directory {
+                continue;
+            }
+            deepest = deepest.max(Some(node.depth));
+            pending.extend(
+                node.children
+                    .values()
+                    .filter_map(|child| self.nodes.get(child)),
+            );
+        }
+        match deepest {
+            Some(deepest) if parent.depth + 1 + (deepest - node.depth) > max => Err(Error::TooDeep),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves `ino` and everything below it to `depth`.
+    fn set_depth(&mut self, ino: INode, depth: u32) {
+        let mut pending = vec![(ino, depth)];
+        while let Some((ino, depth)) = pending.pop() {
+            if let Some(node) = self.nodes.get_mut(&ino) {
+                if node.depth == depth {
+                    continue;
+                }
+                node.depth = depth;
+                pending.extend(node.children.values().map(|&child| (child, depth + 1)));
+            }
+        }
+    }
+
+    /// Reports whether `ino` is `dir` itself or lies somewhere below it.
+    fn is_within(&self, ino: INode, dir: INode) -> bool {
+        let mut current = ino;
+        for _ in 0..=self.nodes.len() {
+            if current == dir {
+                return true;
+            }
+            match self.nodes.get(&current) {
+                Some(node) if node.parent != 0 => current = node.parent,
+                _ => return false,
+            }
+        }
+
+        false
+    }
+
+    /// Drops a directory entry's reference to `ino`, which was already removed
+    /// from its parent's children.
+    fn unlink_inode(&mut self, ino: INode) {
+        let now = self.clock.now();
+        if let Some(attrs) = self.attrs.get_mut(&ino) {
+            attrs.nlink = match attrs.kind {
+                FileType::Directory => 0,
+                _ => attrs.nlink.saturating_sub(1),
+            };
+            attrs.ctime = now;
+        }
+        // other links still lead to the node
+        if self.attrs.get(&ino).is_none_or(|attrs| attrs.nlink == 0) {
+            self.nodes.remove(&ino);
+        } else {
+            self.repoint_node(ino);
+        }
+
+        if !self.lookup_counts.contains_key(&ino) {
+            self.free_if_unlinked(ino);
+        }
+    }
+
+    /// Moves the parent and name of the node of `ino` to a link that's left,
+    /// if the one it had was removed, so its path still exists.
+    fn repoint_node(&mut self, ino: INode) {
+        let (parent, name) = match self.nodes.get(&ino) {
+            Some(node) => (node.parent, node.name.clone()),
+            None => return,
+        };
+        let linked = self
+            .nodes
+            .get(&parent)
+            .is_some_and(|dir| dir.children.get(&name) == Some(&ino));
+        if linked {
+            return;
+        }
+
+        let found = self.nodes.values().find_map(|dir| {
+            dir.children
+                .iter()
+                .find(|(_, &child)| child == ino)
+                .map(|(name, _)| (dir.index, dir.depth, name.clone()))
+        });
+        if let (Some((parent, depth, name)), Some(node)) = (found, self.nodes.get_mut(&ino)) {
+            node.parent = parent;
+            node.name = name;
+            node.depth = depth + 1;
+        }
+    }
+
+    fn remember_lookup(&mut self, ino: INode) {
+        *self.lookup_counts.entry(ino).or_insert(0) += 1;
+    }
+
+    fn free_if_unlinked(&mut self, ino: INode) {
+        let unlinked = match self.attrs.get(&ino) {
+            Some(attrs) => attrs.nlink == 0,
+            None => false,
+        };
+
+        if unlinked {
+            if let (Some(attrs), Some(file)) = (self.attrs.get(&ino), self.files.get(&ino)) {
+                charge(&mut self.usage_by_uid, attrs.uid, file.len() as u64, 0);
+            }
+            self.attrs.remove(&ino);
+            if self.files.remove(&ino).is_some() {
+                if let Some(storage) = &mut self.storage {
+                    storage.remove(ino);
+                }
+            }
+            self.xattrs.remove(&ino);
+            self.volumes.remove(&ino);
+            self.symlinks.remove(&ino);
+            self.dirty.remove(&ino);
+            self.last_used.remove(&ino);
+            if self.spilled.remove(&ino) {
+                if let Some(path) = self.spill_file(ino) {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            self.allocator.free(ino);
+        }
     }
 }
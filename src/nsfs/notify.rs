@@ -0,0 +1,44 @@
+use crate::nsfs::INode;
+
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, Mutex};
+
+/// Inodes whose attributes changed and may be cached by the kernel for up
+/// to the attribute TTL. The filesystem queues them from the session thread
+/// and whoever holds a clone drains them to tell the kernel to fetch the
+/// attributes again. Entries the kernel may have cached as missing are
+/// queued the same way once they appear. Clones share the same queue.
+#[derive(Clone, Default)]
+pub(crate) struct InvalidationQueue {
+    inodes: Arc<Mutex<Vec<INode>>>,
+    entries: Arc<Mutex<Vec<(INode, OsString)>>>,
+}
+
+impl InvalidationQueue {
+    /// Queues `ino` unless it's already waiting.
+    pub(crate) fn push(&self, ino: INode) {
+        let mut inodes = self.inodes.lock().unwrap();
+        if !inodes.contains(&ino) {
+            inodes.push(ino);
+        }
+    }
+
+    /// Takes every queued inode, in the order they were first queued.
+    pub(crate) fn drain(&self) -> Vec<INode> {
+        std::mem::take(&mut *self.inodes.lock().unwrap())
+    }
+
+    /// Queues `name` in `parent`, which was looked up as missing and now
+    /// exists.
+    pub(crate) fn push_entry(&self, parent: INode, name: &OsStr) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.iter().any(|(p, n)| *p == parent && n == name) {
+            entries.push((parent, name.to_os_string()));
+        }
+    }
+
+    /// Takes every queued entry, in the order they were first queued.
+    pub(crate) fn drain_entries(&self) -> Vec<(INode, OsString)> {
+        std::mem::take(&mut *self.entries.lock().unwrap())
+    }
+}
@@ -0,0 +1,325 @@
+use crate::nsfs::wal::wal_path;
+use crate::nsfs::{blocks_for, dir_size, File, MonotonicAllocator, Node, NsFS};
+
+use fuser::{FileAttr, FileType};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"NSFSv004";
+/// State files from before volumes were saved.
+const MAGIC_V3: &[u8; 8] = b"NSFSv003";
+/// State files from before hard links were saved, whose inodes have a
+/// single entry each.
+const MAGIC_V2: &[u8; 8] = b"NSFSv002";
+/// State files from before checkpoints were numbered, read as checkpoint 0.
+const MAGIC_V1: &[u8; 8] = b"NSFSv001";
+
+/// Kind of a record that only adds another entry for an inode saved before.
+const LINK_RECORD: u8 = 0xff;
+
+// The state file is MAGIC, the number of the checkpoint it was written by as a
+// u64, the volumes as a u32 count and (ino u64, device id u64) * count, then
+// one record per directory entry, parents before their children and
+// the root first. The first entry of an inode carries everything about it:
+//
+//   parent u64, ino u64, name, kind u8, perm u16, uid u32, gid u32, rdev u32,
+//   flags u32, atime, mtime, ctime, crtime, data, xattr count u32,
+//   (name, value) * count
+//
+// Any further entry, a hard link, is only `parent u64, ino u64, name` and
+// LINK_RECORD as its kind.
+//
+// Integers are little endian, names and data are a u64 length followed by the
+// bytes and times are u64 seconds plus u32 nanoseconds since the epoch.
+
+impl NsFS {
+    /// Writes the tree, attributes, contents and extended attributes to
+    /// `path`, replacing it only once everything was written.
+    pub(crate) fn save_to(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(fs::File::create(&tmp)?);
+        out.write_all(MAGIC)?;
+        write_u64(&mut out, self.checkpoint_id)?;
+        let mut volumes: Vec<_> = self.volumes.iter().collect();
+        volumes.sort();
+        write_u32(&mut out, volumes.len() as u32)?;
+        for (&ino, &device_id) in volumes {
+            write_u64(&mut out, ino)?;
+            write_u64(&mut out, device_id)?;
+        }
+
+        let mut saved = HashSet::new();
+        let mut pending = vec![(0, OsString::from("/"), 1)];
+        while let Some((parent, name, ino)) = pending.pop() {
+            let (node, attrs) = match (self.nodes.get(&ino), self.attrs.get(&ino)) {
+                (Some(node), Some(attrs)) => (node, attrs),
+                _ => continue,
+            };
+
+            write_u64(&mut out, parent)?;
+            write_u64(&mut out, ino)?;
+            write_bytes(&mut out, name.as_encoded_bytes())?;
+            if !saved.insert(ino) {
+                out.write_all(&[LINK_RECORD])?;
+                continue;
+            }
+            out.write_all(&[kind_to_u8(attrs.kind)])?;
+            out.write_all(&attrs.perm.to_le_bytes())?;
+            write_u32(&mut out, attrs.uid)?;
+            write_u32(&mut out, attrs.gid)?;
+            write_u32(&mut out, attrs.rdev)?;
+            write_u32(&mut out, attrs.flags)?;
+            for time in [attrs.atime, attrs.mtime, attrs.ctime, attrs.crtime] {
+                write_time(&mut out, time)?;
+            }
+
+            let data = match self.symlinks.get(&ino) {
+                Some(target) => Some(target.as_os_str().as_encoded_bytes().to_vec()),
+                None => self.files.get(&ino).map(File::to_vec),
+            };
+            write_bytes(&mut out, &data.unwrap_or_default())?;
+
+            let mut xattrs: Vec<_> = self.xattrs.get(&ino).into_iter().flatten().collect();
+            xattrs.sort();
+            write_u32(&mut out, xattrs.len() as u32)?;
+            for (name, value) in xattrs {
+                write_bytes(&mut out, name.as_encoded_bytes())?;
+                write_bytes(&mut out, value)?;
+            }
+
+            let mut children: Vec<_> = node
+                .children
+                .iter()
+                .map(|(name, &child)| (ino, name.clone(), child))
+                .collect();
+            children.sort_unstable_by(|a, b| (b.2, &b.1).cmp(&(a.2, &a.1)));
+            pending.extend(children);
+        }
+
+        out.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Reads a filesystem written by `save_to` and replays the write-ahead
+    /// log next to it, if there's one.
+    pub fn load_from(path: &Path) -> io::Result<NsFS> {
+        let mut input = BufReader::new(fs::File::open(path)?);
+        let mut fs = NsFS::new();
+        let mut volumes = Vec::new();
+        match &read_array::<8>(&mut input)? {
+            MAGIC => {
+                fs.checkpoint_id = read_u64(&mut input)?;
+                for _ in 0..read_u32(&mut input)? {
+                    volumes.push((read_u64(&mut input)?, read_u64(&mut input)?));
+                }
+            }
+            MAGIC_V3 | MAGIC_V2 => fs.checkpoint_id = read_u64(&mut input)?,
+            MAGIC_V1 => {}
+            _ => return Err(invalid("not an nsfs state file")),
+        }
+
+        let mut last_inode = 1;
+        fs.nodes.clear();
+        fs.attrs.clear();
+        loop {
+            let parent = match read_u64(&mut input) {
+                Ok(parent) => parent,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            let ino = read_u64(&mut input)?;
+            let name = OsString::from_vec(read_bytes(&mut input)?);
+            let kind = match read_array::<1>(&mut input)?[0] {
+                LINK_RECORD => {
+                    match (fs.nodes.get_mut(&parent), fs.attrs.get_mut(&ino)) {
+                        (Some(parent_node), Some(attrs)) => {
+                            parent_node.add_child(&name, ino);
+                            attrs.nlink += 1;
+                        }
+                        _ => return Err(invalid("link saved before its inode")),
+                    }
+                    continue;
+                }
+                kind => kind_from_u8(kind)?,
+            };
+            let perm = u16::from_le_bytes(read_array(&mut input)?);
+            let uid = read_u32(&mut input)?;
+            let gid = read_u32(&mut input)?;
+            let rdev = read_u32(&mut input)?;
+            let flags = read_u32(&mut input)?;
+            let atime = read_time(&mut input)?;
+            let mtime = read_time(&mut input)?;
+            let ctime = read_time(&mut input)?;
+            let crtime = read_time(&mut input)?;
+            let data = read_bytes(&mut input)?;
+
+            let mut xattrs = std::collections::HashMap::new();
+            for _ in 0..read_u32(&mut input)? {
+                let name = OsString::from_vec(read_bytes(&mut input)?);
+                xattrs.insert(name, read_bytes(&mut input)?);
+            }
+            if !xattrs.is_empty() {
+                fs.xattrs.insert(ino, xattrs);
+            }
+
+            if ino != 1 {
+                match fs.nodes.get_mut(&parent) {
+                    Some(parent_node) => {
+                        parent_node.add_child(&name, ino);
+                    }
+                    None => return Err(invalid("entry saved before its parent")),
+                }
+            }
+
+            let size = data.len() as u64;
+            match kind {
+                FileType::RegularFile => {
+                    fs.files.insert(ino, File::from_vec(data));
+                }
+                FileType::Symlink => {
+                    fs.symlinks
+                        .insert(ino, std::path::PathBuf::from(OsString::from_vec(data)));
+                }
+                _ => {}
+            }
+            let nlink = match kind {
+                FileType::Directory => 2,
+                _ => 1,
+            };
+            fs.attrs.insert(
+                ino,
+                FileAttr {
+                    ino,
+                    size,
+                    blocks: blocks_for(size, fs.block_size),
+                    atime,
+                    mtime,
+                    ctime,
+                    crtime,
+                    kind,
+                    perm,
+                    nlink,
+                    uid,
+                    gid,
+                    rdev,
+                    blksize: fs.block_size,
+                    flags,
+                },
+            );
+            let depth = fs.nodes.get(&parent).map_or(0, |node| node.depth + 1);
+            fs.nodes
+                .insert(ino, Node::new(ino, parent, &name, kind, depth));
+            last_inode = last_inode.max(ino);
+        }
+
+        if !fs.nodes.contains_key(&1) {
+            return Err(invalid("missing root"));
+        }
+        for (ino, device_id) in volumes {
+            if fs.set_device(ino, device_id).is_err() {
+                return Err(invalid("volume saved for a missing directory"));
+            }
+        }
+        fs.allocator = Box::new(MonotonicAllocator::after(last_inode));
+        fs.recount_usage();
+        for (ino, node) in &fs.nodes {
+            if let Some(attrs) = fs.attrs.get_mut(ino) {
+                if attrs.kind == FileType::Directory {
+                    attrs.size = dir_size(node);
+                    attrs.blocks = blocks_for(attrs.size, fs.block_size);
+                }
+            }
+        }
+
+        let wal = wal_path(path);
+        if wal.exists() {
+            fs.replay_wal(&wal)?;
+        }
+        Ok(fs)
+    }
+}
+
+pub(super) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(super) fn kind_to_u8(kind: FileType) -> u8 {
+    match kind {
+        FileType::NamedPipe => 0,
+        FileType::CharDevice => 1,
+        FileType::BlockDevice => 2,
+        FileType::Directory => 3,
+        FileType::RegularFile => 4,
+        FileType::Symlink => 5,
+        FileType::Socket => 6,
+    }
+}
+
+pub(super) fn kind_from_u8(kind: u8) -> io::Result<FileType> {
+    match kind {
+        0 => Ok(FileType::NamedPipe),
+        1 => Ok(FileType::CharDevice),
+        2 => Ok(FileType::BlockDevice),
+        3 => Ok(FileType::Directory),
+        4 => Ok(FileType::RegularFile),
+        5 => Ok(FileType::Symlink),
+        6 => Ok(FileType::Socket),
+        _ => Err(invalid("unknown file type")),
+    }
+}
+
+pub(super) fn write_u32(out: &mut impl Write, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(super) fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(super) fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_u64(out, bytes.len() as u64)?;
+    out.write_all(bytes)
+}
+
+fn write_time(out: &mut impl Write, time: SystemTime) -> io::Result<()> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    write_u64(out, since_epoch.as_secs())?;
+    write_u32(out, since_epoch.subsec_nanos())
+}
+
+pub(super) fn read_array<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub(super) fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(input)?))
+}
+
+pub(super) fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(read_array(input)?))
+}
+
+pub(super) fn read_bytes(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u64(input)?;
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn read_time(input: &mut impl Read) -> io::Result<SystemTime> {
+    let secs = read_u64(input)?;
+    let nanos = read_u32(input)?;
+    Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+}
@@ -0,0 +1,209 @@
+use crate::nsfs::{INode, NsFS};
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+/// A change mirrored to the shadow directory, with paths relative to it.
+enum ShadowOp {
+    Create(PathBuf),
+    Write {
+        path: PathBuf,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Truncate {
+        path: PathBuf,
+        len: u64,
+    },
+    Unlink(PathBuf),
+    /// Moves `from` to `to`, or swaps them if `exchange` is set.
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        exchange: bool,
+    },
+}
+
+/// The thread applying changes to the shadow directory, in the order they
+/// were made.
+pub(crate) struct ShadowWorker {
+    sender: Sender<ShadowOp>,
+    thread: JoinHandle<()>,
+}
+
+impl ShadowWorker {
+    fn start(root: PathBuf) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for op in receiver {
+                if let Err(err) = apply(&root, &op) {
+                    log::warn!("mirroring to {:?} failed: {}", root, err);
+                }
+            }
+        });
+        Self { sender, thread }
+    }
+}
+
+impl NsFS {
+    /// Mirrors the creation of `name` in `parent` to `shadow_root`, if set.
+    pub(crate) fn shadow_create(&mut self, parent: INode, name: &OsStr) {
+        if let Some(path) = self.shadow_path(parent, Some(name)) {
+            self.shadow(ShadowOp::Create(path));
+        }
+    }
+
+    /// Mirrors a write of `data` at `offset` of `ino` to `shadow_root`, if
+    /// set.
+    pub(crate) fn shadow_write(&mut self, ino: INode, offset: usize, data: &[u8]) {
+        if let Some(path) = self.shadow_path(ino, None) {
+            self.shadow(ShadowOp::Write {
+                path,
+                offset: offset as u64,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    /// Mirrors a change of the length of `ino` to `len` to `shadow_root`, if
+    /// set.
+    pub(crate) fn shadow_truncate(&mut self, ino: INode, len: u64) {
+        if let Some(path) = self.shadow_path(ino, None) {
+            self.shadow(ShadowOp::Truncate { path, len });
+        }
+    }
+
+    /// Mirrors replacing the whole content of `ino` with `data` to
+    /// `shadow_root`, if set.
+    pub(crate) fn shadow_content(&mut self, ino: INode, data: &[u8]) {
+        if let Some(path) = self.shadow_path(ino, None) {
+            self.shadow(ShadowOp::Truncate {
+                path: path.clone(),
+                len: 0,
+            });
+            self.shadow(ShadowOp::Write {
+                path,
+                offset: 0,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    /// Mirrors moving `name` in `parent` to `newname` in `newparent`, or
+    /// swapping them, to `shadow_root`, if set.
+    pub(crate) fn shadow_rename(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        newparent: INode,
+        newname: &OsStr,
+        exchange: bool,
+    ) {
+        let from = self.shadow_path(parent, Some(name));
+        let to = self.shadow_path(newparent, Some(newname));
+        if let (Some(from), Some(to)) = (from, to) {
+            self.shadow(ShadowOp::Rename { from, to, exchange });
+        }
+    }
+
+    /// Mirrors the removal of `name` from `parent` to `shadow_root`, if set.
+    pub(crate) fn shadow_unlink(&mut self, parent: INode, name: &OsStr) {
+        if let Some(path) = self.shadow_path(parent, Some(name)) {
+            self.shadow(ShadowOp::Unlink(path));
+        }
+    }
+
+    /// Waits until every change so far reached the shadow directory.
+    pub(crate) fn sync_shadow(&mut self) {
+        if let Some(worker) = self.shadow_worker.take() {
+            drop(worker.sender);
+            if worker.thread.join().is_err() {
+                log::error!("the shadow directory thread panicked");
+            }
+        }
+    }
+
+    /// The path of `ino`, or of `name` in it, relative to `shadow_root`.
+    fn shadow_path(&self, ino: INode, name: Option<&OsStr>) -> Option<PathBuf> {
+        self.shadow_root.as_ref()?;
+        let path = self.path_of(ino)?;
+        let path = path.strip_prefix("/").ok()?;
+        Some(match name {
+            Some(name) => path.join(name),
+            None => path.to_path_buf(),
+        })
+    }
+
+    fn shadow(&mut self, op: ShadowOp) {
+        let root = match &self.shadow_root {
+            Some(root) => root.clone(),
+            None => return,
+        };
+        let worker = self
+            .shadow_worker
+            .get_or_insert_with(|| ShadowWorker::start(root));
+        if worker.sender.send(op).is_err() {
+            log::error!("the shadow directory thread is gone");
+        }
+    }
+}
+
+fn apply(root: &Path, op: &ShadowOp) -> io::Result<()> {
+    match op {
+        ShadowOp::Create(path) => {
+            let path = root.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::File::create(path).map(drop)
+        }
+        ShadowOp::Write { path, offset, data } => {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(root.join(path))?;
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(data)
+        }
+        ShadowOp::Truncate { path, len } => fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(root.join(path))?
+            .set_len(*len),
+        ShadowOp::Unlink(path) => {
+            let path = root.join(path);
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir(path),
+                Ok(_) => fs::remove_file(path),
+                // it was never mirrored, e.g. a directory
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(err) => Err(err),
+            }
+        }
+        ShadowOp::Rename { from, to, exchange } => {
+            let (from, to) = (root.join(from), root.join(to));
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let result = if *exchange {
+                let swap = to.with_file_name(".nsfs-exchange");
+                fs::rename(&from, &swap)
+                    .and_then(|()| fs::rename(&to, &from))
+                    .and_then(|()| fs::rename(&swap, &to))
+            } else {
+                fs::rename(&from, &to)
+            };
+            match result {
+                // it was never mirrored, e.g. an empty directory
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::ops::Index;
+
+/// Values stored in slots addressed by their index. Removing a value puts
+/// its slot on a free list, so indexes get reused and the table only grows
+/// to the most values held at once.
+pub(crate) struct Slab<T> {
+    slots: Vec<Option<T>>,
+    free: Vec<u64>,
+    len: usize,
+}
+
+impl<T> Slab<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Stores `value` in a free slot, or a new one if none is free, and
+    /// returns its index.
+    pub(crate) fn insert(&mut self, value: T) -> u64 {
+        self.len += 1;
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize] = Some(value);
+                index
+            }
+            None => {
+                self.slots.push(Some(value));
+                (self.slots.len() - 1) as u64
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, index: &u64) -> Option<T> {
+        let value = self.slots.get_mut(*index as usize)?.take()?;
+        self.free.push(*index);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub(crate) fn get(&self, index: &u64) -> Option<&T> {
+        self.slots.get(*index as usize)?.as_ref()
+    }
+
+    pub(crate) fn get_mut(&mut self, index: &u64) -> Option<&mut T> {
+        self.slots.get_mut(*index as usize)?.as_mut()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Indexes of the occupied slots, in ascending order.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = u64> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .map(|(index, _)| index as u64)
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().flatten()
+    }
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Index<&u64> for Slab<T> {
+    type Output = T;
+
+    fn index(&self, index: &u64) -> &T {
+        self.get(index).expect("no value at this index")
+    }
+}
@@ -0,0 +1,85 @@
+use crate::nsfs::{Error, File, INode, Node, NsFS};
+
+use fuser::FileAttr;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+/// A copy of the tree, attributes and contents of a filesystem at one point.
+/// Open handles and kernel references aren't part of it.
+#[derive(Clone)]
+pub(crate) struct Snapshot {
+    attrs: HashMap<INode, FileAttr>,
+    nodes: HashMap<INode, Node>,
+    files: HashMap<INode, File>,
+    xattrs: HashMap<INode, HashMap<OsString, Vec<u8>>>,
+    symlinks: HashMap<INode, PathBuf>,
+    volumes: HashMap<INode, u64>,
+}
+
+impl NsFS {
+    /// Copies the filesystem as it is now. Spilled files are brought back
+    /// first, as only their length is in memory. Content kept by `storage`
+    /// can't be copied, so there are no snapshots with one.
+    pub(crate) fn snapshot(&mut self) -> Result<Snapshot, Error> {
+        if self.storage.is_some() {
+            return Err(Error::NotSupported);
+        }
+        self.fault_in_all()?;
+        Ok(Snapshot {
+            attrs: self.attrs.clone(),
+            nodes: self.nodes.clone(),
+            files: self.files.clone(),
+            xattrs: self.xattrs.clone(),
+            symlinks: self.symlinks.clone(),
+            volumes: self.volumes.clone(),
+        })
+    }
+
+    /// Rolls the filesystem back to `snap`. Every file is considered changed
+    /// since it was last persisted. Inode numbers handed out since the
+    /// snapshot aren't reused, the kernel may still know them.
+    pub(crate) fn restore(&mut self, snap: Snapshot) {
+        self.attrs = snap.attrs;
+        self.nodes = snap.nodes;
+        self.files = snap.files;
+        self.xattrs = snap.xattrs;
+        self.symlinks = snap.symlinks;
+        self.volumes = snap.volumes;
+        // the restored content is all in memory
+        for ino in std::mem::take(&mut self.spilled) {
+            if let Some(path) = self.spill_file(ino) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        self.last_used.retain(|ino, _| self.files.contains_key(ino));
+        self.dirty = self.files.keys().copied().collect();
+        self.recount_usage();
+    }
+
+    /// Keeps a snapshot of the filesystem as `name`, replacing an older one
+    /// of the same name.
+    pub(crate) fn save_snapshot(&mut self, name: &OsStr) -> Result<(), Error> {
+        let snap = self.snapshot()?;
+        self.snapshots.insert(name.to_os_string(), snap);
+        Ok(())
+    }
+
+    /// Rolls the filesystem back to the snapshot kept as `name`, which stays
+    /// around to roll back to again. The kernel is told to drop what it
+    /// cached and the state file, if any, is saved again so the write-ahead
+    /// log continues the restored tree.
+    pub(crate) fn restore_snapshot(&mut self, name: &OsStr) -> Result<(), Error> {
+        let snap = match self.snapshots.get(name) {
+            Some(snap) => snap.clone(),
+            None => return Err(Error::NotFound),
+        };
+        self.restore(snap);
+        if let Some(invalidations) = &self.invalidations {
+            for &ino in self.attrs.keys() {
+                invalidations.push(ino);
+            }
+        }
+        self.checkpoint()
+    }
+}
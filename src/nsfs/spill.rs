@@ -0,0 +1,106 @@
+use crate::nsfs::{Error, File, INode, NsFS};
+
+use std::path::PathBuf;
+
+impl NsFS {
+    pub(crate) fn spill_file(&self, ino: INode) -> Option<PathBuf> {
+        self.spill_path
+            .as_ref()
+            .map(|dir| dir.join(ino.to_string()))
+    }
+
+    /// Brings back the content of `ino` if it was spilled, marks it as the
+    /// most recently used file and spills the least recently used ones while
+    /// file content takes more than `spill_high_water` bytes.
+    pub(crate) fn page_in(&mut self, ino: INode) -> Result<(), Error> {
+        if self.spill_path.is_none() || self.storage.is_some() {
+            return Ok(());
+        }
+        self.fault_in(ino)?;
+        self.lru_tick += 1;
+        self.last_used.insert(ino, self.lru_tick);
+
+        let mut resident: usize = self.files.values().map(File::resident_bytes).sum();
+        while resident > self.spill_high_water {
+            let coldest = self
+                .files
+                .iter()
+                .filter(|(&other, file)| other != ino && file.resident_bytes() > 0)
+                .min_by_key(|(other, _)| self.last_used.get(other).copied().unwrap_or_default())
+                .map(|(&other, _)| other);
+            match coldest {
+                Some(coldest) => resident -= self.spill(coldest)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the content of `ino` to its spill file, returning how many
+    /// resident bytes that freed.
+    fn spill(&mut self, ino: INode) -> Result<usize, Error> {
+        let path = match self.spill_file(ino) {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let file = match self.files.get_mut(&ino) {
+            Some(file) => file,
+            None => return Ok(0),
+        };
+
+        std::fs::write(&path, file.to_vec()).map_err(|err| {
+            log::warn!("spilling inode {} to {:?} failed: {}", ino, path, err);
+            Error::Io
+        })?;
+        let freed = file.resident_bytes();
+        let len = file.len();
+        *file = File::new();
+        file.resize(len);
+        self.spilled.insert(ino);
+        log::debug!("spilled inode {} ({} bytes)", ino, len);
+        Ok(freed)
+    }
+
+    /// Brings back the content of `ino` if it was spilled. Anything that
+    /// reads or changes file content must call this first, as a spilled file
+    /// only reads as a hole.
+    pub(crate) fn fault_in(&mut self, ino: INode) -> Result<(), Error> {
+        if !self.spilled.contains(&ino) {
+            return Ok(());
+        }
+        let path = match self.spill_file(ino) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let data = std::fs::read(&path).map_err(|err| {
+            log::warn!(
+                "reading spilled inode {} from {:?} failed: {}",
+                ino,
+                path,
+                err
+            );
+            Error::Io
+        })?;
+        if let Some(file) = self.files.get_mut(&ino) {
+            let len = file.len();
+            *file = File::from_vec(data);
+            file.resize(len);
+            file.share_segments(0, len, &mut self.segment_index);
+        }
+        self.spilled.remove(&ino);
+        if let Err(err) = std::fs::remove_file(&path) {
+            log::warn!("removing spill file {:?} failed: {}", path, err);
+        }
+        Ok(())
+    }
+
+    /// Brings back every spilled file, e.g. before saving the whole state.
+    pub(crate) fn fault_in_all(&mut self) -> Result<(), Error> {
+        let spilled: Vec<INode> = self.spilled.iter().copied().collect();
+        for ino in spilled {
+            self.fault_in(ino)?;
+        }
+        Ok(())
+    }
+}
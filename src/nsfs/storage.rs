@@ -0,0 +1,79 @@
+use crate::nsfs::{Error, INode};
+
+/// Where file content lives if not in memory, such as an mmap'd or on-disk
+/// file. With a storage plugged in, reads and writes of file content go
+/// through it and the in-memory file only keeps track of the length, so
+/// reflinks, snapshots and saved state don't see the content.
+pub(crate) trait Storage: Send {
+    /// Copies `[offset, offset + len)` into `buf`, replacing its content.
+    fn read(&self, ino: INode, offset: usize, len: usize, buf: &mut Vec<u8>) -> Result<(), Error>;
+    /// Stores `data` at `offset`, growing the content if it ends past the end.
+    fn write(&mut self, ino: INode, offset: usize, data: &[u8]) -> Result<(), Error>;
+    /// Truncates or extends the content to `size` bytes.
+    fn truncate(&mut self, ino: INode, size: usize) -> Result<(), Error>;
+    /// Drops the content of a file that's gone.
+    fn remove(&mut self, ino: INode);
+}
+
+/// A storage keeping content in memory that records every call made to it.
+/// Clones share the same record.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct RecordingStorage {
+    calls: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    files: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<INode, Vec<u8>>>>,
+}
+
+#[cfg(test)]
+impl RecordingStorage {
+    pub(crate) fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+#[cfg(test)]
+impl Storage for RecordingStorage {
+    fn read(&self, ino: INode, offset: usize, len: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
+        self.record(format!("read {} {} {}", ino, offset, len));
+        buf.clear();
+        buf.resize(len, 0);
+        if let Some(data) = self.files.lock().unwrap().get(&ino) {
+            let end = data.len().min(offset + len);
+            if offset < end {
+                buf[..end - offset].copy_from_slice(&data[offset..end]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, ino: INode, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.record(format!("write {} {} {}", ino, offset, data.len()));
+        let mut files = self.files.lock().unwrap();
+        let content = files.entry(ino).or_default();
+        if content.len() < offset + data.len() {
+            content.resize(offset + data.len(), 0);
+        }
+        content[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, ino: INode, size: usize) -> Result<(), Error> {
+        self.record(format!("truncate {} {}", ino, size));
+        self.files
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_default()
+            .resize(size, 0);
+        Ok(())
+    }
+
+    fn remove(&mut self, ino: INode) {
+        self.record(format!("remove {}", ino));
+        self.files.lock().unwrap().remove(&ino);
+    }
+}
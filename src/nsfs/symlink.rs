@@ -0,0 +1,97 @@
+use crate::nsfs::{Error, INode, NsFS, WalRecord};
+
+use fuser::{FileAttr, FileType};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path};
+
+/// How many symlinks resolving a single path may follow, like Linux's
+/// `SYMLOOP_MAX`. Anything longer is taken to be a loop.
+const SYMLOOP_MAX: usize = 40;
+
+impl NsFS {
+    /// Creates `name` in `parent` as a symlink to `target`, which is stored
+    /// as is and only resolved when followed.
+    pub(crate) fn create_symlink(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        target: &Path,
+        uid: u32,
+        gid: u32,
+    ) -> Result<&FileAttr, Error> {
+        let ino = self.insert_node(parent, name, FileType::Symlink, 0, 0, uid, gid)?;
+        let size = target.as_os_str().len() as u64;
+        self.symlinks.insert(ino, target.to_path_buf());
+        let attrs = self.attrs.get_mut(&ino).unwrap();
+        attrs.size = size;
+        attrs.perm = 0o777;
+        self.log_change(WalRecord::Symlink {
+            parent,
+            name: Cow::Borrowed(name),
+            ino,
+            target: Cow::Borrowed(target),
+            uid,
+            gid,
+        });
+        self.emit_created(parent, name, ino);
+        Ok(self.attrs.get(&ino).unwrap())
+    }
+
+    pub(crate) fn read_link(&self, ino: INode) -> Result<&Path, Error> {
+        if !self.attrs.contains_key(&ino) {
+            return Err(Error::NotFound);
+        }
+        match self.symlinks.get(&ino) {
+            Some(target) => Ok(target),
+            None => Err(Error::InvalidArgument),
+        }
+    }
+
+    /// Resolves `path`, relative to the root, following symlinks along the
+    /// way and at the end. Following more than `SYMLOOP_MAX` of them fails
+    /// with `Error::TooManyLinks`.
+    pub(crate) fn resolve_path(&self, path: &Path) -> Result<INode, Error> {
+        let mut pending: VecDeque<OsString> = components(path).collect();
+        let mut current = 1;
+        let mut followed = 0;
+
+        while let Some(name) = pending.pop_front() {
+            if name == "/" {
+                current = 1;
+                continue;
+            }
+
+            let ino = self.find_node(current, &name)?.index;
+            let target = match self.symlinks.get(&ino) {
+                Some(target) => target,
+                None => {
+                    current = ino;
+                    continue;
+                }
+            };
+
+            followed += 1;
+            if followed > SYMLOOP_MAX {
+                return Err(Error::TooManyLinks);
+            }
+            // the target is resolved from the directory holding the symlink
+            for component in components(target).collect::<Vec<_>>().into_iter().rev() {
+                pending.push_front(component);
+            }
+        }
+
+        Ok(current)
+    }
+}
+
+/// The names in `path`, with the root as `/`.
+fn components(path: &Path) -> impl Iterator<Item = OsString> + '_ {
+    path.components().map(|component| match component {
+        Component::RootDir | Component::Prefix(_) => OsString::from("/"),
+        Component::CurDir => OsString::from("."),
+        Component::ParentDir => OsString::from(".."),
+        Component::Normal(name) => name.to_os_string(),
+    })
+}
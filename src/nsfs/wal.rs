@@ -0,0 +1,566 @@
+use crate::nsfs::persist::{
+    invalid, kind_from_u8, kind_to_u8, read_array, read_bytes, read_u32, read_u64, write_bytes,
+    write_u32, write_u64,
+};
+use crate::nsfs::{Error, INode, MonotonicAllocator, NsFS, SetAttr};
+
+use fuser::FileType;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+const WAL_MAGIC: &[u8; 8] = b"NSFSWAL1";
+
+/// The log is folded into a new snapshot once it grows past this.
+const WAL_CHECKPOINT_BYTES: u64 = 64 << 20;
+
+// The log is WAL_MAGIC, the number of the checkpoint it continues as a u64
+// and the records appended since, each a u32 length followed by that many
+// bytes: a tag u8 and the fields of the record, encoded like the state file.
+// A record cut short by a crash ends the log.
+
+/// A change to the filesystem, as it's appended to the write-ahead log.
+/// Nodes are identified by the inodes they had, so they get the same ones
+/// when the log is replayed.
+#[derive(Debug, PartialEq)]
+pub(crate) enum WalRecord<'a> {
+    Create {
+        parent: INode,
+        name: Cow<'a, OsStr>,
+        ino: INode,
+        kind: FileType,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+        rdev: u32,
+    },
+    Symlink {
+        parent: INode,
+        name: Cow<'a, OsStr>,
+        ino: INode,
+        target: Cow<'a, Path>,
+        uid: u32,
+        gid: u32,
+    },
+    Write {
+        ino: INode,
+        offset: u64,
+        data: Cow<'a, [u8]>,
+    },
+    /// The whole content of a file was replaced.
+    Content {
+        ino: INode,
+        data: Cow<'a, [u8]>,
+    },
+    SetAttr {
+        ino: INode,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+        size: u64,
+    },
+    Link {
+        ino: INode,
+        parent: INode,
+        name: Cow<'a, OsStr>,
+    },
+    Unlink {
+        parent: INode,
+        name: Cow<'a, OsStr>,
+    },
+    Rename {
+        parent: INode,
+        name: Cow<'a, OsStr>,
+        newparent: INode,
+        newname: Cow<'a, OsStr>,
+        flags: u32,
+    },
+    SetXattr {
+        ino: INode,
+        name: Cow<'a, OsStr>,
+        value: Cow<'a, [u8]>,
+    },
+    RemoveXattr {
+        ino: INode,
+        name: Cow<'a, OsStr>,
+    },
+    /// A directory was made a volume of its own.
+    SetVolume {
+        ino: INode,
+        device_id: u64,
+    },
+}
+
+/// The open write-ahead log.
+pub(crate) struct Wal {
+    file: fs::File,
+    len: u64,
+}
+
+/// Where the write-ahead log of the state file `path` is kept.
+pub(crate) fn wal_path(path: &Path) -> PathBuf {
+    path.with_extension("wal")
+}
+
+impl NsFS {
+    /// Starts logging every change next to `state_file`, beginning with a
+    /// checkpoint so the log always continues a snapshot. Records reach the
+    /// OS with each operation, so they survive the process crashing, but
+    /// only a checkpoint forces them to disk.
+    pub(crate) fn open_wal(&mut self) -> Result<(), Error> {
+        let path = match &self.state_file {
+            Some(path) => wal_path(path),
+            None => return Err(Error::InvalidArgument),
+        };
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|err| {
+                log::warn!("opening {:?} failed: {}", path, err);
+                Error::Io
+            })?;
+        self.wal = Some(Wal { file, len: 0 });
+        self.checkpoint()
+    }
+
+    /// Saves the whole filesystem to `state_file`, if set, and empties the
+    /// write-ahead log, whose records are all in the new snapshot.
+    pub(crate) fn checkpoint(&mut self) -> Result<(), Error> {
+        let path = match self.state_file.clone() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        self.fault_in_all()?;
+        // a crash before the log is reset leaves it behind the snapshot,
+        // which is then told by the numbers not matching
+        self.checkpoint_id += 1;
+        self.save_to(&path)
+            .and_then(|()| self.reset_wal())
+            .map_err(|err| {
+                log::warn!("saving state to {:?} failed: {}", path, err);
+                Error::Io
+            })
+    }
+
+    fn reset_wal(&mut self) -> io::Result<()> {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+        wal.file.set_len(0)?;
+        wal.file.seek(SeekFrom::Start(0))?;
+        let mut header = WAL_MAGIC.to_vec();
+        write_u64(&mut header, self.checkpoint_id)?;
+        wal.file.write_all(&header)?;
+        wal.file.sync_data()?;
+        wal.len = header.len() as u64;
+        Ok(())
+    }
+
+    /// Appends `record` to the write-ahead log, if one is open.
+    pub(crate) fn append_wal_record(&mut self, record: &WalRecord<'_>) -> io::Result<()> {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+        let mut payload = Vec::new();
+        encode(&mut payload, record)?;
+        let mut framed = Vec::with_capacity(payload.len() + 4);
+        write_u32(&mut framed, payload.len() as u32)?;
+        framed.extend_from_slice(&payload);
+        wal.file.write_all(&framed)?;
+        wal.len += framed.len() as u64;
+        Ok(())
+    }
+
+    /// Logs a change the filesystem already went through. A change that
+    /// can't be logged stays made; it's only lost if the process crashes
+    /// before the next checkpoint.
+    pub(crate) fn log_change(&mut self, record: WalRecord<'_>) {
+        if self.wal.is_none() {
+            return;
+        }
+        if let Err(err) = self.append_wal_record(&record) {
+            log::error!("appending to the write-ahead log failed: {}", err);
+        }
+        if self
+            .wal
+            .as_ref()
+            .is_some_and(|wal| wal.len >= WAL_CHECKPOINT_BYTES)
+        {
+            if let Err(err) = self.checkpoint() {
+                log::error!("checkpoint failed: {}", err);
+            }
+        }
+    }
+
+    /// Logs the creation of `ino` as `name` in `parent`.
+    pub(crate) fn log_create(&mut self, parent: INode, name: &OsStr, ino: INode) {
+        if self.wal.is_none() {
+            return;
+        }
+        if let Some(&attrs) = self.attrs.get(&ino) {
+            self.log_change(WalRecord::Create {
+                parent,
+                name: Cow::Borrowed(name),
+                ino,
+                kind: attrs.kind,
+                perm: attrs.perm,
+                uid: attrs.uid,
+                gid: attrs.gid,
+                rdev: attrs.rdev,
+            });
+        }
+    }
+
+    /// Logs the permissions, owner and size of `ino`.
+    pub(crate) fn log_attrs(&mut self, ino: INode) {
+        if self.wal.is_none() {
+            return;
+        }
+        if let Some(&attrs) = self.attrs.get(&ino) {
+            self.log_change(WalRecord::SetAttr {
+                ino,
+                perm: attrs.perm,
+                uid: attrs.uid,
+                gid: attrs.gid,
+                size: attrs.size,
+            });
+        }
+    }
+
+    pub(crate) fn log_rename(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        newparent: INode,
+        newname: &OsStr,
+        flags: u32,
+    ) {
+        self.log_change(WalRecord::Rename {
+            parent,
+            name: Cow::Borrowed(name),
+            newparent,
+            newname: Cow::Borrowed(newname),
+            flags,
+        });
+    }
+
+    /// Applies the records of the log at `path` that continue the snapshot
+    /// the filesystem was loaded from and returns how many there were. A log
+    /// left behind by an earlier checkpoint is ignored. Times aren't logged,
+    /// so replayed changes happen now.
+    pub(crate) fn replay_wal(&mut self, path: &Path) -> io::Result<usize> {
+        let mut input = BufReader::new(fs::File::open(path)?);
+        let mut magic = [0; 8];
+        match input.read_exact(&mut magic) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+            Err(err) => return Err(err),
+        }
+        if &magic != WAL_MAGIC {
+            return Err(invalid("not an nsfs write-ahead log"));
+        }
+        if read_u64(&mut input)? != self.checkpoint_id {
+            return Ok(0);
+        }
+
+        let mut replayed = 0;
+        loop {
+            let len = match read_u32(&mut input) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            let mut payload = Vec::new();
+            (&mut input).take(len as u64).read_to_end(&mut payload)?;
+            if payload.len() != len as usize {
+                log::warn!("{:?} ends with a partial record", path);
+                break;
+            }
+            let record = decode(&mut payload.as_slice())?;
+            if let Err(err) = self.apply(record) {
+                log::warn!("replaying a record of {:?} failed: {}", path, err);
+            }
+            replayed += 1;
+        }
+
+        let last = self.attrs.keys().copied().max().unwrap_or(1);
+        self.allocator = Box::new(MonotonicAllocator::after(last));
+        Ok(replayed)
+    }
+
+    fn apply(&mut self, record: WalRecord<'_>) -> Result<(), Error> {
+        match record {
+            WalRecord::Create {
+                parent,
+                name,
+                ino,
+                kind,
+                perm,
+                uid,
+                gid,
+                rdev,
+            } => {
+                self.allocate_next(ino)?;
+                let ino = self.insert_node(parent, &name, kind, rdev, 0, uid, gid)?;
+                self.lookup_counts.remove(&ino);
+                self.attrs.get_mut(&ino).unwrap().perm = perm;
+            }
+            WalRecord::Symlink {
+                parent,
+                name,
+                ino,
+                target,
+                uid,
+                gid,
+            } => {
+                self.allocate_next(ino)?;
+                self.create_symlink(parent, &name, &target, uid, gid)?;
+                self.lookup_counts.remove(&ino);
+            }
+            WalRecord::Write { ino, offset, data } => {
+                self.write_file(ino, &data, offset as usize)?;
+            }
+            WalRecord::Content { ino, data } => {
+                self.replace_content(ino, &data)?;
+            }
+            WalRecord::SetAttr {
+                ino,
+                perm,
+                uid,
+                gid,
+                size,
+            } => {
+                let is_file = self.files.contains_key(&ino);
+                let changes = SetAttr {
+                    mode: Some(perm as u32),
+                    uid: Some(uid),
+                    gid: Some(gid),
+                    size: is_file.then_some(size),
+                    ..Default::default()
+                };
+                self.set_attr(ino, changes)?;
+            }
+            WalRecord::Link { ino, parent, name } => {
+                self.link_node(ino, parent, &name)?;
+                self.lookup_counts.remove(&ino);
+            }
+            WalRecord::Unlink { parent, name } => match self.find_node(parent, &name)?.kind {
+                FileType::Directory => self.remove_dir(parent, &name, 0)?,
+                _ => self.remove_file(parent, &name, 0)?,
+            },
+            WalRecord::Rename {
+                parent,
+                name,
+                newparent,
+                newname,
+                flags,
+            } => {
+                self.rename_ex(parent, &name, newparent, &newname, flags, 0)?;
+            }
+            WalRecord::SetXattr { ino, name, value } => {
+                self.set_xattr(ino, &name, &value, 0)?;
+            }
+            WalRecord::RemoveXattr { ino, name } => self.remove_xattr(ino, &name)?,
+            WalRecord::SetVolume { ino, device_id } => self.set_device(ino, device_id)?,
+        }
+        Ok(())
+    }
+
+    /// Makes `ino` the inode the next node gets, as it did when the record
+    /// creating it was logged.
+    fn allocate_next(&mut self, ino: INode) -> Result<(), Error> {
+        if ino < 2 || self.attrs.contains_key(&ino) {
+            return Err(Error::AlreadyExists);
+        }
+        self.allocator = Box::new(MonotonicAllocator::after(ino - 1));
+        Ok(())
+    }
+}
+
+fn encode(out: &mut Vec<u8>, record: &WalRecord<'_>) -> io::Result<()> {
+    match record {
+        WalRecord::Create {
+            parent,
+            name,
+            ino,
+            kind,
+            perm,
+            uid,
+            gid,
+            rdev,
+        } => {
+            out.push(0);
+            write_u64(out, *parent)?;
+            write_bytes(out, name.as_encoded_bytes())?;
+            write_u64(out, *ino)?;
+            out.push(kind_to_u8(*kind));
+            out.extend_from_slice(&perm.to_le_bytes());
+            write_u32(out, *uid)?;
+            write_u32(out, *gid)?;
+            write_u32(out, *rdev)?;
+        }
+        WalRecord::Symlink {
+            parent,
+            name,
+            ino,
+            target,
+            uid,
+            gid,
+        } => {
+            out.push(1);
+            write_u64(out, *parent)?;
+            write_bytes(out, name.as_encoded_bytes())?;
+            write_u64(out, *ino)?;
+            write_bytes(out, target.as_os_str().as_encoded_bytes())?;
+            write_u32(out, *uid)?;
+            write_u32(out, *gid)?;
+        }
+        WalRecord::Write { ino, offset, data } => {
+            out.push(2);
+            write_u64(out, *ino)?;
+            write_u64(out, *offset)?;
+            write_bytes(out, data)?;
+        }
+        WalRecord::Content { ino, data } => {
+            out.push(3);
+            write_u64(out, *ino)?;
+            write_bytes(out, data)?;
+        }
+        WalRecord::SetAttr {
+            ino,
+            perm,
+            uid,
+            gid,
+            size,
+        } => {
+            out.push(4);
+            write_u64(out, *ino)?;
+            out.extend_from_slice(&perm.to_le_bytes());
+            write_u32(out, *uid)?;
+            write_u32(out, *gid)?;
+            write_u64(out, *size)?;
+        }
+        WalRecord::Link { ino, parent, name } => {
+            out.push(5);
+            write_u64(out, *ino)?;
+            write_u64(out, *parent)?;
+            write_bytes(out, name.as_encoded_bytes())?;
+        }
+        WalRecord::Unlink { parent, name } => {
+            out.push(6);
+            write_u64(out, *parent)?;
+            write_bytes(out, name.as_encoded_bytes())?;
+        }
+        WalRecord::Rename {
+            parent,
+            name,
+            newparent,
+            newname,
+            flags,
+        } => {
+            out.push(7);
+            write_u64(out, *parent)?;
+            write_bytes(out, name.as_encoded_bytes())?;
+            write_u64(out, *newparent)?;
+            write_bytes(out, newname.as_encoded_bytes())?;
+            write_u32(out, *flags)?;
+        }
+        WalRecord::SetXattr { ino, name, value } => {
+            out.push(8);
+            write_u64(out, *ino)?;
+            write_bytes(out, name.as_encoded_bytes())?;
+            write_bytes(out, value)?;
+        }
+        WalRecord::RemoveXattr { ino, name } => {
+            out.push(9);
+            write_u64(out, *ino)?;
+            write_bytes(out, name.as_encoded_bytes())?;
+        }
+        WalRecord::SetVolume { ino, device_id } => {
+            out.push(10);
+            write_u64(out, *ino)?;
+            write_u64(out, *device_id)?;
+        }
+    }
+    Ok(())
+}
+
+fn decode(input: &mut impl Read) -> io::Result<WalRecord<'static>> {
+    let name = |input: &mut _| -> io::Result<Cow<'static, OsStr>> {
+        Ok(Cow::Owned(OsString::from_vec(read_bytes(input)?)))
+    };
+    let record = match read_array::<1>(input)?[0] {
+        0 => WalRecord::Create {
+            parent: read_u64(input)?,
+            name: name(input)?,
+            ino: read_u64(input)?,
+            kind: kind_from_u8(read_array::<1>(input)?[0])?,
+            perm: u16::from_le_bytes(read_array(input)?),
+            uid: read_u32(input)?,
+            gid: read_u32(input)?,
+            rdev: read_u32(input)?,
+        },
+        1 => WalRecord::Symlink {
+            parent: read_u64(input)?,
+            name: name(input)?,
+            ino: read_u64(input)?,
+            target: Cow::Owned(PathBuf::from(OsString::from_vec(read_bytes(input)?))),
+            uid: read_u32(input)?,
+            gid: read_u32(input)?,
+        },
+        2 => WalRecord::Write {
+            ino: read_u64(input)?,
+            offset: read_u64(input)?,
+            data: Cow::Owned(read_bytes(input)?),
+        },
+        3 => WalRecord::Content {
+            ino: read_u64(input)?,
+            data: Cow::Owned(read_bytes(input)?),
+        },
+        4 => WalRecord::SetAttr {
+            ino: read_u64(input)?,
+            perm: u16::from_le_bytes(read_array(input)?),
+            uid: read_u32(input)?,
+            gid: read_u32(input)?,
+            size: read_u64(input)?,
+        },
+        5 => WalRecord::Link {
+            ino: read_u64(input)?,
+            parent: read_u64(input)?,
+            name: name(input)?,
+        },
+        6 => WalRecord::Unlink {
+            parent: read_u64(input)?,
+            name: name(input)?,
+        },
+        7 => WalRecord::Rename {
+            parent: read_u64(input)?,
+            name: name(input)?,
+            newparent: read_u64(input)?,
+            newname: name(input)?,
+            flags: read_u32(input)?,
+        },
+        8 => WalRecord::SetXattr {
+            ino: read_u64(input)?,
+            name: name(input)?,
+            value: Cow::Owned(read_bytes(input)?),
+        },
+        9 => WalRecord::RemoveXattr {
+            ino: read_u64(input)?,
+            name: name(input)?,
+        },
+        10 => WalRecord::SetVolume {
+            ino: read_u64(input)?,
+            device_id: read_u64(input)?,
+        },
+        _ => return Err(invalid("unknown write-ahead log record")),
+    };
+    Ok(record)
+}
@@ -0,0 +1,336 @@
+use crate::nsfs::checksum::crc32_update;
+use crate::nsfs::{Error, File, INode, NsFS, WalRecord, SECTOR_SIZE};
+
+use fuser::FileType;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// A read-only attribute of regular files holding the MIME type sniffed from
+/// their first bytes. It's computed on every read and never stored.
+pub(crate) const MIME_TYPE_XATTR: &str = "user.mime_type";
+
+/// A read-only attribute of directories listing every path below them,
+/// relative to the directory, one per line in depth-first order. It isn't
+/// listed, so copying attributes doesn't walk whole trees.
+pub(crate) const TREE_XATTR: &str = "user.tree";
+
+/// A read-only attribute of regular files holding the CRC-32 of their
+/// content as eight hex digits, to check it survived being saved and loaded
+/// again. It isn't listed, for the same reason as `TREE_XATTR`.
+pub(crate) const CHECKSUM_XATTR: &str = "user.checksum";
+
+/// A read-only attribute of directories holding `recursive_size` in decimal.
+/// It isn't listed, for the same reason as `TREE_XATTR`.
+pub(crate) const DU_XATTR: &str = "user.du";
+
+/// The read-only attributes of directories named by this followed by a
+/// prefix list the names in the directory starting with it, one per line in
+/// order, as `find_by_prefix` finds them. They aren't listed.
+pub(crate) const PREFIX_XATTR: &str = "user.prefix.";
+
+/// Setting this attribute of the root directory keeps a snapshot of the
+/// whole filesystem named by the value, as `setfattr -n user.nsfs.snapshot
+/// -v NAME` does. It's never stored, and only root and the owner of the root
+/// directory may set it.
+pub(crate) const SNAPSHOT_XATTR: &str = "user.nsfs.snapshot";
+
+/// Setting this attribute of the root directory rolls the filesystem back to
+/// the snapshot named by the value. The same users as for `SNAPSHOT_XATTR`
+/// may set it.
+pub(crate) const RESTORE_XATTR: &str = "user.nsfs.restore";
+
+/// Setting this attribute of a node copies it, and everything below it, to
+/// the name given by the value in the same directory.
+pub(crate) const CLONE_XATTR: &str = "user.nsfs.clone";
+
+/// Setting this attribute of a directory to a decimal number makes it and
+/// everything below it the volume with that device id, see `set_device`.
+/// The same users as for `SNAPSHOT_XATTR` may set it.
+pub(crate) const VOLUME_XATTR: &str = "user.nsfs.volume";
+
+/// Whether `name` runs a command through `control` rather than being stored.
+pub(crate) fn is_control(name: &OsStr) -> bool {
+    [SNAPSHOT_XATTR, RESTORE_XATTR, CLONE_XATTR, VOLUME_XATTR]
+        .iter()
+        .any(|control| name == *control)
+}
+
+/// Whether `name` is computed rather than stored, so it can't be changed.
+fn is_computed(name: &OsStr) -> bool {
+    name == MIME_TYPE_XATTR
+        || name == TREE_XATTR
+        || name == CHECKSUM_XATTR
+        || name == DU_XATTR
+        || name.as_bytes().starts_with(PREFIX_XATTR.as_bytes())
+}
+
+/// How much of a file is looked at to tell text from binary data.
+const SNIFF_LEN: usize = 512;
+
+/// Guesses the MIME type of `file` from magic numbers at its start.
+fn sniff_mime_type(file: &File) -> &'static str {
+    let len = file.len().min(SNIFF_LEN);
+    // a hole at the start reads as zeroes, which isn't any of the types below
+    let head = file.slice(0, len).unwrap_or(&[0]);
+    if file.is_empty() {
+        "application/x-empty"
+    } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if head.starts_with(b"\xff\xd8\xff") {
+        "image/jpeg"
+    } else if head.starts_with(b"\x1f\x8b") {
+        "application/gzip"
+    } else if head.starts_with(b"%PDF-") {
+        "application/pdf"
+    } else if is_text(head) {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// UTF-8 without control characters other than whitespace. A character cut
+/// off at the end of `head` still counts as text.
+fn is_text(head: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none(),
+    };
+    valid
+        && head
+            .iter()
+            .all(|&byte| byte >= 0x20 || matches!(byte, b'\t' | b'\n' | b'\r' | 0x0c))
+}
+
+impl NsFS {
+    /// Stores an extended attribute. Names are kept verbatim, so namespaces
+    /// such as `trusted.overlay.*` round-trip as overlayfs expects.
+    pub(crate) fn set_xattr(
+        &mut self,
+        ino: INode,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), Error> {
+        if !self.attrs.contains_key(&ino) {
+            return Err(Error::NotFound);
+        }
+        // commands need to know who runs them, see `control`
+        if is_computed(name) || is_control(name) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let xattrs = self.xattrs.entry(ino).or_default();
+        let exists = xattrs.contains_key(name);
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Err(Error::AlreadyExists);
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(Error::NoAttribute);
+        }
+
+        xattrs.insert(name.to_os_string(), value.to_vec());
+        self.log_change(WalRecord::SetXattr {
+            ino,
+            name: Cow::Borrowed(name),
+            value: Cow::Borrowed(value),
+        });
+        Ok(())
+    }
+
+    /// Runs the command `name`, one of the attributes `is_control` accepts,
+    /// with `value` as its argument on behalf of `uid`.
+    pub(crate) fn control(
+        &mut self,
+        ino: INode,
+        name: &OsStr,
+        value: &[u8],
+        uid: u32,
+    ) -> Result<(), Error> {
+        if !self.attrs.contains_key(&ino) {
+            return Err(Error::NotFound);
+        }
+        if value.is_empty() {
+            return Err(Error::InvalidArgument);
+        }
+        if name == VOLUME_XATTR {
+            self.check_mount_owner(uid)?;
+            let device_id = std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or(Error::InvalidArgument)?;
+            return self.set_device(ino, device_id);
+        }
+        let value = OsStr::from_bytes(value);
+        if name == CLONE_XATTR {
+            let parent = match self.nodes.get(&ino) {
+                // the root isn't in any directory the copy could go to
+                Some(node) if node.index == 1 => return Err(Error::InvalidArgument),
+                Some(node) => node.parent,
+                None => return Err(Error::NotFound),
+            };
+            return self.clone_subtree(ino, parent, value).map(drop);
+        }
+        if ino != 1 {
+            return Err(Error::InvalidArgument);
+        }
+        self.check_mount_owner(uid)?;
+        if name == SNAPSHOT_XATTR {
+            self.save_snapshot(value)
+        } else {
+            self.restore_snapshot(value)
+        }
+    }
+
+    /// Commands affecting the whole filesystem may only be run by root and
+    /// by the owner of the root directory, who mounted it.
+    fn check_mount_owner(&self, uid: u32) -> Result<(), Error> {
+        match self.attrs.get(&1) {
+            Some(root) if uid == 0 || uid == root.uid => Ok(()),
+            _ => Err(Error::NotPermitted),
+        }
+    }
+
+    pub(crate) fn get_xattr(&self, ino: INode, name: &OsStr) -> Result<Cow<'_, [u8]>, Error> {
+        let attrs = match self.attrs.get(&ino) {
+            Some(attrs) => attrs,
+            None => return Err(Error::NotFound),
+        };
+        if name == MIME_TYPE_XATTR {
+            if let Some(file) = self.files.get(&ino) {
+                return Ok(Cow::Borrowed(sniff_mime_type(file).as_bytes()));
+            }
+        }
+        if name == TREE_XATTR && attrs.kind == FileType::Directory {
+            return Ok(Cow::Owned(self.tree_listing(ino)));
+        }
+        if let Some(prefix) = name.as_bytes().strip_prefix(PREFIX_XATTR.as_bytes()) {
+            if attrs.kind == FileType::Directory {
+                let mut listing = Vec::new();
+                for name in self.find_by_prefix(ino, OsStr::from_bytes(prefix))? {
+                    listing.extend_from_slice(name.as_bytes());
+                    listing.push(b'\n');
+                }
+                return Ok(Cow::Owned(listing));
+            }
+        }
+        if name == DU_XATTR && attrs.kind == FileType::Directory {
+            let size = self.recursive_size(ino)?;
+            return Ok(Cow::Owned(size.to_string().into_bytes()));
+        }
+        if name == CHECKSUM_XATTR {
+            if let Some(file) = self.files.get(&ino) {
+                let checksum = match &self.storage {
+                    // the content is only in the storage
+                    Some(storage) => {
+                        let mut data = Vec::new();
+                        storage.read(ino, 0, file.len(), &mut data)?;
+                        crc32_update(0, &data)
+                    }
+                    None => file.checksum(),
+                };
+                return Ok(Cow::Owned(format!("{:08x}", checksum).into_bytes()));
+            }
+        }
+
+        match self.xattrs.get(&ino).and_then(|xattrs| xattrs.get(name)) {
+            Some(value) => Ok(Cow::Borrowed(value)),
+            None => Err(Error::NoAttribute),
+        }
+    }
+
+    /// The bytes taken by `ino` and everything below it, counted like `du`
+    /// does: whole blocks, and inodes with several links only once.
+    pub(crate) fn recursive_size(&self, ino: INode) -> Result<u64, Error> {
+        if !self.attrs.contains_key(&ino) {
+            return Err(Error::NotFound);
+        }
+        let mut seen = HashSet::new();
+        let mut pending = vec![ino];
+        let mut total = 0;
+        while let Some(ino) = pending.pop() {
+            if !seen.insert(ino) {
+                continue;
+            }
+            if let Some(attrs) = self.attrs.get(&ino) {
+                total += attrs.blocks * SECTOR_SIZE;
+            }
+            if let Some(node) = self.nodes.get(&ino) {
+                pending.extend(node.children.values());
+            }
+        }
+        Ok(total)
+    }
+
+    /// Every path below directory `ino`, relative to it, each followed by a
+    /// newline. Entries are visited depth-first in name order.
+    fn tree_listing(&self, ino: INode) -> Vec<u8> {
+        let mut listing = Vec::new();
+        let mut pending = vec![(ino, PathBuf::new())];
+        while let Some((dir, path)) = pending.pop() {
+            let node = match self.nodes.get(&dir) {
+                Some(node) => node,
+                None => continue,
+            };
+            let mut children: Vec<_> = node.children.iter().collect();
+            // reversed, so the stack pops them in name order
+            children.sort_by(|a, b| b.0.cmp(a.0));
+            for (name, &child) in children {
+                pending.push((child, path.join(name)));
+            }
+
+            if path != Path::new("") {
+                listing.extend_from_slice(path.as_os_str().as_encoded_bytes());
+                listing.push(b'\n');
+            }
+        }
+        listing
+    }
+
+    /// Returns the attribute names as a sequence of NUL-terminated strings.
+    pub(crate) fn list_xattr(&self, ino: INode) -> Result<Vec<u8>, Error> {
+        if !self.attrs.contains_key(&ino) {
+            return Err(Error::NotFound);
+        }
+
+        let mut names = Vec::new();
+        if self.files.contains_key(&ino) {
+            names.extend_from_slice(MIME_TYPE_XATTR.as_bytes());
+            names.push(0);
+        }
+        if let Some(xattrs) = self.xattrs.get(&ino) {
+            for name in xattrs.keys() {
+                names.extend_from_slice(name.as_encoded_bytes());
+                names.push(0);
+            }
+        }
+        Ok(names)
+    }
+
+    pub(crate) fn remove_xattr(&mut self, ino: INode, name: &OsStr) -> Result<(), Error> {
+        if !self.attrs.contains_key(&ino) {
+            return Err(Error::NotFound);
+        }
+        if is_computed(name) {
+            return Err(Error::PermissionDenied);
+        }
+
+        match self
+            .xattrs
+            .get_mut(&ino)
+            .and_then(|xattrs| xattrs.remove(name))
+        {
+            Some(_) => {
+                self.log_change(WalRecord::RemoveXattr {
+                    ino,
+                    name: Cow::Borrowed(name),
+                });
+                Ok(())
+            }
+            None => Err(Error::NoAttribute),
+        }
+    }
+}
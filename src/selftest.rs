@@ -0,0 +1,176 @@
+use crate::nsfs;
+
+use libc::c_int;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What the self-test runs its operations on. Paths are relative to the
+/// root of the filesystem.
+pub(crate) trait Target {
+    fn create(&mut self, path: &Path) -> io::Result<()>;
+    fn write(&mut self, path: &Path, data: &[u8]) -> io::Result<()>;
+    fn read(&mut self, path: &Path) -> io::Result<Vec<u8>>;
+    fn mkdir(&mut self, path: &Path) -> io::Result<()>;
+    /// The names in a directory, without `.` and `..`, sorted.
+    fn readdir(&mut self, path: &Path) -> io::Result<Vec<OsString>>;
+    fn unlink(&mut self, path: &Path) -> io::Result<()>;
+    fn rmdir(&mut self, path: &Path) -> io::Result<()>;
+}
+
+/// Runs the scripted sequence, describing the first step that fails.
+pub(crate) fn run(target: &mut dyn Target) -> Result<(), String> {
+    let dir = Path::new("selftest");
+    let file = dir.join("file");
+    let data = b"Hello, nsfs";
+    let step =
+        |name: &str, result: io::Result<()>| result.map_err(|err| format!("{}: {}", name, err));
+    let expect = |name: &str, ok: bool| match ok {
+        true => Ok(()),
+        false => Err(format!("{}: unexpected result", name)),
+    };
+
+    step("mkdir", target.mkdir(dir))?;
+    step("create", target.create(&file))?;
+    step("write", target.write(&file, data))?;
+    let read = target.read(&file).map_err(|err| format!("read: {}", err))?;
+    expect("read", read == data)?;
+    let names = target
+        .readdir(dir)
+        .map_err(|err| format!("readdir: {}", err))?;
+    expect("readdir", names == [OsString::from("file")])?;
+    step("unlink", target.unlink(&file))?;
+    let names = target
+        .readdir(dir)
+        .map_err(|err| format!("readdir: {}", err))?;
+    expect("readdir after unlink", names.is_empty())?;
+    step("rmdir", target.rmdir(dir))?;
+    let names = target
+        .readdir(Path::new(""))
+        .map_err(|err| format!("readdir: {}", err))?;
+    expect(
+        "readdir after rmdir",
+        !names.contains(&OsString::from("selftest")),
+    )
+}
+
+/// A mounted filesystem, reached through the kernel.
+pub(crate) struct MountedDir {
+    root: PathBuf,
+}
+
+impl MountedDir {
+    pub(crate) fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+impl Target for MountedDir {
+    fn create(&mut self, path: &Path) -> io::Result<()> {
+        fs::File::create(self.root.join(path)).map(drop)
+    }
+
+    fn write(&mut self, path: &Path, data: &[u8]) -> io::Result<()> {
+        fs::write(self.root.join(path), data)
+    }
+
+    fn read(&mut self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(self.root.join(path))
+    }
+
+    fn mkdir(&mut self, path: &Path) -> io::Result<()> {
+        fs::create_dir(self.root.join(path))
+    }
+
+    fn readdir(&mut self, path: &Path) -> io::Result<Vec<OsString>> {
+        let mut names = fs::read_dir(self.root.join(path))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    fn unlink(&mut self, path: &Path) -> io::Result<()> {
+        fs::remove_file(self.root.join(path))
+    }
+
+    fn rmdir(&mut self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(self.root.join(path))
+    }
+}
+
+/// The in-memory filesystem, used directly.
+impl Target for nsfs::NsFS {
+    fn create(&mut self, path: &Path) -> io::Result<()> {
+        let (parent, name) = split(self, path)?;
+        let fh = self
+            .create_file(parent, &name, 0, 0, 0, None, 0)
+            .map_err(to_io)?
+            .1;
+        self.close_file(fh).map_err(to_io)
+    }
+
+    fn write(&mut self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let ino = resolve(self, path)?;
+        self.write_file(ino, data, 0).map(drop).map_err(to_io)
+    }
+
+    fn read(&mut self, path: &Path) -> io::Result<Vec<u8>> {
+        let ino = resolve(self, path)?;
+        let size = self.get_attr(ino).map_err(to_io)?.size as usize;
+        self.read_file(ino, size, 0)
+            .map(|data| data.to_vec())
+            .map_err(to_io)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> io::Result<()> {
+        let (parent, name) = split(self, path)?;
+        self.create_dir(parent, &name, 0, 0, None, 0)
+            .map(drop)
+            .map_err(to_io)
+    }
+
+    fn readdir(&mut self, path: &Path) -> io::Result<Vec<OsString>> {
+        let ino = resolve(self, path)?;
+        let mut names: Vec<OsString> = self
+            .read_dir(ino, 0)
+            .map_err(to_io)?
+            .into_iter()
+            .map(|(.., name)| name)
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn unlink(&mut self, path: &Path) -> io::Result<()> {
+        let (parent, name) = split(self, path)?;
+        self.remove_file(parent, &name, 0).map_err(to_io)
+    }
+
+    fn rmdir(&mut self, path: &Path) -> io::Result<()> {
+        let (parent, name) = split(self, path)?;
+        self.remove_dir(parent, &name, 0).map_err(to_io)
+    }
+}
+
+fn resolve(fs: &nsfs::NsFS, path: &Path) -> io::Result<u64> {
+    fs.resolve_path(&Path::new("/").join(path)).map_err(to_io)
+}
+
+/// The directory holding `path` and the name in it.
+fn split(fs: &nsfs::NsFS, path: &Path) -> io::Result<(u64, OsString)> {
+    let name = match path.file_name() {
+        Some(name) => name.to_os_string(),
+        None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+    let parent = resolve(fs, path.parent().unwrap_or(Path::new("")))?;
+    Ok((parent, name))
+}
+
+fn to_io(err: nsfs::Error) -> io::Error {
+    io::Error::from_raw_os_error(c_int::from(err))
+}
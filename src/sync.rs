@@ -0,0 +1,502 @@
+use crate::nsfs;
+
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
+};
+use libc::c_int;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// `NsFS` behind a single mutex, so a session dispatching requests from
+/// several threads can share it and other threads can reach it while it's
+/// mounted. Clones share the same filesystem.
+///
+/// Every operation holds the lock for its whole duration, so operations
+/// never run in parallel: a long read or write holds up everything else,
+/// lookups and `getattr` included. That keeps every operation atomic
+/// without touching `NsFS` itself. Locking the maps separately would let
+/// reads of different files overlap, but most operations touch several of
+/// them (attributes, nodes and content for a write) and would have to take
+/// their locks in one fixed order to avoid deadlocks.
+#[derive(Clone)]
+pub(crate) struct SyncNsFS {
+    inner: Arc<Mutex<nsfs::NsFS>>,
+}
+
+impl SyncNsFS {
+    pub(crate) fn new(fs: nsfs::NsFS) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    /// Waits for the filesystem to be free. A thread that panicked while
+    /// holding it leaves it as it was, which is still usable.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, nsfs::NsFS> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Filesystem for SyncNsFS {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        Filesystem::init(&mut *self.lock(), req, config)
+    }
+
+    fn destroy(&mut self) {
+        Filesystem::destroy(&mut *self.lock());
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        Filesystem::lookup(&mut *self.lock(), req, parent, name, reply);
+    }
+
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        Filesystem::forget(&mut *self.lock(), req, ino, nlookup);
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        Filesystem::getattr(&mut *self.lock(), req, ino, reply);
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        Filesystem::setattr(
+            &mut *self.lock(),
+            req,
+            ino,
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime,
+            fh,
+            crtime,
+            chgtime,
+            bkuptime,
+            flags,
+            reply,
+        );
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        Filesystem::readlink(&mut *self.lock(), req, ino, reply);
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umas: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        Filesystem::mknod(
+            &mut *self.lock(),
+            req,
+            parent,
+            name,
+            mode,
+            umas,
+            rdev,
+            reply,
+        );
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        Filesystem::mkdir(&mut *self.lock(), req, parent, name, mode, umask, reply);
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        Filesystem::unlink(&mut *self.lock(), req, parent, name, reply);
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        Filesystem::rmdir(&mut *self.lock(), req, parent, name, reply);
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        Filesystem::symlink(&mut *self.lock(), req, parent, name, link, reply);
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        Filesystem::rename(
+            &mut *self.lock(),
+            req,
+            parent,
+            name,
+            newparent,
+            newname,
+            flags,
+            reply,
+        );
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        Filesystem::link(&mut *self.lock(), req, ino, newparent, newname, reply);
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        Filesystem::open(&mut *self.lock(), req, ino, flags, reply);
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        Filesystem::read(
+            &mut *self.lock(),
+            req,
+            ino,
+            fh,
+            offset,
+            size,
+            flags,
+            lock_owner,
+            reply,
+        );
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        Filesystem::write(
+            &mut *self.lock(),
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        );
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        Filesystem::flush(&mut *self.lock(), req, ino, fh, lock_owner, reply);
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        Filesystem::release(
+            &mut *self.lock(),
+            req,
+            ino,
+            fh,
+            flags,
+            lock_owner,
+            flush,
+            reply,
+        );
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        Filesystem::fsync(&mut *self.lock(), req, ino, fh, datasync, reply);
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        Filesystem::opendir(&mut *self.lock(), req, ino, flags, reply);
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        Filesystem::readdir(&mut *self.lock(), req, ino, fh, offset, reply);
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        Filesystem::releasedir(&mut *self.lock(), req, ino, fh, flags, reply);
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        Filesystem::fsyncdir(&mut *self.lock(), req, ino, fh, datasync, reply);
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        Filesystem::statfs(&mut *self.lock(), req, ino, reply);
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        Filesystem::setxattr(
+            &mut *self.lock(),
+            req,
+            ino,
+            name,
+            value,
+            flags,
+            position,
+            reply,
+        );
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        Filesystem::getxattr(&mut *self.lock(), req, ino, name, size, reply);
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        Filesystem::listxattr(&mut *self.lock(), req, ino, size, reply);
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        Filesystem::removexattr(&mut *self.lock(), req, ino, name, reply);
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        Filesystem::access(&mut *self.lock(), req, ino, mask, reply);
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        Filesystem::create(
+            &mut *self.lock(),
+            req,
+            parent,
+            name,
+            mode,
+            umask,
+            flags,
+            reply,
+        );
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        Filesystem::getlk(
+            &mut *self.lock(),
+            req,
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+            reply,
+        );
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        Filesystem::setlk(
+            &mut *self.lock(),
+            req,
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+            sleep,
+            reply,
+        );
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        Filesystem::bmap(&mut *self.lock(), req, ino, blocksize, idx, reply);
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        Filesystem::ioctl(
+            &mut *self.lock(),
+            req,
+            ino,
+            fh,
+            flags,
+            cmd,
+            in_data,
+            out_size,
+            reply,
+        );
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        Filesystem::fallocate(&mut *self.lock(), req, ino, fh, offset, length, mode, reply);
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        Filesystem::copy_file_range(
+            &mut *self.lock(),
+            req,
+            ino_in,
+            fh_in,
+            offset_in,
+            ino_out,
+            fh_out,
+            offset_out,
+            len,
+            flags,
+            reply,
+        );
+    }
+}